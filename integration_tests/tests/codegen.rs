@@ -1,5 +1,6 @@
 use crate::dummy_jobs::*;
 use crate::test_guard::TestGuard;
+use assert_matches::assert_matches;
 use diesel::prelude::*;
 use failure::Fallible;
 use swirl::db::DieselPoolObj;
@@ -17,9 +18,9 @@ fn generated_jobs_serialize_all_arguments_except_first() -> Fallible<()> {
     }
 
     let runner = TestGuard::runner("a".to_string());
-    let conn = runner.connection_pool().get()?;
-    check_arg_equal_to_env("a".into()).enqueue(&conn)?;
-    check_arg_equal_to_env("b".into()).enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    check_arg_equal_to_env("a".into()).enqueue(&mut conn)?;
+    check_arg_equal_to_env("b".into()).enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
@@ -38,15 +39,144 @@ fn jobs_with_args_but_no_env() -> Fallible<()> {
     }
 
     let runner = TestGuard::dummy_runner();
-    let conn = runner.connection_pool().get()?;
-    assert_foo("foo".into()).enqueue(&conn)?;
-    assert_foo("not foo".into()).enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    assert_foo("foo".into()).enqueue(&mut conn)?;
+    assert_foo("not foo".into()).enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
     Ok(())
 }
 
+// Stands in for a crate which re-exports swirl under another name
+mod facade {
+    pub use swirl as jobs;
+}
+
+#[test]
+fn jobs_can_be_defined_through_a_reexport_of_swirl() -> Fallible<()> {
+    #[facade::jobs::background_job(crate = "facade::jobs")]
+    fn assert_bar(arg: String) -> Result<(), PerformError> {
+        if arg == "bar" {
+            Ok(())
+        } else {
+            Err("arg wasn't bar!".into())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    assert_bar("bar".into()).enqueue(&mut conn)?;
+    assert_bar("not bar".into()).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn job_types_can_be_referred_to_without_a_job() {
+    #[swirl::background_job]
+    fn routed_job(_arg: String) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    fn queue_for(job_type: &str) -> &'static str {
+        match job_type {
+            routed_job::JOB_TYPE => "routed",
+            failure_job::JOB_TYPE | panic_job::JOB_TYPE => "broken",
+            _ => "default",
+        }
+    }
+
+    assert_eq!("routed_job", routed_job::JOB_TYPE);
+    assert_eq!(
+        <routed_job::Job as swirl::Job>::JOB_TYPE,
+        routed_job::JOB_TYPE
+    );
+    assert_eq!("routed", queue_for("routed_job"));
+    assert_eq!("broken", queue_for("panic_job"));
+    assert_eq!("default", queue_for(HandWrittenJob::JOB_TYPE));
+}
+
+#[test]
+fn integer_arguments_round_trip_without_losing_precision() -> Fallible<()> {
+    #[swirl::background_job]
+    fn assert_extreme_ids(max: i64, min: i64, unsigned: u64) -> Result<(), PerformError> {
+        assert_eq!(i64::MAX, max);
+        assert_eq!(i64::MIN, min);
+        assert_eq!(u64::MAX, unsigned);
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    assert_extreme_ids(i64::MAX, i64::MIN, u64::MAX).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn binary_arguments_round_trip_intact() -> Fallible<()> {
+    #[swirl::background_job]
+    fn assert_bytes(bytes: Vec<u8>) -> Result<(), PerformError> {
+        assert_eq!((0..=255).collect::<Vec<u8>>(), bytes);
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    assert_bytes((0..=255).collect()).enqueue(&mut conn)?;
+
+    // Bytes are stored as an array of numbers, not as a string
+    let data = swirl::schema::background_jobs::table
+        .select(swirl::schema::background_jobs::data)
+        .first::<serde_json::Value>(&mut conn)?;
+    assert_eq!(Some(256), data["bytes"].as_array().map(Vec::len));
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn previews_match_the_enqueued_job() -> Fallible<()> {
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job]
+    fn import_rows(
+        source: String,
+        row_ids: Vec<i64>,
+        limit: Option<u32>,
+        dry_run: bool,
+    ) -> Result<(), PerformError> {
+        assert_eq!("users.csv", source);
+        assert_eq!(vec![3, 1, 2], row_ids);
+        assert_eq!(None, limit);
+        assert!(dry_run);
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let job = import_rows("users.csv".into(), vec![3, 1, 2], None, true);
+    let (preview_type, preview_data) = job.preview()?;
+    assert_eq!(import_rows::JOB_TYPE, preview_type);
+    assert_eq!(0, background_jobs.count().get_result::<i64>(&mut conn)?);
+
+    job.enqueue(&mut conn)?;
+    let stored = background_jobs
+        .select((job_type, data))
+        .first::<(String, serde_json::Value)>(&mut conn)?;
+    assert_eq!((preview_type, preview_data), stored);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
 #[test]
 fn env_can_have_any_name() -> Fallible<()> {
     #[swirl::background_job]
@@ -56,8 +186,8 @@ fn env_can_have_any_name() -> Fallible<()> {
     }
 
     let runner = TestGuard::runner(String::from("my environment"));
-    let conn = runner.connection_pool().get()?;
-    env_with_different_name().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    env_with_different_name().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     runner.check_for_failed_jobs()?;
@@ -79,8 +209,8 @@ fn test_imports_only_used_in_job_body_are_not_warned_as_unused() -> Fallible<()>
     }
 
     let runner = TestGuard::dummy_runner();
-    let conn = runner.connection_pool().get()?;
-    uses_trait_import().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    uses_trait_import().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     runner.check_for_failed_jobs()?;
@@ -92,28 +222,30 @@ fn jobs_can_take_a_connection_as_an_argument() -> Fallible<()> {
     use diesel::sql_query;
 
     #[swirl::background_job]
-    fn takes_env_and_conn(_env: &(), conn: &PgConnection) -> Result<(), swirl::PerformError> {
+    fn takes_env_and_conn(_env: &(), conn: &mut PgConnection) -> Result<(), swirl::PerformError> {
         sql_query("SELECT 1").execute(conn)?;
         Ok(())
     }
 
     #[swirl::background_job]
-    fn takes_only_conn(conn: &PgConnection) -> Result<(), swirl::PerformError> {
+    fn takes_only_conn(conn: &mut PgConnection) -> Result<(), swirl::PerformError> {
         sql_query("SELECT 1").execute(conn)?;
         Ok(())
     }
 
     #[swirl::background_job]
     fn takes_connection_pool(pool: &dyn DieselPoolObj) -> Result<(), swirl::PerformError> {
-        let conn1 = pool.get()?;
-        let conn2 = pool.get()?;
-        sql_query("SELECT 1").execute(&**conn1)?;
-        sql_query("SELECT 1").execute(&**conn2)?;
+        let mut conn1 = pool.get()?;
+        let mut conn2 = pool.get()?;
+        sql_query("SELECT 1").execute(&mut **conn1)?;
+        sql_query("SELECT 1").execute(&mut **conn2)?;
         Ok(())
     }
 
     #[swirl::background_job]
-    fn takes_fully_qualified_conn(conn: &diesel::PgConnection) -> Result<(), swirl::PerformError> {
+    fn takes_fully_qualified_conn(
+        conn: &mut diesel::PgConnection,
+    ) -> Result<(), swirl::PerformError> {
         sql_query("SELECT 1").execute(conn)?;
         Ok(())
     }
@@ -122,24 +254,1021 @@ fn jobs_can_take_a_connection_as_an_argument() -> Fallible<()> {
     fn takes_fully_qualified_pool(
         pool: &dyn swirl::db::DieselPoolObj,
     ) -> Result<(), swirl::PerformError> {
-        let conn1 = pool.get()?;
-        let conn2 = pool.get()?;
-        sql_query("SELECT 1").execute(&**conn1)?;
-        sql_query("SELECT 1").execute(&**conn2)?;
+        let mut conn1 = pool.get()?;
+        let mut conn2 = pool.get()?;
+        sql_query("SELECT 1").execute(&mut **conn1)?;
+        sql_query("SELECT 1").execute(&mut **conn2)?;
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    {
+        let mut conn = runner.connection_pool().get()?;
+        takes_env_and_conn().enqueue(&mut conn)?;
+        takes_only_conn().enqueue(&mut conn)?;
+        takes_connection_pool().enqueue(&mut conn)?;
+        takes_fully_qualified_conn().enqueue(&mut conn)?;
+        takes_fully_qualified_pool().enqueue(&mut conn)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_take_both_a_connection_and_the_pool() -> Fallible<()> {
+    use diesel::sql_query;
+    use diesel::sql_types::Text;
+    use swirl::schema::background_jobs::dsl::*;
+
+    // Records are written as jobs which won't be picked up for an hour
+    fn write_record(conn: &mut PgConnection, name: &str) -> QueryResult<usize> {
+        sql_query(
+            "INSERT INTO background_jobs (job_type, data, retry_at) \
+             VALUES ($1, 'null', NOW() + INTERVAL '1 hour')",
+        )
+        .bind::<Text, _>(name)
+        .execute(conn)
+    }
+
+    #[swirl::background_job]
+    fn takes_conn_and_pool(
+        conn: &mut PgConnection,
+        pool: &dyn DieselPoolObj,
+        succeed: bool,
+    ) -> Result<(), PerformError> {
+        write_record(conn, "main_write")?;
+        write_record(&mut *pool.get()?, "log_write")?;
+        if succeed {
+            Ok(())
+        } else {
+            Err("job failed".into())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    takes_conn_and_pool(true).enqueue(&mut conn)?;
+    takes_conn_and_pool(false).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let mut count_records = |name: &str| {
+        background_jobs
+            .filter(job_type.eq(name))
+            .count()
+            .get_result(&mut conn)
+    };
+    // Writes through the connection are rolled back when the job fails,
+    // writes through the pool are not
+    assert_eq!(Ok(1), count_records("main_write"));
+    assert_eq!(Ok(2), count_records("log_write"));
+    Ok(())
+}
+
+#[test]
+fn correlation_ids_are_available_from_the_job_context() -> Fallible<()> {
+    use swirl::JobContext;
+
+    #[swirl::background_job]
+    fn check_correlation_id(
+        ctx: &JobContext,
+        expected: Option<String>,
+    ) -> Result<(), PerformError> {
+        assert!(ctx.id() > 0);
+        assert_eq!(expected.as_deref(), ctx.correlation_id());
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    check_correlation_id(Some("request-1234".into()))
+        .enqueue_with_correlation(&mut conn, "request-1234")?;
+    check_correlation_id(None).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn job_metadata_is_available_from_the_job_context() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::JobContext;
+
+    type Attempts = Arc<Mutex<Vec<(u32, SystemTime, SystemTime)>>>;
+
+    #[swirl::background_job]
+    fn fails_first_time(attempts: &Attempts, ctx: &JobContext) -> Result<(), PerformError> {
+        let mut attempts = attempts.lock().unwrap();
+        attempts.push((ctx.retries(), ctx.enqueued_at(), ctx.ready_at()));
+        if attempts.len() == 1 {
+            Err("first attempt".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    let attempts = Attempts::default();
+    let runner = TestGuard::runner(attempts.clone());
+    let mut conn = runner.connection_pool().get()?;
+    fails_first_time().enqueue(&mut conn)?;
+    // Jobs are ready as soon as they're enqueued, so move the job back in
+    // time to tell the two apart
+    diesel::update(background_jobs)
+        .set((created_at.eq(now - 1.hour()), retry_at.eq(now - 1.minute())))
+        .execute(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    diesel::update(background_jobs)
+        .set(retry_at.eq(now))
+        .execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let attempts = attempts.lock().unwrap();
+    assert_eq!(2, attempts.len());
+    let (first_retries, first_enqueued_at, first_ready_at) = attempts[0];
+    let (second_retries, second_enqueued_at, second_ready_at) = attempts[1];
+    assert_eq!((0, 1), (first_retries, second_retries));
+    assert_eq!(first_enqueued_at, second_enqueued_at);
+    let waited = first_ready_at.duration_since(first_enqueued_at)?;
+    assert_eq!(Duration::from_secs(59 * 60), waited);
+    assert!(second_ready_at > first_ready_at);
+    Ok(())
+}
+
+#[test]
+fn jobs_of_different_types_can_be_enqueued_in_one_batch() -> Fallible<()> {
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::EnqueueBatch;
+
+    #[swirl::background_job]
+    fn batched_with_string(arg: String) -> Result<(), PerformError> {
+        assert_eq!("foo", arg);
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn batched_with_numbers(a: i32, b: i64) -> Result<(), PerformError> {
+        assert_eq!((1, 2), (a, b));
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn batched_without_args() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    assert_eq!(Vec::<i64>::new(), EnqueueBatch::new().commit(&mut conn)?);
+
+    let mut batch = EnqueueBatch::new();
+    batch.push(batched_with_string("foo".into()))?;
+    batch.push(batched_with_numbers(1, 2))?;
+    batch.push(batched_without_args())?;
+    assert_eq!(3, batch.len());
+    let ids = batch.commit(&mut conn)?;
+
+    let types = ids
+        .iter()
+        .map(|&job_id| {
+            background_jobs
+                .find(job_id)
+                .select(job_type)
+                .first::<String>(&mut conn)
+        })
+        .collect::<QueryResult<Vec<_>>>()?;
+    assert_eq!(
+        vec![
+            "batched_with_string",
+            "batched_with_numbers",
+            "batched_without_args"
+        ],
+        types
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn enqueue_options_can_be_combined() -> Fallible<()> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::Bool;
+    use std::time::Duration;
+    use swirl::schema::background_jobs::dsl::*;
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let dependency = failure_job().enqueue_returning_id(&mut conn)?;
+    let job_id = HandWrittenJob
+        .enqueue_builder()
+        .correlation_id("request-1234")
+        .after(dependency)
+        .delay(Duration::from_secs(60 * 60))
+        .ttl(Duration::from_secs(2 * 60 * 60))
+        .enqueue(&mut conn)?;
+
+    let row = background_jobs
+        .find(job_id)
+        .select((
+            correlation_id,
+            depends_on,
+            sql::<Bool>("retry_at - created_at = INTERVAL '1 hour'"),
+            sql::<Bool>("expires_at - created_at = INTERVAL '2 hours'"),
+        ))
+        .first::<(Option<String>, Option<i64>, bool, bool)>(&mut conn)?;
+    assert_eq!(
+        (Some("request-1234".into()), Some(dependency), true, true),
+        row
+    );
+
+    // The job is still delayed once its dependency is gone
+    diesel::delete(background_jobs.find(dependency)).execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(1), background_jobs.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn jobs_can_report_progress_while_they_run() -> Fallible<()> {
+    use crate::sync::Barrier;
+    use swirl::schema::background_job_progress::dsl::*;
+    use swirl::JobContext;
+
+    #[swirl::background_job]
+    fn progress_job(env: &Barrier, ctx: &JobContext) -> Result<(), PerformError> {
+        ctx.set_progress(50)?;
+        env.wait();
+        env.wait();
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn too_much_progress_job(_env: &Barrier, ctx: &JobContext) -> Result<(), PerformError> {
+        ctx.set_progress(101)
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    progress_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    barrier.wait();
+    let reported = background_job_progress
+        .select(progress)
+        .load::<i16>(&mut conn)?;
+    assert_eq!(vec![50], reported);
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    // The progress is deleted along with the job
+    assert_eq!(Ok(0), background_job_progress.count().get_result(&mut conn));
+
+    too_much_progress_job().enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn running_jobs_can_be_cancelled() -> Fallible<()> {
+    use crate::sync::Barrier;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::{Cancelled, JobContext};
+
+    #[swirl::background_job]
+    fn cancellable_job(
+        env: &Barrier,
+        ctx: &JobContext,
+        conn: &mut PgConnection,
+    ) -> Result<(), PerformError> {
+        env.wait();
+        while !ctx.is_cancel_requested(conn)? {
+            sleep(Duration::from_millis(10));
+        }
+        Err(Cancelled.into())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    cancellable_job().enqueue(&mut conn)?;
+    let job_id = background_jobs.select(id).first::<i64>(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    barrier.wait();
+    swirl::request_cancel(&mut conn, job_id)?;
+    // Cancelled jobs are deleted rather than counted as failures
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn jobs_can_enqueue_themselves_to_run_again_later() -> Fallible<()> {
+    use diesel::dsl::now;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::JobContext;
+
+    #[swirl::background_job]
+    fn rescheduling_job(
+        runs: &Arc<AtomicUsize>,
+        ctx: &JobContext,
+        conn: &mut PgConnection,
+        name: String,
+    ) -> Result<(), PerformError> {
+        assert_eq!("report", name);
+        ctx.enqueue_self_after(conn, Duration::from_secs(60 * 60))?;
+        if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err("the first run fails".into());
+        }
+        Ok(())
+    }
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runner = TestGuard::runner(runs.clone());
+    let mut conn = runner.connection_pool().get()?;
+    let first_id = rescheduling_job("report".into()).enqueue_returning_id(&mut conn)?;
+    let advance_clock = |conn: &mut PgConnection| {
+        diesel::update(background_jobs)
+            .set(retry_at.eq(now))
+            .execute(conn)
+    };
+
+    // The copy enqueued by the failed run is rolled back with it
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert_eq!(
+        vec![first_id],
+        background_jobs.select(id).load::<i64>(&mut conn)?
+    );
+
+    advance_clock(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(2, runs.load(Ordering::SeqCst));
+    let rescheduled_id = background_jobs.select(id).first::<i64>(&mut conn)?;
+    assert_ne!(first_id, rescheduled_id);
+    assert_eq!(Ok(1), background_jobs.count().get_result(&mut conn));
+
+    // The new job waits for its delay
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(2, runs.load(Ordering::SeqCst));
+
+    advance_clock(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(3, runs.load(Ordering::SeqCst));
+    assert_eq!(Ok(1), background_jobs.count().get_result(&mut conn));
+
+    // Jobs which aren't run from the queue have nothing to copy
+    assert!(runner
+        .run_job_inline(rescheduling_job("report".into()))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn jobs_can_fan_out_to_other_jobs_with_enqueue_options() -> Fallible<()> {
+    use diesel::dsl::now;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::JobContext;
+
+    #[swirl::background_job]
+    fn dispatching_job(
+        runs: &Arc<AtomicUsize>,
+        ctx: &JobContext,
+        conn: &mut PgConnection,
+    ) -> Result<(), PerformError> {
+        let hour = Duration::from_secs(60 * 60);
+        let first = ctx
+            .enqueue_builder(failure_job())
+            .delay(hour)
+            .enqueue(conn)?;
+        ctx.enqueue_builder(failure_job())
+            .after(first)
+            .enqueue(conn)?;
+        ctx.enqueue_builder(failure_job())
+            .correlation_id("other-request")
+            .delay(hour)
+            .ttl(2 * hour)
+            .enqueue(conn)?;
+        if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err("the first run fails".into());
+        }
+        Ok(())
+    }
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runner = TestGuard::runner(runs.clone());
+    let mut conn = runner.connection_pool().get()?;
+    let dispatcher_id = dispatching_job()
+        .enqueue_builder()
+        .correlation_id("request-1234")
+        .enqueue(&mut conn)?;
+
+    // The jobs enqueued by the failed run are rolled back with it
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert_eq!(
+        vec![dispatcher_id],
+        background_jobs.select(id).load::<i64>(&mut conn)?
+    );
+
+    diesel::update(background_jobs)
+        .set(retry_at.eq(now))
+        .execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let children = background_jobs
+        .select((id, correlation_id, depends_on, expires_at.is_not_null()))
+        .order(id)
+        .load::<(i64, Option<String>, Option<i64>, bool)>(&mut conn)?;
+    let first = children[0].0;
+    assert_eq!(
+        vec![
+            (first, Some("request-1234".into()), None, false),
+            (first + 1, Some("request-1234".into()), Some(first), false),
+            (first + 2, Some("other-request".into()), None, true),
+        ],
+        children
+    );
+    Ok(())
+}
+
+#[test]
+fn jobs_can_set_their_own_statement_timeout() -> Fallible<()> {
+    use diesel::sql_query;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[swirl::background_job(statement_timeout_ms = 50)]
+    fn slow_query_job(conn: &mut PgConnection, seconds: f64) -> Result<(), PerformError> {
+        sql_query(format!("SELECT pg_sleep({})", seconds)).execute(conn)?;
+        Ok(())
+    }
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let errors = errors.clone();
+        TestGuard::builder(())
+            .on_failure(move |_, e| errors.lock().unwrap().push(e.to_string()))
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+
+    slow_query_job(0.01).enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    // The pool's connections allow a second per statement, so without the
+    // job's own timeout this would finish successfully
+    slow_query_job(0.5).enqueue(&mut conn)?;
+    let started_at = Instant::now();
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert!(started_at.elapsed() < Duration::from_millis(500));
+    let errors = errors.lock().unwrap();
+    assert_eq!(1, errors.len());
+    assert!(errors[0].contains("statement timeout"), "{}", errors[0]);
+    Ok(())
+}
+
+#[test]
+fn job_statement_timeouts_do_not_apply_after_the_job_returns() -> Fallible<()> {
+    use diesel::sql_query;
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job(statement_timeout_ms = 50)]
+    fn slow_to_delete() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    // Deleting the job once it succeeds takes longer than the job's timeout
+    sql_query(
+        "CREATE OR REPLACE FUNCTION slow_delete() RETURNS trigger AS $$ \
+         BEGIN PERFORM pg_sleep(0.2); RETURN OLD; END $$ LANGUAGE plpgsql",
+    )
+    .execute(&mut conn)?;
+    sql_query(
+        "CREATE TRIGGER slow_delete BEFORE DELETE ON background_jobs \
+         FOR EACH ROW WHEN (OLD.job_type = 'slow_to_delete') \
+         EXECUTE FUNCTION slow_delete()",
+    )
+    .execute(&mut conn)?;
+
+    slow_to_delete().enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    let result = runner.check_for_failed_jobs();
+    let remaining = background_jobs.count().get_result::<i64>(&mut conn);
+    sql_query("DROP TRIGGER slow_delete ON background_jobs").execute(&mut conn)?;
+    sql_query("DROP FUNCTION slow_delete()").execute(&mut conn)?;
+    result?;
+    assert_eq!(Ok(0), remaining);
+    Ok(())
+}
+
+#[test]
+fn debounced_jobs_are_enqueued_once_per_window() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+    use std::time::Duration;
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job]
+    fn refresh_cache(key: String) -> Result<(), PerformError> {
+        assert!(!key.is_empty());
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let window = Duration::from_secs(30);
+    let first = refresh_cache("users".into()).enqueue_debounced(&mut conn, window)?;
+    assert!(first.is_some());
+    for _ in 0..3 {
+        let repeated = refresh_cache("users".into()).enqueue_debounced(&mut conn, window)?;
+        assert_eq!(None, repeated);
+    }
+    // Other arguments are a different job
+    assert!(refresh_cache("posts".into())
+        .enqueue_debounced(&mut conn, window)?
+        .is_some());
+    assert_eq!(Ok(2), background_jobs.count().get_result(&mut conn));
+
+    // A job which has started running doesn't cover later changes
+    let mut worker_conn = runner.connection_pool().get()?;
+    let running = worker_conn.transaction(|worker_conn| {
+        background_jobs
+            .find(first.unwrap())
+            .for_update()
+            .select(id)
+            .first::<i64>(worker_conn)?;
+        refresh_cache("users".into()).enqueue_debounced(&mut conn, window)
+    })?;
+    assert!(running.is_some());
+    diesel::delete(background_jobs.find(running.unwrap())).execute(&mut conn)?;
+
+    // Neither does one which is older than the window
+    diesel::update(background_jobs.find(first.unwrap()))
+        .set(created_at.eq(now - 1.minute()))
+        .execute(&mut conn)?;
+    let later = refresh_cache("users".into()).enqueue_debounced(&mut conn, window)?;
+    assert!(later.is_some());
+    assert_ne!(first, later);
+    assert_eq!(Ok(3), background_jobs.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn enqueue_errors_can_be_matched_on() -> Fallible<()> {
+    use diesel::r2d2;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::time::Duration;
+    use swirl::{EnqueueError, Runner};
+
+    // Maps are stored as JSON objects, so their keys must serialize as strings
+    #[swirl::background_job(validate = "require_coordinates")]
+    fn unserializable_job(
+        coordinates: std::collections::HashMap<(i32, i32), String>,
+    ) -> Result<(), PerformError> {
+        assert!(coordinates.is_empty());
+        Ok(())
+    }
+
+    fn require_coordinates(coordinates: &HashMap<(i32, i32), String>) -> Result<(), String> {
+        if coordinates.is_empty() {
+            Err("no coordinates given".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let coordinates = vec![((1, 2), "a".to_string())]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    assert_matches!(
+        unserializable_job(HashMap::new()).enqueue(&mut conn),
+        Err(EnqueueError::InvalidArguments(_))
+    );
+    assert_matches!(
+        unserializable_job(coordinates.clone()).enqueue(&mut conn),
+        Err(EnqueueError::SerializationError(_))
+    );
+    let read_only = conn
+        .build_transaction()
+        .read_only()
+        .run(|conn| Ok::<_, EnqueueError>(HandWrittenJob.enqueue(conn)))?;
+    assert_matches!(read_only, Err(EnqueueError::DatabaseError(_)));
+
+    let pool_builder = r2d2::Pool::builder().connection_timeout(Duration::from_millis(100));
+    let unreachable = Runner::builder(())
+        .connection_pool_builder("postgres://localhost:1/unreachable", pool_builder)
+        .build();
+    assert_matches!(
+        unreachable.enqueue(HandWrittenJob),
+        Err(EnqueueError::NoDatabaseConnection(_))
+    );
+
+    let other = EnqueueError::from(Box::<dyn Error + Send + Sync>::from("quota exceeded"));
+    assert_matches!(other, EnqueueError::Other(_));
+    assert_eq!("quota exceeded", other.to_string());
+    Ok(())
+}
+
+#[test]
+fn jobs_which_fail_validation_are_not_enqueued() -> Fallible<()> {
+    use swirl::schema::background_jobs::dsl::*;
+    use swirl::EnqueueError;
+
+    fn check_dimensions(width: &u32, height: &u32) -> Result<(), String> {
+        if *width == 0 || *height == 0 {
+            Err(format!("{}x{} is empty", width, height))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[swirl::background_job(validate = "check_dimensions")]
+    fn resize_job(width: u32, height: u32) -> Result<(), PerformError> {
+        assert!(width > 0 && height > 0);
         Ok(())
     }
 
     let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let error = resize_job(0, 10).enqueue(&mut conn).unwrap_err();
+    assert_matches!(error, EnqueueError::InvalidArguments(_));
+    assert_eq!("Invalid job arguments: 0x10 is empty", error.to_string());
+    assert_matches!(
+        runner.enqueue(resize_job(10, 0)),
+        Err(EnqueueError::InvalidArguments(_))
+    );
+    assert_eq!(Ok(0), background_jobs.count().get_result(&mut conn));
+
+    resize_job(10, 10).enqueue(&mut conn)?;
+    assert_eq!(Ok(1), background_jobs.count().get_result(&mut conn));
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn the_enqueue_observer_sees_every_enqueued_job() -> Fallible<()> {
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[swirl::background_job]
+    fn audited_job(name: String, count: u32) -> Result<(), PerformError> {
+        assert!(!name.is_empty() && count > 0);
+        Ok(())
+    }
+
+    // Other tests enqueue jobs concurrently, so only this test's jobs are kept
+    let seen = Arc::new(Mutex::new(Vec::new()));
     {
-        let conn = runner.connection_pool().get()?;
-        takes_env_and_conn().enqueue(&conn)?;
-        takes_only_conn().enqueue(&conn)?;
-        takes_connection_pool().enqueue(&conn)?;
-        takes_fully_qualified_conn().enqueue(&conn)?;
-        takes_fully_qualified_pool().enqueue(&conn)?;
+        let seen = seen.clone();
+        swirl::set_enqueue_observer(move |job_type, data| {
+            if job_type == "audited_job" {
+                seen.lock().unwrap().push(data.clone());
+            }
+        });
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    audited_job("first".into(), 1).enqueue(&mut conn)?;
+    runner.enqueue(audited_job("second".into(), 2))?;
+    assert_eq!(
+        vec![
+            json!({"name": "first", "count": 1}),
+            json!({"name": "second", "count": 2}),
+        ],
+        *seen.lock().unwrap()
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn guarded_jobs_are_snoozed_until_their_guard_passes() -> Fallible<()> {
+    use diesel::dsl::now;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use swirl::schema::background_jobs::dsl::*;
+
+    fn flag_is_set(flag: &Arc<AtomicBool>) -> bool {
+        flag.load(Ordering::SeqCst)
+    }
+
+    #[swirl::background_job(guard = "flag_is_set")]
+    fn guarded_job(_flag: &Arc<AtomicBool>) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let flag = Arc::new(AtomicBool::new(false));
+    let runner = TestGuard::runner(flag.clone());
+    let mut conn = runner.connection_pool().get()?;
+    guarded_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(1), background_jobs.count().get_result(&mut conn));
+
+    flag.store(true, Ordering::SeqCst);
+    // Make the snoozed job eligible to run again immediately
+    diesel::update(background_jobs)
+        .set(retry_at.eq(now))
+        .execute(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn jobs_can_return_any_error_convertible_to_perform_error() -> Fallible<()> {
+    #[swirl::background_job]
+    fn returns_anyhow_result(arg: String) -> anyhow::Result<()> {
+        let number = arg.parse::<i32>()?;
+        anyhow::ensure!(number > 0, "{} is not positive", number);
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn returns_string_error(arg: String) -> Result<String, String> {
+        if arg.is_empty() {
+            Err("empty string".into())
+        } else {
+            Ok(arg)
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    returns_anyhow_result("1".into()).enqueue(&mut conn)?;
+    returns_anyhow_result("-1".into()).enqueue(&mut conn)?;
+    returns_anyhow_result("not a number".into()).enqueue(&mut conn)?;
+    returns_string_error("foo".into()).enqueue(&mut conn)?;
+    returns_string_error("".into()).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(3)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn enqueued_jobs_are_stored_under_their_job_type() -> Fallible<()> {
+    use swirl::schema::background_jobs;
+    use swirl::Job;
+
+    #[swirl::background_job]
+    fn stored_under_job_type() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    stored_under_job_type().enqueue(&mut conn)?;
+
+    let job_type = background_jobs::table
+        .select(background_jobs::job_type)
+        .get_result::<String>(&mut conn)?;
+    assert_eq!(stored_under_job_type::Job::JOB_TYPE, job_type);
+    assert_eq!("stored_under_job_type", job_type);
+    Ok(())
+}
+
+#[test]
+fn jobs_without_arguments_can_be_stored_as_null_or_an_empty_object() -> Fallible<()> {
+    use serde_json::json;
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job]
+    fn no_arguments() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    no_arguments().enqueue(&mut conn)?;
+    let stored = background_jobs
+        .select(data)
+        .get_result::<serde_json::Value>(&mut conn)?;
+    assert_eq!(json!({}), stored);
+    diesel::insert_into(background_jobs)
+        .values(&vec![
+            (job_type.eq("no_arguments"), data.eq(json!(null))),
+            (job_type.eq("no_arguments"), data.eq(json!({}))),
+        ])
+        .execute(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(0, background_jobs.count().get_result::<i64>(&mut conn)?);
+    Ok(())
+}
+
+#[test]
+fn jobs_can_opt_out_of_running_in_a_transaction() -> Fallible<()> {
+    use diesel::sql_query;
+
+    #[swirl::background_job(no_transaction = true)]
+    fn create_index_concurrently(conn: &mut PgConnection) -> Result<(), PerformError> {
+        // This fails if run inside of a transaction
+        sql_query(
+            "CREATE INDEX CONCURRENTLY no_transaction_jobs_index ON no_transaction_jobs (id)",
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    sql_query("CREATE TABLE no_transaction_jobs (id INTEGER)").execute(&mut conn)?;
+    create_index_concurrently().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let result = runner.check_for_failed_jobs();
+    let index_count =
+        sql_query("SELECT indexname FROM pg_indexes WHERE indexname = 'no_transaction_jobs_index'")
+            .execute(&mut conn);
+    sql_query("DROP TABLE no_transaction_jobs").execute(&mut conn)?;
+
+    result?;
+    assert_eq!(Ok(1), index_count);
+    Ok(())
+}
+
+// Types used by jobs have to be visible from the module the job is defined in
+#[derive(swirl::Serialize, swirl::Deserialize)]
+#[serde(crate = "swirl::serde")]
+struct Params {
+    name: String,
+    count: i32,
+}
+
+#[test]
+fn arguments_can_be_flattened_into_the_job() -> Fallible<()> {
+    use serde_json::json;
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job]
+    fn takes_flattened_params(#[serde(flatten)] params: Params) -> Result<(), PerformError> {
+        if params.name == "foo" && params.count == 3 {
+            Ok(())
+        } else {
+            Err("wrong params".into())
+        }
     }
 
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    takes_flattened_params(Params {
+        name: "foo".into(),
+        count: 3,
+    })
+    .enqueue(&mut conn)?;
+    let stored = background_jobs
+        .select(data)
+        .get_result::<serde_json::Value>(&mut conn)?;
+    assert_eq!(json!({ "name": "foo", "count": 3 }), stored);
+
     runner.run_all_pending_jobs()?;
     runner.check_for_failed_jobs()?;
     Ok(())
 }
+
+// Stands in for a handle to something which can only be stored by its id
+#[derive(Debug, PartialEq)]
+struct Resource {
+    id: i32,
+    name: String,
+}
+
+impl Resource {
+    fn load(id: i32) -> Self {
+        Self {
+            id,
+            name: format!("resource {}", id),
+        }
+    }
+}
+
+impl swirl::JobArg for Resource {
+    type Storable = i32;
+    type Error = String;
+
+    fn to_storable(&self) -> i32 {
+        self.id
+    }
+
+    fn from_storable(id: i32) -> Result<Self, String> {
+        if id > 0 {
+            Ok(Self::load(id))
+        } else {
+            Err(format!("There is no resource with id {}", id))
+        }
+    }
+}
+
+#[test]
+fn arguments_can_be_stored_as_another_type() -> Fallible<()> {
+    use serde_json::json;
+    use swirl::schema::background_jobs::dsl::*;
+
+    #[swirl::background_job]
+    fn takes_resource(#[job_arg] resource: Resource, count: i32) -> Result<(), PerformError> {
+        assert_eq!(Resource::load(7), resource);
+        assert_eq!(3, count);
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    takes_resource(Resource::load(7), 3).enqueue(&mut conn)?;
+    let stored = background_jobs
+        .select(data)
+        .get_result::<serde_json::Value>(&mut conn)?;
+    assert_eq!(json!({ "resource": 7, "count": 3 }), stored);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    diesel::insert_into(background_jobs)
+        .values((
+            job_type.eq("takes_resource"),
+            data.eq(json!({ "resource": 0, "count": 3 })),
+        ))
+        .execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_enqueued_from_an_async_connection() -> Fallible<()> {
+    use diesel_async::pooled_connection::deadpool::Pool;
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use diesel_async::AsyncPgConnection;
+
+    #[swirl::background_job]
+    fn enqueued_async(arg: String) -> Result<(), PerformError> {
+        if arg == "foo" {
+            Ok(())
+        } else {
+            Err("arg wasn't foo!".into())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let database_url =
+        dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(database_url);
+    let pool = Pool::builder(manager).build()?;
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let mut conn = pool.get().await?;
+            enqueued_async("foo".into())
+                .enqueue_async(&mut conn)
+                .await?;
+            enqueued_async("bar".into())
+                .enqueue_async(&mut conn)
+                .await?;
+            Ok::<_, failure::Error>(())
+        })?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
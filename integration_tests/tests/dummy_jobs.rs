@@ -1,6 +1,9 @@
 pub use swirl::Job;
 
+use diesel::prelude::*;
+use std::time::Duration;
 use swirl::errors::PerformError;
+use swirl::JobContext;
 
 use crate::sync::Barrier;
 
@@ -18,8 +21,53 @@ pub fn failure_job() -> Result<(), PerformError> {
     Err("failed".into())
 }
 
+#[swirl::background_job]
+/// A job which fails and asks to be retried in 30 seconds
+pub fn retry_after_job() -> Result<(), PerformError> {
+    Err(swirl::RetryAfter(Duration::from_secs(30), "rate limited".into()).into())
+}
+
 #[swirl::background_job]
 /// A job which panics
 pub fn panic_job() -> Result<(), PerformError> {
     panic!()
 }
+
+/// A job which implements `Job` by hand rather than through
+/// `#[swirl::background_job]`. It checks that its row is visible through the
+/// connection it was given, and locked when seen through a connection from the
+/// pool.
+#[derive(swirl::Serialize, swirl::Deserialize)]
+#[serde(crate = "swirl::serde")]
+pub struct HandWrittenJob;
+
+impl Job for HandWrittenJob {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "hand_written_job";
+
+    fn perform(
+        self,
+        _: &Self::Environment,
+        conn: &mut PgConnection,
+        ctx: &JobContext<'_>,
+    ) -> Result<(), PerformError> {
+        use swirl::schema::background_jobs::dsl::*;
+
+        let visible = background_jobs
+            .find(ctx.id())
+            .count()
+            .get_result::<i64>(conn)?;
+        let unlocked = background_jobs
+            .find(ctx.id())
+            .select(id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&mut **ctx.pool().get()?)?;
+        if visible != 1 || !unlocked.is_empty() {
+            return Err("job row was not locked by the given connection".into());
+        }
+        Ok(())
+    }
+}
+
+swirl::register_job!(HandWrittenJob);
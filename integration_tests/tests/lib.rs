@@ -7,4 +7,5 @@ mod test_guard;
 mod util;
 
 mod codegen;
+mod registry;
 mod runner;
@@ -0,0 +1,207 @@
+use failure::Fallible;
+use swirl::{JobsFailed, PerformError, Registry};
+
+use crate::sync::Barrier;
+use crate::test_guard::TestGuard;
+
+#[test]
+fn check_env_coverage_reports_jobs_with_other_environments() {
+    let error = Registry::<()>::load()
+        .check_env_coverage()
+        .expect_err("jobs with other environments should be reported");
+    let job_types = error.job_types().collect::<Vec<_>>();
+
+    assert!(job_types.contains(&"barrier_job"));
+    assert!(!job_types.contains(&"failure_job"));
+    assert!(error
+        .to_string()
+        .contains("barrier_job (`integration_tests::sync::Barrier`)"));
+
+    let job_types = Registry::<Barrier>::load()
+        .check_env_coverage()
+        .unwrap_err()
+        .job_types()
+        .collect::<Vec<_>>();
+    assert!(!job_types.contains(&"barrier_job"));
+    assert!(job_types.contains(&"failure_job"));
+}
+
+#[test]
+#[should_panic(expected = "No jobs are registered for the environment type `u8`")]
+fn strict_runners_panic_when_no_jobs_are_registered() {
+    TestGuard::builder(0u8)
+        .require_non_empty_registry(true)
+        .build();
+}
+
+#[test]
+fn strict_runners_build_when_jobs_are_registered() {
+    TestGuard::builder(())
+        .require_non_empty_registry(true)
+        .build();
+}
+
+#[test]
+fn jobs_can_be_registered_after_the_runner_is_built() -> Fallible<()> {
+    use diesel::prelude::*;
+    use swirl::{Job, JobContext};
+
+    // Not registered with `register_job!`, so it isn't loaded on build
+    #[derive(swirl::Serialize, swirl::Deserialize)]
+    #[serde(crate = "swirl::serde")]
+    struct PluginJob;
+
+    impl Job for PluginJob {
+        type Environment = u16;
+        const JOB_TYPE: &'static str = "plugin_job";
+
+        fn perform(
+            self,
+            _: &Self::Environment,
+            _: &mut PgConnection,
+            _: &JobContext<'_>,
+        ) -> Result<(), PerformError> {
+            Ok(())
+        }
+    }
+
+    let runner = TestGuard::runner(0u16);
+    runner.enqueue(PluginJob)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    runner.register_job::<PluginJob>();
+    let mut conn = runner.connection_pool().get()?;
+    diesel::update(swirl::schema::background_jobs::table)
+        .set(swirl::schema::background_jobs::retry_at.eq(diesel::dsl::now))
+        .execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn runners_can_be_given_a_registry_built_without_inventory() -> Fallible<()> {
+    use diesel::prelude::*;
+    use swirl::{Job, JobContext};
+
+    // Not registered with `register_job!`
+    #[derive(swirl::Serialize, swirl::Deserialize)]
+    #[serde(crate = "swirl::serde")]
+    struct ListedJob;
+
+    impl Job for ListedJob {
+        type Environment = u64;
+        const JOB_TYPE: &'static str = "listed_job";
+
+        fn perform(
+            self,
+            _: &Self::Environment,
+            _: &mut PgConnection,
+            _: &JobContext<'_>,
+        ) -> Result<(), PerformError> {
+            Ok(())
+        }
+    }
+
+    swirl::jobs! {
+        fn listed_jobs() -> Registry<u64> {
+            ListedJob,
+        }
+    }
+
+    assert!(Registry::<u64>::load().is_empty());
+    assert_eq!(
+        vec!["listed_job"],
+        listed_jobs().job_types().collect::<Vec<_>>()
+    );
+
+    let runner = TestGuard::builder(0u64)
+        .registry(listed_jobs())
+        .require_non_empty_registry(true)
+        .build();
+    runner.enqueue(ListedJob)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn registry_metadata_reflects_job_attributes() {
+    use swirl::JobMeta;
+
+    #[swirl::background_job(no_transaction = true, exclusive = true, weight = 3)]
+    fn maintenance_job(_env: &i16) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn ordinary_job(_env: &i16) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let registry = Registry::<i16>::load();
+    let mut job_types = registry.job_types().collect::<Vec<_>>();
+    job_types.sort();
+    assert_eq!(vec!["maintenance_job", "ordinary_job"], job_types);
+
+    assert_eq!(
+        Some(JobMeta {
+            job_type: "maintenance_job",
+            env_type_name: "i16",
+            in_transaction: false,
+            exclusive: true,
+            at_most_once: false,
+            statement_timeout: None,
+            weight: 3,
+        }),
+        registry.metadata("maintenance_job"),
+    );
+    assert_eq!(
+        Some(JobMeta {
+            job_type: "ordinary_job",
+            env_type_name: "i16",
+            in_transaction: true,
+            exclusive: false,
+            at_most_once: false,
+            statement_timeout: None,
+            weight: 1,
+        }),
+        registry.metadata("ordinary_job"),
+    );
+    assert_eq!(None, registry.metadata("unknown_job"));
+}
+
+mod duplicate_jobs {
+    use swirl::PerformError;
+
+    pub struct DuplicateEnv;
+
+    pub mod first {
+        #[swirl::background_job]
+        pub fn duplicate_job(_env: &super::DuplicateEnv) -> Result<(), super::PerformError> {
+            Ok(())
+        }
+    }
+
+    pub mod second {
+        #[swirl::background_job]
+        pub fn duplicate_job(_env: &super::DuplicateEnv) -> Result<(), super::PerformError> {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "The job type `duplicate_job` is used by both")]
+fn loading_jobs_with_the_same_job_type_panics() {
+    Registry::<duplicate_jobs::DuplicateEnv>::load();
+}
+
+#[test]
+#[should_panic(expected = "The job type `duplicate_job` is used by both")]
+fn registering_jobs_with_the_same_job_type_panics() {
+    let mut registry = Registry::<duplicate_jobs::DuplicateEnv>::new();
+    registry.register::<duplicate_jobs::first::duplicate_job::Job>();
+    registry.register::<duplicate_jobs::second::duplicate_job::Job>();
+}
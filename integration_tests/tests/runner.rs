@@ -1,11 +1,12 @@
 use assert_matches::assert_matches;
 use diesel::prelude::*;
 use failure::Fallible;
+use std::error::Error;
 use std::sync::mpsc::sync_channel;
 use std::thread;
 use std::time::Duration;
 use swirl::schema::*;
-use swirl::JobsFailed;
+use swirl::{FailedJobsError, JobsFailed};
 
 use crate::dummy_jobs::*;
 use crate::sync::Barrier;
@@ -15,18 +16,18 @@ use crate::test_guard::TestGuard;
 fn run_all_pending_jobs_returns_when_all_jobs_enqueued() -> Fallible<()> {
     let barrier = Barrier::new(3);
     let runner = TestGuard::runner(barrier.clone());
-    let conn = runner.connection_pool().get()?;
-    barrier_job().enqueue(&conn)?;
-    barrier_job().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
 
-    let queued_job_count = background_jobs::table.count().get_result(&conn);
+    let queued_job_count = background_jobs::table.count().get_result(&mut conn);
     let unlocked_job_count = background_jobs::table
         .select(background_jobs::id)
         .for_update()
         .skip_locked()
-        .load::<i64>(&conn)
+        .load::<i64>(&mut conn)
         .map(|v| v.len());
 
     assert_eq!(Ok(2), queued_job_count);
@@ -40,9 +41,9 @@ fn run_all_pending_jobs_returns_when_all_jobs_enqueued() -> Fallible<()> {
 fn check_for_failed_jobs_blocks_until_all_queued_jobs_are_finished() -> Fallible<()> {
     let barrier = Barrier::new(3);
     let runner = TestGuard::runner(barrier.clone());
-    let conn = runner.connection_pool().get()?;
-    barrier_job().enqueue(&conn)?;
-    barrier_job().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
 
@@ -68,22 +69,43 @@ fn check_for_failed_jobs_blocks_until_all_queued_jobs_are_finished() -> Fallible
 #[test]
 fn check_for_failed_jobs_panics_if_jobs_failed() -> Fallible<()> {
     let runner = TestGuard::dummy_runner();
-    let conn = runner.connection_pool().get()?;
-    failure_job().enqueue(&conn)?;
-    failure_job().enqueue(&conn)?;
-    failure_job().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     assert_eq!(Err(JobsFailed(3)), runner.check_for_failed_jobs());
     Ok(())
 }
 
+#[test]
+fn failed_jobs_error_can_be_matched_and_displayed() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let error = runner.check_for_failed_jobs().unwrap_err();
+    assert_eq!("1 job failed", error.to_string());
+    assert!(error.source().is_none());
+    assert_matches!(error, FailedJobsError::JobsFailed(1));
+
+    failure_job().enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(
+        "2 jobs failed",
+        runner.check_for_failed_jobs().unwrap_err().to_string()
+    );
+    Ok(())
+}
+
 #[test]
 fn panicking_jobs_are_caught_and_treated_as_failures() -> Fallible<()> {
     let runner = TestGuard::dummy_runner();
-    let conn = runner.connection_pool().get()?;
-    panic_job().enqueue(&conn)?;
-    failure_job().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    panic_job().enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
 
     runner.run_all_pending_jobs()?;
     assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
@@ -99,9 +121,9 @@ fn run_all_pending_jobs_errs_if_jobs_dont_start_in_timeout() -> Fallible<()> {
         .thread_count(1)
         .job_start_timeout(Duration::from_millis(50))
         .build();
-    let conn = runner.connection_pool().get()?;
-    barrier_job().enqueue(&conn)?;
-    barrier_job().enqueue(&conn)?;
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
 
     let run_result = runner.run_all_pending_jobs();
     assert_matches!(run_result, Err(swirl::FetchError::NoMessageReceived));
@@ -113,6 +135,171 @@ fn run_all_pending_jobs_errs_if_jobs_dont_start_in_timeout() -> Fallible<()> {
     Ok(())
 }
 
+#[test]
+fn start_timeout_hook_is_called_before_erroring() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+
+    let barrier = Barrier::new(2);
+    let timeouts = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let timeouts = timeouts.clone();
+        TestGuard::builder(barrier.clone())
+            .thread_count(1)
+            .job_start_timeout(Duration::from_millis(50))
+            .on_job_start_timeout(move |pending| timeouts.lock().unwrap().push(pending))
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
+
+    let run_result = runner.run_all_pending_jobs();
+    assert_matches!(run_result, Err(swirl::FetchError::NoMessageReceived));
+    // The first job is hung, so the second was queued but never started
+    assert_eq!(vec![1], *timeouts.lock().unwrap());
+
+    barrier.wait();
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn busy_workers_do_not_time_out_when_waiting_while_saturated() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn outlasts_start_timeout() -> Result<(), PerformError> {
+        thread::sleep(Duration::from_millis(150));
+        Ok(())
+    }
+
+    // The only worker is busy for three times the timeout before the second
+    // job can start
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .job_start_timeout(Duration::from_millis(50))
+        .wait_while_saturated(true)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    outlasts_start_timeout().enqueue(&mut conn)?;
+    outlasts_start_timeout().enqueue(&mut conn)?;
+
+    let report = runner.run_all_pending_jobs()?;
+    assert_eq!(2, report.started());
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn fail_fast_runs_stop_after_the_first_failure() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn step(succeed: bool) -> Result<(), PerformError> {
+        if succeed {
+            Ok(())
+        } else {
+            Err("step failed".into())
+        }
+    }
+
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .fail_fast(true)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    step(true).enqueue(&mut conn)?;
+    step(false).enqueue(&mut conn)?;
+    step(true).enqueue(&mut conn)?;
+
+    let report = runner.run_all_pending_jobs()?;
+    assert!(report.stopped_after_failure());
+    assert_eq!(2, report.started());
+    assert_eq!(1, report.succeeded());
+    assert_eq!(1, report.failed());
+
+    let pending = runner.dry_run()?;
+    assert_eq!(1, pending.len());
+    let untried_jobs = background_jobs::table
+        .filter(background_jobs::retries.eq(0))
+        .count()
+        .get_result::<i64>(&mut conn)?;
+    assert_eq!(1, untried_jobs);
+    Ok(())
+}
+
+#[test]
+fn continuous_progress_does_not_time_out() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn short_sleep_job() -> Result<(), PerformError> {
+        thread::sleep(Duration::from_millis(40));
+        Ok(())
+    }
+
+    // Each job finishes well within the timeout, but all of them together take
+    // several times longer than it
+    let runner = TestGuard::builder(())
+        .thread_count(2)
+        .job_start_timeout(Duration::from_millis(100))
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    for _ in 0..20 {
+        short_sleep_job().enqueue(&mut conn)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn slow_job_types_can_be_given_a_longer_start_timeout() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn slow_job() -> Result<(), PerformError> {
+        thread::sleep(Duration::from_millis(300));
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn fast_job() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let enqueue_jobs = |runner: &swirl::Runner<(), crate::db::DieselPool>| -> Fallible<()> {
+        let mut conn = runner.connection_pool().get()?;
+        slow_job().enqueue(&mut conn)?;
+        fast_job().enqueue(&mut conn)?;
+        fast_job().enqueue(&mut conn)?;
+        Ok(())
+    };
+
+    // With a single thread, the fast jobs can't start until the slow job is done
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .job_start_timeout(Duration::from_millis(100))
+        .build();
+    enqueue_jobs(&runner)?;
+    let run_result = runner.run_all_pending_jobs();
+    assert_matches!(run_result, Err(swirl::FetchError::NoMessageReceived));
+    runner.check_for_failed_jobs()?;
+    drop(runner);
+
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .job_start_timeout(Duration::from_millis(100))
+        .job_start_timeout_for("slow_job", Duration::from_secs(5))
+        .build();
+    enqueue_jobs(&runner)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
 #[test]
 fn jobs_failing_to_load_doesnt_panic_threads() -> Fallible<()> {
     let runner = TestGuard::builder(())
@@ -121,21 +308,2279 @@ fn jobs_failing_to_load_doesnt_panic_threads() -> Fallible<()> {
         .build();
 
     {
-        let conn = runner.connection_pool().get()?;
-        failure_job().enqueue(&conn)?;
+        let mut conn = runner.connection_pool().get()?;
+        failure_job().enqueue(&mut conn)?;
         // Since jobs are loaded with `SELECT FOR UPDATE`, it will always fail in
         // read-only mode
-        diesel::sql_query("SET default_transaction_read_only = 't'").execute(&conn)?;
+        diesel::sql_query("SET default_transaction_read_only = 't'").execute(&mut conn)?;
     }
 
     let run_result = runner.run_all_pending_jobs();
 
     {
-        let conn = runner.connection_pool().get()?;
-        diesel::sql_query("SET default_transaction_read_only = 'f'").execute(&conn)?;
+        let mut conn = runner.connection_pool().get()?;
+        diesel::sql_query("SET default_transaction_read_only = 'f'").execute(&mut conn)?;
     }
 
     assert_matches!(run_result, Err(swirl::FetchError::FailedLoadingJob(_)));
+    let error = run_result.unwrap_err();
+    assert!(error.is_read_only_transaction());
+    assert!(!error.is_serialization_failure());
+    assert!(!error.is_connection_error());
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn dry_run_lists_pending_jobs_without_running_them() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    panic_job().enqueue(&mut conn)?;
+
+    let pending = runner.dry_run()?;
+    let job_types = pending.iter().map(|(_, t)| t.as_str()).collect::<Vec<_>>();
+    assert_eq!(vec!["failure_job", "panic_job"], job_types);
+
+    let unlocked_untried_jobs = background_jobs::table
+        .select(background_jobs::id)
+        .filter(background_jobs::retries.eq(0))
+        .for_update()
+        .skip_locked()
+        .load::<i64>(&mut conn)?;
+    let pending_ids = pending.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+    assert_eq!(pending_ids, unlocked_untried_jobs);
+
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_are_started_in_the_order_their_transactions_began() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+    use swirl::{Job, JobContext, PerformError};
+
+    type StartedJobs = Arc<Mutex<Vec<i64>>>;
+
+    #[swirl::background_job]
+    fn record_start(started: &StartedJobs, ctx: &JobContext) -> Result<(), PerformError> {
+        started.lock().unwrap().push(ctx.id());
+        Ok(())
+    }
+
+    let started = StartedJobs::default();
+    let runner = TestGuard::builder(started.clone()).thread_count(1).build();
+
+    // The second transaction begins after the first, but gets a lower id for
+    // one of its jobs, and commits before it
+    let (first_ids, second_ids) = {
+        let mut first_conn = runner.connection_pool().get()?;
+        let mut second_conn = runner.connection_pool().get()?;
+        first_conn.transaction(|first_conn| {
+            let early = record_start().enqueue_returning_id(first_conn)?;
+            let second_ids = second_conn.transaction(|second_conn| {
+                Ok::<_, failure::Error>(vec![
+                    record_start().enqueue_returning_id(second_conn)?,
+                    record_start().enqueue_returning_id(second_conn)?,
+                ])
+            })?;
+            let late = record_start().enqueue_returning_id(first_conn)?;
+            Ok::<_, failure::Error>((vec![early, late], second_ids))
+        })?
+    };
+    assert!(first_ids[1] > second_ids[0]);
+    let expected = first_ids.into_iter().chain(second_ids).collect::<Vec<_>>();
+
+    let pending = runner.dry_run()?;
+    assert_eq!(
+        expected,
+        pending.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(expected, *started.lock().unwrap());
+    Ok(())
+}
+
+#[test]
+fn batched_jobs_are_started_in_the_order_they_were_enqueued() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+    use swirl::{EnqueueBatch, Job, JobContext, PerformError};
+
+    type StartedJobs = Arc<Mutex<Vec<i64>>>;
+
+    #[swirl::background_job]
+    fn record_batched_start(started: &StartedJobs, ctx: &JobContext) -> Result<(), PerformError> {
+        started.lock().unwrap().push(ctx.id());
+        Ok(())
+    }
+
+    let started = StartedJobs::default();
+    let runner = TestGuard::builder(started.clone()).thread_count(1).build();
+    let mut conn = runner.connection_pool().get()?;
+
+    let mut expected = vec![record_batched_start().enqueue_returning_id(&mut conn)?];
+    let mut batch = EnqueueBatch::new();
+    batch.push(record_batched_start())?;
+    batch.push(record_batched_start())?;
+    expected.extend(batch.commit(&mut conn)?);
+    expected.push(record_batched_start().enqueue_returning_id(&mut conn)?);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(expected, *started.lock().unwrap());
+    Ok(())
+}
+
+#[test]
+fn is_job_running_is_true_while_a_worker_holds_the_job() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn wait_twice(barrier: &Barrier) -> Result<(), PerformError> {
+        barrier.wait();
+        barrier.wait();
+        Ok(())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    let job_id = wait_twice().enqueue_returning_id(&mut conn)?;
+    assert!(!swirl::is_job_running(&mut conn, job_id)?);
+
+    runner.run_all_pending_jobs()?;
+    // The job has started once it reaches the barrier
+    barrier.wait();
+    assert!(swirl::is_job_running(&mut conn, job_id)?);
+    // Checking doesn't keep the job locked
+    assert!(swirl::is_job_running(&mut conn, job_id)?);
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert!(!swirl::is_job_running(&mut conn, job_id)?);
+    Ok(())
+}
+
+#[test]
+fn claimed_jobs_do_not_hold_a_connection_while_they_run() -> Fallible<()> {
+    use swirl::{FetchMode, PerformError};
+
+    #[swirl::background_job(no_transaction = true)]
+    fn wait_twice_without_transaction(barrier: &Barrier) -> Result<(), PerformError> {
+        barrier.wait();
+        barrier.wait();
+        Ok(())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone())
+        .thread_count(1)
+        .connection_count(3)
+        .fetch_mode(FetchMode::Claim {
+            timeout: Duration::from_secs(60),
+        })
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    let job_id = wait_twice_without_transaction().enqueue_returning_id(&mut conn)?;
+
+    thread::scope(|scope| -> Fallible<()> {
+        // The only worker is running the job, so this can't return until
+        // it's done
+        let run = scope.spawn(|| runner.run_n_pending_jobs(1));
+        barrier.wait();
+        // Only this test's connection, and the one the job was given, are in
+        // use. With row locks, the connection the job was fetched with would
+        // be as well.
+        let state = runner.connection_pool().state();
+        assert_eq!(2, state.connections - state.idle_connections);
+        let locked_by = background_jobs::table
+            .find(job_id)
+            .select(background_jobs::locked_by)
+            .first::<Option<String>>(&mut conn)?;
+        assert!(locked_by.is_some());
+        assert!(swirl::is_job_running(&mut conn, job_id)?);
+        barrier.wait();
+        assert_eq!(1, run.join().unwrap()?);
+        Ok(())
+    })?;
+
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn claimed_jobs_are_run_again_once_their_claim_runs_out() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let crashed_job = HandWrittenJob.enqueue_returning_id(&mut conn)?;
+    let running_job = HandWrittenJob.enqueue_returning_id(&mut conn)?;
+    diesel::update(background_jobs::table.find(crashed_job))
+        .set((
+            background_jobs::locked_by.eq("crashed worker"),
+            background_jobs::locked_until.eq((now - 1.second()).nullable()),
+        ))
+        .execute(&mut conn)?;
+    diesel::update(background_jobs::table.find(running_job))
+        .set((
+            background_jobs::locked_by.eq("running worker"),
+            background_jobs::locked_until.eq((now + 1.hour()).nullable()),
+        ))
+        .execute(&mut conn)?;
+
+    // Runners which lock rows skip claimed jobs as well
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let remaining = background_jobs::table
+        .select(background_jobs::id)
+        .load::<i64>(&mut conn)?;
+    assert_eq!(vec![running_job], remaining);
+    assert!(swirl::is_job_running(&mut conn, running_job)?);
+    Ok(())
+}
+
+#[test]
+fn at_most_once_jobs_are_not_run_again_after_a_crash() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job(delivery = "at_most_once")]
+    fn charge_card(barrier: &Barrier) -> Result<(), PerformError> {
+        barrier.wait();
+        barrier.wait();
+        Ok(())
+    }
+
+    #[swirl::background_job(delivery = "at_most_once")]
+    fn decline_card(_barrier: &Barrier) -> Result<(), PerformError> {
+        Err("card declined".into())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    let job_id = charge_card().enqueue_returning_id(&mut conn)?;
+
+    thread::scope(|scope| -> Fallible<()> {
+        let run = scope.spawn(|| runner.run_n_pending_jobs(1));
+        barrier.wait();
+        // The attempt was committed before the job started. If the runner
+        // crashed now, the job's row would be unlocked, but no worker would
+        // pick it up again.
+        let (retries, abandoned) = background_jobs::table
+            .find(job_id)
+            .select((background_jobs::retries, background_jobs::abandoned))
+            .first::<(i32, bool)>(&mut conn)?;
+        assert_eq!((1, true), (retries, abandoned));
+        assert!(swirl::is_job_running(&mut conn, job_id)?);
+        barrier.wait();
+        assert_eq!(1, run.join().unwrap()?);
+        Ok(())
+    })?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+
+    // Failed jobs may have done part of their work, so they aren't retried
+    let job_id = decline_card().enqueue_returning_id(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert!(runner.dry_run()?.is_empty());
+    let (retries, abandoned) = background_jobs::table
+        .find(job_id)
+        .select((background_jobs::retries, background_jobs::abandoned))
+        .first::<(i32, bool)>(&mut conn)?;
+    assert_eq!((1, true), (retries, abandoned));
+    Ok(())
+}
+
+#[test]
+fn fetch_stats_count_every_query_for_the_next_job() -> Fallible<()> {
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .connection_count(3)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    assert_eq!(None, runner.fetch_stats().average_fetch_time());
+    for _ in 0..3 {
+        HandWrittenJob.enqueue(&mut conn)?;
+    }
+
+    // Each job is fetched with its own query, and stopping at the limit
+    // doesn't need another one
+    let before = runner.fetch_stats();
+    runner.run_n_pending_jobs(3)?;
+    runner.check_for_failed_jobs()?;
+    let stats = runner.fetch_stats().since(&before);
+    assert_eq!(3, stats.fetches());
+    assert_eq!(0, stats.empty_fetches());
+
+    // Finding out the queue is empty takes one more
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let stats = runner.fetch_stats();
+    assert_eq!(4, stats.fetches());
+    assert_eq!(1, stats.empty_fetches());
+    let average = stats.average_fetch_time().unwrap();
+    assert!(average > Duration::ZERO);
+    assert!(average <= stats.total_fetch_time());
+    Ok(())
+}
+
+#[test]
+fn jobs_running_past_the_threshold_are_reported_as_stuck() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn returns_right_away(_barrier: &Barrier) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone()).thread_count(2).build();
+    let mut conn = runner.connection_pool().get()?;
+    returns_right_away().enqueue(&mut conn)?;
+    let stuck_id = barrier_job().enqueue_returning_id(&mut conn)?;
+    assert_eq!(
+        Vec::<swirl::StuckJob>::new(),
+        runner.stuck_jobs(Duration::ZERO)
+    );
+
+    runner.run_all_pending_jobs()?;
+    thread::sleep(Duration::from_millis(100));
+    let stuck_jobs = runner.stuck_jobs(Duration::from_millis(50));
+    assert_eq!(
+        vec![(stuck_id, "barrier_job")],
+        stuck_jobs
+            .iter()
+            .map(|job| (job.id, &*job.job_type))
+            .collect::<Vec<_>>()
+    );
+    assert!(stuck_jobs[0].running_for >= Duration::from_millis(100));
+    assert!(runner.stuck_jobs(Duration::from_secs(60)).is_empty());
+
+    // Reporting a job doesn't stop it
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert!(runner.stuck_jobs(Duration::ZERO).is_empty());
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn lazy_threads_are_only_started_once_there_are_jobs_for_them() -> Fallible<()> {
+    // Thread names are cut off at 15 bytes on Linux
+    let worker_threads = || -> Fallible<usize> {
+        let mut count = 0;
+        for task in std::fs::read_dir("/proc/self/task")? {
+            let name = std::fs::read_to_string(task?.path().join("comm"))?;
+            if name.starts_with("lazy-worker-") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    };
+
+    let barrier = Barrier::new(3);
+    let runner = TestGuard::builder(barrier.clone())
+        .thread_count(5)
+        .thread_name_prefix("lazy-worker")
+        .lazy_threads(true)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    assert_eq!(0, worker_threads()?);
+
+    // Finding out the queue is empty takes a single thread
+    runner.run_all_pending_jobs()?;
+    assert_eq!(1, worker_threads()?);
+
+    // The jobs wait for each other, so each needs a thread of its own, and
+    // another finds the queue empty
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert_eq!(3, worker_threads()?);
+    Ok(())
+}
+
+#[test]
+fn failed_jobs_can_be_retried_once_the_problem_is_fixed() -> Fallible<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use swirl::{JobFilter, PerformError};
+
+    #[swirl::background_job]
+    fn send_mail(smtp_configured: &Arc<AtomicBool>) -> Result<(), PerformError> {
+        if smtp_configured.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err("SMTP is not configured".into())
+        }
+    }
+
+    #[swirl::background_job]
+    fn send_text(_smtp_configured: &Arc<AtomicBool>) -> Result<(), PerformError> {
+        Err("no SMS provider".into())
+    }
+
+    let smtp_configured = Arc::new(AtomicBool::new(false));
+    let runner = TestGuard::builder(smtp_configured.clone())
+        .max_retries(1)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    for _ in 0..3 {
+        send_mail().enqueue(&mut conn)?;
+    }
+    send_text().enqueue(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(4)), runner.check_for_failed_jobs());
+    // Only one attempt is allowed, so none of them would be run again
+    assert!(runner.dry_run()?.is_empty());
+
+    smtp_configured.store(true, Ordering::SeqCst);
+    let retried = runner.retry_failed_jobs(JobFilter::new().job_type("send_mail"))?;
+    assert_eq!(3, retried);
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let remaining = background_jobs::table
+        .select(background_jobs::job_type)
+        .load::<String>(&mut conn)?;
+    assert_eq!(vec!["send_text"], remaining);
+    Ok(())
+}
+
+#[test]
+fn old_abandoned_jobs_are_pruned() -> Fallible<()> {
+    use diesel::connection::SimpleConnection;
+
+    let runner = TestGuard::builder(())
+        .abandoned_job_ttl(Duration::from_secs(60 * 60))
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    // More than one batch of old abandoned jobs, one recent abandoned job, and
+    // an old job which is still being retried
+    conn.batch_execute(
+        "INSERT INTO background_jobs (job_type, data, retries, abandoned, last_retry) \
+         SELECT 'failure_job', '{}', 5, true, NOW() - INTERVAL '2 hours' \
+         FROM generate_series(1, 1500); \
+         INSERT INTO background_jobs (job_type, data, retries, abandoned, last_retry) \
+         VALUES ('recently_abandoned', '{}', 5, true, NOW()), \
+                ('still_retrying', '{}', 1, false, NOW() - INTERVAL '2 hours');",
+    )?;
+
+    assert_eq!(1500, runner.prune_abandoned_jobs()?);
+    let mut remaining = background_jobs::table
+        .select(background_jobs::job_type)
+        .load::<String>(&mut conn)?;
+    remaining.sort();
+    assert_eq!(vec!["recently_abandoned", "still_retrying"], remaining);
+    assert_eq!(0, runner.prune_abandoned_jobs()?);
+    Ok(())
+}
+
+#[test]
+fn payloads_can_be_transformed_before_jobs_are_deserialized() -> Fallible<()> {
+    use diesel::connection::SimpleConnection;
+    use std::sync::{Arc, Mutex};
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn greet_user(greeted: &Arc<Mutex<Vec<String>>>, name: String) -> Result<(), PerformError> {
+        greeted.lock().unwrap().push(name);
+        Ok(())
+    }
+
+    let greeted = Arc::new(Mutex::new(Vec::<String>::new()));
+    let runner = TestGuard::builder(greeted.clone())
+        .transform_payload(|job_type, data| {
+            assert_eq!("greet_user", job_type);
+            let data = data.as_object_mut().unwrap();
+            if data.contains_key("password") {
+                return Err("jobs must not be given passwords".into());
+            }
+            // The argument used to be called `username`
+            if let Some(name) = data.remove("username") {
+                data.insert("name".into(), name);
+            }
+            Ok(())
+        })
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    greet_user("Ferris".into()).enqueue(&mut conn)?;
+    conn.batch_execute(
+        "INSERT INTO background_jobs (job_type, data) VALUES \
+         ('greet_user', '{\"username\": \"Corro\"}'), \
+         ('greet_user', '{\"name\": \"Mallory\", \"password\": \"hunter2\"}')",
+    )?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let mut greeted = greeted.lock().unwrap().clone();
+    greeted.sort();
+    assert_eq!(vec!["Corro", "Ferris"], greeted);
+    Ok(())
+}
+
+#[test]
+fn run_to_completion_reports_every_job_it_ran() -> Fallible<()> {
+    use swirl::RunError;
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    HandWrittenJob.enqueue(&mut conn)?;
+    HandWrittenJob.enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
+    panic_job().enqueue(&mut conn)?;
+
+    let report = assert_matches!(runner.run_to_completion(), Err(RunError::JobsFailed(r)) => r);
+    assert_eq!(4, report.started());
+    assert_eq!(2, report.succeeded());
+    assert_eq!(2, report.failed());
+
+    // Jobs which failed earlier and are waiting to be retried don't count
+    HandWrittenJob.enqueue(&mut conn)?;
+    let report = runner.run_to_completion()?;
+    assert_eq!(1, report.started());
+    assert_eq!(1, report.succeeded());
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn peek_next_returns_the_job_a_worker_would_pick_up() -> Fallible<()> {
+    use diesel::dsl::IntervalDsl;
+    use diesel::sql_query;
+    use diesel::sql_types::BigInt;
+
+    #[derive(QueryableByName)]
+    struct FetchedJob {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    assert_eq!(None, runner.peek_next()?);
+
+    let first = failure_job().enqueue_returning_id(&mut conn)?;
+    HandWrittenJob.enqueue_after(&mut conn, first)?;
+    let last = panic_job().enqueue_returning_id(&mut conn)?;
+    assert_eq!(Some((first, "failure_job".into())), runner.peek_next()?);
+
+    // Neither the job waiting to be retried, nor the one waiting for it, is
+    // ready to run
+    diesel::update(background_jobs::table.find(first))
+        .set(background_jobs::retry_at.eq(diesel::dsl::now + 1.hour()))
+        .execute(&mut conn)?;
+    conn.transaction::<_, failure::Error, _>(|conn| {
+        let fetched = sql_query(swirl::sql::FETCH_NEXT_JOB).get_result::<FetchedJob>(conn)?;
+        assert_eq!(last, fetched.id);
+        // The job stays visible while another worker has it locked
+        assert_eq!(Some((last, "panic_job".into())), runner.peek_next()?);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[test]
+fn the_queue_can_be_inspected_through_a_read_only_pool() -> Fallible<()> {
+    use diesel::r2d2;
+    use diesel::sql_query;
+
+    // Stands in for a read replica, which refuses to write
+    #[derive(Debug)]
+    struct ReadOnly;
+
+    impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for ReadOnly {
+        fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+            sql_query("SET default_transaction_read_only = on")
+                .execute(conn)
+                .map_err(r2d2::Error::QueryError)?;
+            Ok(())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let database_url = dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let read_pool = r2d2::Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(ReadOnly))
+        .build(r2d2::ConnectionManager::<PgConnection>::new(database_url))?;
+    let mut conn = runner.connection_pool().get()?;
+    assert_eq!(None, runner.peek_next_on(&read_pool)?);
+
+    let first = failure_job().enqueue_returning_id(&mut conn)?;
+    let second = HandWrittenJob.enqueue_returning_id(&mut conn)?;
+    assert_eq!(
+        vec![
+            (first, "failure_job".to_string()),
+            (second, "hand_written_job".to_string())
+        ],
+        runner.dry_run_on(&read_pool)?
+    );
+    assert_eq!(
+        Some((first, "failure_job".into())),
+        runner.peek_next_on(&read_pool)?
+    );
+
+    // The pool really can't write
+    let insert = sql_query("INSERT INTO background_jobs (job_type, data) VALUES ('x', '{}')")
+        .execute(&mut *read_pool.get()?);
+    assert!(insert.is_err());
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_run_by_a_rayon_executor() -> Fallible<()> {
+    use rayon::prelude::*;
+    use swirl::{PerformError, RayonExecutor};
+
+    #[swirl::background_job]
+    fn sum_in_parallel() -> Result<(), PerformError> {
+        assert!(rayon::current_thread_index().is_some());
+        // Runs on the same pool as the job itself
+        let sum = (1..=100u64).into_par_iter().sum::<u64>();
+        assert_eq!(5050, sum);
+        Ok(())
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build()?;
+    let runner = TestGuard::builder(())
+        .executor(RayonExecutor::new(pool))
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    for _ in 0..4 {
+        sum_in_parallel().enqueue(&mut conn)?;
+    }
+    failure_job().enqueue(&mut conn)?;
+    panic_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+    assert_eq!(Ok(2), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn worker_threads_are_named() -> Fallible<()> {
+    use crate::test_guard::GuardBuilder;
+    use std::sync::{Arc, Mutex};
+
+    type ThreadNames = Arc<Mutex<Vec<String>>>;
+
+    // Each job waits for the others, so they all run on threads of their own
+    #[swirl::background_job]
+    fn record_thread_name(env: &(ThreadNames, Barrier)) -> Result<(), swirl::PerformError> {
+        let thread = thread::current();
+        let name = thread.name().ok_or("thread has no name")?;
+        env.0.lock().unwrap().push(name.into());
+        env.1.wait();
+        Ok(())
+    }
+
+    let run = |names: ThreadNames, builder: GuardBuilder<_>, jobs| -> Fallible<Vec<String>> {
+        let runner = builder.build();
+        let mut conn = runner.connection_pool().get()?;
+        for _ in 0..jobs {
+            record_thread_name().enqueue(&mut conn)?;
+        }
+        runner.run_all_pending_jobs()?;
+        runner.check_for_failed_jobs()?;
+        let mut names = names.lock().unwrap().clone();
+        names.sort();
+        Ok(names)
+    };
+
+    let names = ThreadNames::default();
+    let builder = TestGuard::builder((names.clone(), Barrier::new(1))).thread_count(1);
+    assert_eq!(vec!["swirl-worker-0"], run(names, builder, 1)?);
+
+    let names = ThreadNames::default();
+    let builder = TestGuard::builder((names.clone(), Barrier::new(2)))
+        .thread_count(2)
+        .thread_name_prefix("custom-name");
+    assert_eq!(
+        vec!["custom-name-0", "custom-name-1"],
+        run(names, builder, 2)?
+    );
+    Ok(())
+}
+
+#[test]
+fn panicking_jobs_are_not_printed_by_the_default_panic_hook() -> Fallible<()> {
+    use std::env;
+    use std::process::Command;
+
+    const CHILD_ENV_VAR: &str = "SWIRL_TEST_PANIC_HOOK_CHILD";
+
+    if env::var_os(CHILD_ENV_VAR).is_some() {
+        let runner = TestGuard::dummy_runner();
+        let mut conn = runner.connection_pool().get()?;
+        panic_job().enqueue(&mut conn)?;
+
+        runner.run_all_pending_jobs()?;
+        assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+        return Ok(());
+    }
+
+    // The panic hook is global, so we run the job in a separate process to
+    // see what it prints. Hold the lock so no other tests touch the database
+    // while the child process is using it.
+    let _guard = TestGuard::dummy_runner();
+    let output = Command::new(env::current_exe()?)
+        .args([
+            "runner::panicking_jobs_are_not_printed_by_the_default_panic_hook",
+            "--exact",
+            "--nocapture",
+        ])
+        .env(CHILD_ENV_VAR, "1")
+        .output()?;
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(output.status.success(), "child process failed: {}", stderr);
+    assert!(stderr.contains("job panicked"), "stderr: {}", stderr);
+    assert!(
+        !stderr.contains("thread 'swirl-worker' panicked"),
+        "stderr: {}",
+        stderr
+    );
+    Ok(())
+}
+
+#[test]
+fn retry_jitter_spreads_out_retries() -> Fallible<()> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::Double;
+
+    let retry_delays = |runner: &swirl::Runner<(), crate::db::DieselPool>| -> Fallible<Vec<f64>> {
+        let mut conn = runner.connection_pool().get()?;
+        for _ in 0..20 {
+            failure_job().enqueue(&mut conn)?;
+        }
+        runner.run_all_pending_jobs()?;
+        assert_eq!(Err(JobsFailed(20)), runner.check_for_failed_jobs());
+
+        let delays = background_jobs::table
+            .select(sql::<Double>(
+                "EXTRACT(EPOCH FROM retry_at - last_retry)::float8",
+            ))
+            .load(&mut conn)?;
+        Ok(delays)
+    };
+
+    let delays = retry_delays(&TestGuard::dummy_runner())?;
+    assert!(delays.iter().all(|&d| d == 120.0), "{:?}", delays);
+
+    let delays = retry_delays(&TestGuard::builder(()).retry_jitter(0.5).build())?;
+    assert!(
+        delays.iter().all(|&d| (60.0..=120.0).contains(&d)),
+        "{:?}",
+        delays
+    );
+    assert!(delays.iter().any(|&d| d != delays[0]), "{:?}", delays);
+    Ok(())
+}
+
+#[test]
+fn jobs_returning_retry_after_are_retried_after_the_given_delay() -> Fallible<()> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::Double;
+
+    let runner = TestGuard::builder(()).max_retries(2).build();
+    let mut conn = runner.connection_pool().get()?;
+    retry_after_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let (retries, delay, abandoned) = background_jobs::table
+        .select((
+            background_jobs::retries,
+            sql::<Double>("EXTRACT(EPOCH FROM retry_at - last_retry)::float8"),
+            background_jobs::abandoned,
+        ))
+        .first::<(i32, f64, bool)>(&mut conn)?;
+    assert_eq!(1, retries);
+    assert_eq!(30.0, delay);
+    assert!(!abandoned);
+
+    // It still counts towards `max_retries`
+    diesel::update(background_jobs::table)
+        .set(background_jobs::retry_at.eq(diesel::dsl::now))
+        .execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let abandoned = background_jobs::table
+        .select(background_jobs::abandoned)
+        .first::<bool>(&mut conn)?;
+    assert!(abandoned);
+    Ok(())
+}
+
+#[test]
+fn shutdown_with_timeout_reports_jobs_which_are_still_running() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    let job_id = background_jobs::table
+        .select(background_jobs::id)
+        .first::<i64>(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let still_running = runner.shutdown_with_timeout(Duration::from_millis(100));
+    assert_eq!(vec![job_id], still_running);
+
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn wait_for_jobs_can_give_up_after_a_timeout() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let result = runner.wait_for_jobs(Some(Duration::from_millis(50)));
+    assert_eq!(
+        "Timed out waiting for running jobs to finish",
+        result.unwrap_err().to_string()
+    );
+
+    barrier.wait();
+    runner
+        .wait_for_jobs(Some(Duration::from_secs(1)))
+        .map_err(failure::err_msg)?;
+    runner.wait_for_jobs(None).map_err(failure::err_msg)?;
+    Ok(())
+}
+
+#[test]
+fn runner_does_not_start_jobs_after_shutdown() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+
+    let still_running = runner.shutdown_with_timeout(Duration::from_secs(1));
+    assert!(still_running.is_empty());
+
+    failure_job().enqueue(&mut conn)?;
+    let report = runner.run_all_pending_jobs()?;
+    assert_eq!(0, report.started());
+    runner.check_for_failed_jobs()?;
+    let queued_job_count = background_jobs::table.count().get_result(&mut conn);
+    assert_eq!(Ok(1), queued_job_count);
+    Ok(())
+}
+
+#[test]
+fn hooks_are_given_how_long_each_job_took() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn sleep_job(millis: u64, succeed: bool) -> Result<(), PerformError> {
+        thread::sleep(Duration::from_millis(millis));
+        if succeed {
+            Ok(())
+        } else {
+            Err("failed".into())
+        }
+    }
+
+    let successes = Arc::new(Mutex::new(Vec::new()));
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let successes = successes.clone();
+        let failures = failures.clone();
+        TestGuard::builder(())
+            .on_success(move |run| {
+                successes
+                    .lock()
+                    .unwrap()
+                    .push((run.job_type.to_string(), run.duration))
+            })
+            .on_failure(move |run, e| {
+                failures.lock().unwrap().push((
+                    run.job_type.to_string(),
+                    run.duration,
+                    e.to_string(),
+                ))
+            })
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    sleep_job(100, true).enqueue(&mut conn)?;
+    sleep_job(50, false).enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let successes = successes.lock().unwrap();
+    assert_eq!(1, successes.len());
+    assert_eq!("sleep_job", successes[0].0);
+    assert!(successes[0].1 >= Duration::from_millis(100));
+
+    let failures = failures.lock().unwrap();
+    assert_eq!(1, failures.len());
+    assert_eq!("sleep_job", failures[0].0);
+    assert!(failures[0].1 >= Duration::from_millis(50));
+    assert_eq!("failed", failures[0].2);
+    Ok(())
+}
+
+#[test]
+fn failures_are_logged_with_their_sources() -> Fallible<()> {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use swirl::{ErrorChain, PerformError};
+
+    #[derive(Debug)]
+    struct Wrapped(&'static str, Option<Box<Wrapped>>);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.1.as_ref().map(|e| &**e as _)
+        }
+    }
+
+    #[swirl::background_job]
+    fn nested_error_job() -> Result<(), PerformError> {
+        let cause = Wrapped("broken pipe", None);
+        let cause = Wrapped("connection reset", Some(Box::new(cause)));
+        Err(Wrapped("database error", Some(Box::new(cause))).into())
+    }
+
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let failures = failures.clone();
+        TestGuard::builder(())
+            .on_failure(move |_, e| failures.lock().unwrap().push(ErrorChain(&**e).to_string()))
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    nested_error_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert_eq!(
+        vec!["database error: connection reset: broken pipe"],
+        *failures.lock().unwrap()
+    );
+    Ok(())
+}
+
+#[test]
+fn trace_context_is_propagated_into_jobs() -> Fallible<()> {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn check_trace_id(expected: String) -> Result<(), PerformError> {
+        let span = Context::current().span().span_context().clone();
+        assert_eq!(expected, span.trace_id().to_string());
+        Ok(())
+    }
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736")?;
+    let span_context = SpanContext::new(
+        trace_id,
+        SpanId::from_hex("00f067aa0ba902b7")?,
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    {
+        let _guard = Context::current()
+            .with_remote_span_context(span_context)
+            .attach();
+        check_trace_id(trace_id.to_string()).enqueue(&mut conn)?;
+    }
+    check_trace_id(TraceId::INVALID.to_string()).enqueue(&mut conn)?;
+
+    let stored_contexts = background_jobs::table
+        .select(background_jobs::trace_context)
+        .order(background_jobs::id)
+        .load::<Option<serde_json::Value>>(&mut conn)?;
+    assert_eq!(
+        vec![
+            Some(serde_json::json!({
+                "traceparent": "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                "tracestate": "",
+            })),
+            None,
+        ],
+        stored_contexts
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn expired_jobs_are_deleted_without_being_run() -> Fallible<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let expired_count = Arc::new(AtomicUsize::new(0));
+    let runner = {
+        let expired_count = expired_count.clone();
+        TestGuard::builder(())
+            .on_expired(move |run| {
+                assert_eq!("failure_job", run.job_type);
+                expired_count.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue_with_ttl(&mut conn, Duration::from_millis(50))?;
+    failure_job().enqueue_with_ttl(&mut conn, Duration::from_secs(60))?;
+    thread::sleep(Duration::from_millis(100));
+
+    runner.run_all_pending_jobs()?;
+    // Only the job which had not yet expired was run
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert_eq!(1, expired_count.load(Ordering::SeqCst));
+    assert_eq!(Ok(1), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn run_n_pending_jobs_stops_once_it_has_run_enough_jobs() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    for _ in 0..10 {
+        HandWrittenJob.enqueue(&mut conn)?;
+    }
+
+    assert_eq!(3, runner.run_n_pending_jobs(3)?);
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(7), background_jobs::table.count().get_result(&mut conn));
+
+    assert_eq!(7, runner.run_n_pending_jobs(20)?);
+    assert_eq!(0, runner.run_n_pending_jobs(0)?);
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn run_jobs_where_only_runs_matching_jobs() -> Fallible<()> {
+    use swirl::{JobFilter, PerformError};
+
+    #[swirl::background_job]
+    fn other_job() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    other_job().enqueue(&mut conn)?;
+    other_job().enqueue(&mut conn)?;
+
+    runner.run_jobs_where(JobFilter::new().job_type("other_job"))?;
+    runner.check_for_failed_jobs()?;
+    let remaining_jobs = background_jobs::table
+        .select(background_jobs::job_type)
+        .load::<String>(&mut conn)?;
+    assert_eq!(vec!["failure_job"], remaining_jobs);
+
+    other_job().enqueue(&mut conn)?;
+    other_job().enqueue(&mut conn)?;
+    let ids = background_jobs::table
+        .select(background_jobs::id)
+        .filter(background_jobs::job_type.eq("other_job"))
+        .order(background_jobs::id)
+        .load::<i64>(&mut conn)?;
+    runner.run_jobs_where(JobFilter::new().id_range(ids[1]..))?;
+    runner.check_for_failed_jobs()?;
+    let remaining_ids = background_jobs::table
+        .select(background_jobs::id)
+        .filter(background_jobs::job_type.eq("other_job"))
+        .load::<i64>(&mut conn)?;
+    assert_eq!(vec![ids[0]], remaining_ids);
+    Ok(())
+}
+
+#[test]
+fn run_jobs_where_an_id_range_past_the_end_of_i64_matches_no_jobs() -> Fallible<()> {
+    use std::ops::Bound::{Excluded, Unbounded};
+    use swirl::{JobFilter, PerformError};
+
+    #[swirl::background_job]
+    fn job_outside_the_id_range() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    job_outside_the_id_range().enqueue(&mut conn)?;
+
+    runner.run_jobs_where(JobFilter::new().id_range((Excluded(i64::MAX), Unbounded)))?;
+    runner.run_jobs_where(JobFilter::new().id_range((Unbounded, Excluded(i64::MIN))))?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(1), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn run_jobs_where_can_use_an_index_on_an_argument() -> Fallible<()> {
+    use diesel::sql_query;
+    use diesel::sql_types::Text;
+    use swirl::testing::TestRunner;
+    use swirl::{JobFilter, PerformError};
+
+    #[derive(QueryableByName)]
+    struct PlanLine {
+        #[diesel(sql_type = Text, column_name = "QUERY PLAN")]
+        line: String,
+    }
+
+    #[swirl::background_job]
+    fn tenant_job(tenant_id: String) -> Result<(), PerformError> {
+        assert_eq!("a", tenant_id);
+        Ok(())
+    }
+
+    // The index is created in the runner's own schema, so it's dropped along
+    // with the schema instead of being left on the shared table
+    let database_url = dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let runner = TestRunner::new(&*database_url, ()).map_err(failure::err_msg)?;
+    let mut conn = runner.connection_pool().get()?;
+    sql_query(
+        "CREATE INDEX background_jobs_ready_by_tenant_id \
+         ON background_jobs ((data ->> 'tenant_id'), retry_at, id) WHERE NOT abandoned",
+    )
+    .execute(&mut conn)?;
+    let other_tenants = (0..500)
+        .map(|i| {
+            (
+                background_jobs::job_type.eq("tenant_job"),
+                background_jobs::data.eq(serde_json::json!({ "tenant_id": i.to_string() })),
+            )
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(background_jobs::table)
+        .values(&other_tenants)
+        .execute(&mut conn)?;
+    tenant_job("a".into()).enqueue(&mut conn)?;
+    tenant_job("a".into()).enqueue(&mut conn)?;
+    sql_query("ANALYZE background_jobs").execute(&mut conn)?;
+
+    // The runner fetches jobs with the conditions of `FETCH_NEXT_JOB`, and
+    // the argument's condition added to them
+    let filtered_fetch = swirl::sql::FETCH_NEXT_JOB.replacen(
+        "ORDER BY retry_at, id",
+        "AND data ->> 'tenant_id' = 'a' ORDER BY retry_at, id",
+        1,
+    );
+    assert_ne!(swirl::sql::FETCH_NEXT_JOB, filtered_fetch);
+    let plan = sql_query(format!("EXPLAIN {}", filtered_fetch))
+        .load::<PlanLine>(&mut conn)?
+        .into_iter()
+        .map(|plan_line| plan_line.line)
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(
+        plan.contains("background_jobs_ready_by_tenant_id"),
+        "The index wasn't used:\n{}",
+        plan
+    );
+
+    runner.run_jobs_where(JobFilter::new().argument("tenant_id", "a"))?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(
+        Ok(500),
+        background_jobs::table.count().get_result(&mut conn)
+    );
+    Ok(())
+}
+
+#[test]
+fn paused_job_types_are_not_run_until_they_are_resumed() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn email_job() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn report_job() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    swirl::pause_job_type(&mut conn, "email_job")?;
+    swirl::pause_job_type(&mut conn, "email_job")?;
+    email_job().enqueue(&mut conn)?;
+    report_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let remaining_jobs = background_jobs::table
+        .select(background_jobs::job_type)
+        .load::<String>(&mut conn)?;
+    assert_eq!(vec!["email_job"], remaining_jobs);
+    assert_eq!(None, runner.peek_next()?);
+
+    swirl::resume_job_type(&mut conn, "email_job")?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[test]
+fn jobs_are_abandoned_after_max_retries() -> Fallible<()> {
+    let runner = TestGuard::builder(())
+        .thread_count(1)
+        .max_retries(2)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    let retry_now = || {
+        diesel::update(background_jobs::table)
+            .set(background_jobs::retry_at.eq(diesel::dsl::now))
+            .execute(&mut *runner.connection_pool().get().unwrap())
+            .unwrap();
+    };
+
+    let report = runner.run_all_pending_jobs()?;
+    assert_eq!(1, report.started());
+    assert_eq!(1, report.failed());
+    assert_eq!(0, report.abandoned());
+
+    retry_now();
+    let report = runner.run_all_pending_jobs()?;
+    assert_eq!(1, report.failed());
+    assert_eq!(1, report.abandoned());
+
+    retry_now();
+    let report = runner.run_all_pending_jobs()?;
+    assert_eq!(0, report.started());
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn dependent_jobs_wait_for_their_dependency_to_be_deleted() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let dependency = failure_job().enqueue_returning_id(&mut conn)?;
+    let dependent = HandWrittenJob.enqueue_after(&mut conn, dependency)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let depends_on = background_jobs::table
+        .find(dependent)
+        .select(background_jobs::depends_on)
+        .first::<Option<i64>>(&mut conn)?;
+    assert_eq!(Some(dependency), depends_on);
+
+    diesel::delete(background_jobs::table.find(dependency)).execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    Ok(())
+}
+
+#[swirl::background_job]
+fn typed_job(count: u32) -> Result<(), swirl::PerformError> {
+    assert!(count > 0);
+    Ok(())
+}
+
+/// Runs a job whose stored arguments don't match its type with the given
+/// policy, and returns its row's `retries` and `abandoned`, if it still exists
+fn run_bad_payload(policy: swirl::BadPayloadPolicy) -> Fallible<Option<(i32, bool)>> {
+    let runner = TestGuard::builder(()).on_bad_payload(policy).build();
+    let mut conn = runner.connection_pool().get()?;
+    let job_id = diesel::insert_into(background_jobs::table)
+        .values((
+            background_jobs::job_type.eq("typed_job"),
+            background_jobs::data.eq(serde_json::json!({ "count": "many" })),
+        ))
+        .returning(background_jobs::id)
+        .get_result::<i64>(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.wait_for_jobs(None).map_err(failure::err_msg)?;
+    Ok(background_jobs::table
+        .find(job_id)
+        .select((background_jobs::retries, background_jobs::abandoned))
+        .first(&mut conn)
+        .optional()?)
+}
+
+#[test]
+fn bad_payloads_are_handled_according_to_the_policy() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+    use swirl::BadPayloadPolicy;
+
+    assert_eq!(Some((1, false)), run_bad_payload(BadPayloadPolicy::Retry)?);
+    assert_eq!(
+        Some((1, true)),
+        run_bad_payload(BadPayloadPolicy::DeadLetter)?
+    );
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let policy = {
+        let errors = errors.clone();
+        BadPayloadPolicy::custom(move |conn, id, error| {
+            errors.lock().unwrap().push(error.to_string());
+            diesel::delete(background_jobs::table.find(id)).execute(conn)?;
+            Ok(())
+        })
+    };
+    assert_eq!(None, run_bad_payload(policy)?);
+    let errors = errors.lock().unwrap();
+    assert_eq!(1, errors.len());
+    assert!(errors[0].contains("invalid type"), "{}", errors[0]);
+    Ok(())
+}
+
+#[test]
+fn abandon_hook_is_called_once_on_the_final_attempt() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+
+    let abandoned = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let abandoned = abandoned.clone();
+        TestGuard::builder(())
+            .max_retries(3)
+            .on_abandon(move |run, retries, e| {
+                abandoned.lock().unwrap().push((
+                    run.id,
+                    run.job_type.to_string(),
+                    retries,
+                    e.to_string(),
+                ))
+            })
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    let job_id = background_jobs::table
+        .select(background_jobs::id)
+        .first::<i64>(&mut conn)?;
+
+    for _ in 0..3 {
+        assert!(abandoned.lock().unwrap().is_empty());
+        diesel::update(background_jobs::table)
+            .set(background_jobs::retry_at.eq(diesel::dsl::now))
+            .execute(&mut conn)?;
+        runner.run_all_pending_jobs()?;
+        runner.wait_for_jobs(None).map_err(failure::err_msg)?;
+    }
+
+    assert_eq!(
+        vec![(job_id, "failure_job".to_string(), 3, "failed".to_string())],
+        *abandoned.lock().unwrap()
+    );
+    Ok(())
+}
+
+#[test]
+fn hand_written_jobs_get_the_connection_and_pool() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    HandWrittenJob.enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_override_how_they_are_serialized() -> Fallible<()> {
+    use serde_json::Value;
+    use swirl::{JobContext, PerformError};
+
+    #[derive(swirl::Serialize, swirl::Deserialize)]
+    #[serde(crate = "swirl::serde")]
+    struct StoredAsString {
+        value: i32,
+    }
+
+    impl Job for StoredAsString {
+        type Environment = ();
+        const JOB_TYPE: &'static str = "stored_as_string";
+
+        fn to_value(&self) -> Result<Value, serde_json::Error> {
+            Ok(Value::String(self.value.to_string()))
+        }
+
+        fn from_value(value: Value) -> Result<Self, serde_json::Error> {
+            use swirl::serde::de::Error;
+
+            let value = serde_json::from_value::<String>(value)?;
+            value
+                .parse()
+                .map(|value| Self { value })
+                .map_err(serde_json::Error::custom)
+        }
+
+        fn perform(
+            self,
+            _: &Self::Environment,
+            _: &mut PgConnection,
+            _: &JobContext<'_>,
+        ) -> Result<(), PerformError> {
+            if self.value != 42 {
+                return Err(format!("expected 42, got {}", self.value).into());
+            }
+            Ok(())
+        }
+    }
+
+    swirl::register_job!(StoredAsString);
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    StoredAsString { value: 42 }.enqueue(&mut conn)?;
+    let data = background_jobs::table
+        .select(background_jobs::data)
+        .get_result::<Value>(&mut conn)?;
+    assert_eq!(Value::String("42".into()), data);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn concurrency_can_be_raised_for_a_single_run() -> Fallible<()> {
+    // Every job waits until all three are running, so this can only finish
+    // if more jobs run at once than the runner's thread count
+    let barrier = Barrier::new(3);
+    let runner = TestGuard::builder(barrier)
+        .thread_count(1)
+        .connection_count(5)
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut conn)?;
+
+    let report = runner.run_all_pending_jobs_with_concurrency(3)?;
+    assert_eq!(3, report.succeeded());
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_run_inline_without_being_enqueued() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn add_to_env(env: &i32, value: i32) -> Result<(), PerformError> {
+        if env + value == 3 {
+            Ok(())
+        } else {
+            Err(format!("{} + {} is not 3", env, value).into())
+        }
+    }
+
+    let runner = TestGuard::runner(1);
+    runner.run_job_inline(add_to_env(2)).unwrap();
+    let error = runner.run_job_inline(add_to_env(3)).unwrap_err();
+    assert_eq!("1 + 3 is not 3", error.to_string());
+
+    let mut conn = runner.connection_pool().get()?;
+    let job_count = background_jobs::table.count().get_result(&mut conn);
+    assert_eq!(Ok(0), job_count);
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_enqueued_through_the_runner() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    runner.enqueue(barrier_job())?;
+
+    runner.run_all_pending_jobs()?;
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn failures_can_be_recorded_in_custom_columns() -> Fallible<()> {
+    use diesel::sql_query;
+    use diesel::sql_types::{BigInt, Text};
+
+    let runner = TestGuard::builder(())
+        .record_failure(|conn, id, error| {
+            sql_query("UPDATE background_jobs SET last_error = $1 WHERE id = $2")
+                .bind::<Text, _>(error.to_string())
+                .bind::<BigInt, _>(id)
+                .execute(conn)?;
+            Ok(())
+        })
+        .build();
+    let mut conn = runner.connection_pool().get()?;
+    sql_query("ALTER TABLE background_jobs ADD COLUMN last_error TEXT").execute(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let result = runner.check_for_failed_jobs();
+    let last_errors = background_jobs::table
+        .select(diesel::dsl::sql::<diesel::sql_types::Nullable<Text>>(
+            "last_error",
+        ))
+        .load::<Option<String>>(&mut conn);
+    sql_query("ALTER TABLE background_jobs DROP COLUMN last_error").execute(&mut conn)?;
+
+    assert_eq!(Err(JobsFailed(1)), result);
+    assert_eq!(vec![Some(String::from("failed"))], last_errors?);
+    Ok(())
+}
+
+#[test]
+fn panics_can_be_recorded_with_their_backtrace() -> Fallible<()> {
+    use std::sync::{Arc, Mutex};
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let errors = errors.clone();
+        TestGuard::builder(())
+            .panic_backtraces(true)
+            .record_failure(move |_, _, error| {
+                errors.lock().unwrap().push(error.to_string());
+                Ok(())
+            })
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    panic_job().enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+    let mut errors = errors.lock().unwrap().clone();
+    errors.sort();
+    assert_eq!("failed", errors[0]);
+    assert!(errors[1].starts_with("job panicked"), "{}", errors[1]);
+    assert!(errors[1].contains("stack backtrace:"), "{}", errors[1]);
+    assert!(errors[1].contains("panic_job"), "{}", errors[1]);
+    Ok(())
+}
+
+#[test]
+fn jobs_waiting_on_a_starved_pool_fail_instead_of_hanging() -> Fallible<()> {
+    use swirl::db::DieselPoolObj;
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn needs_a_second_connection(pool: &dyn DieselPoolObj) -> Result<(), PerformError> {
+        pool.get()?;
+        Ok(())
+    }
+
+    // The only connection is held by the worker running the job
+    let pool = crate::db::pool_builder().connection_timeout(Duration::from_millis(100));
+    let runner = TestGuard::builder_with_pool((), pool)
+        .thread_count(1)
+        .connection_count(1)
+        .build();
+    runner.enqueue(needs_a_second_connection())?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn fetching_waits_for_connections_held_by_running_jobs() -> Fallible<()> {
+    use crate::db::pool_builder;
+    use swirl::PerformError;
+
+    #[swirl::background_job(no_transaction = true)]
+    fn hold_two_connections(barrier: &Barrier) -> Result<(), PerformError> {
+        barrier.wait();
+        thread::sleep(Duration::from_millis(300));
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn quick_job(_barrier: &Barrier) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    // Once the quick job is done, the other job holds the connection it was
+    // locked with, and the one it was given, which is every connection in
+    // the pool. The worker which is started in place of the quick job can't
+    // get a connection until it finishes.
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder_with_pool(
+        barrier.clone(),
+        pool_builder().connection_timeout(Duration::from_millis(100)),
+    )
+    .thread_count(2)
+    .connection_count(2)
+    .build();
+    runner.enqueue(hold_two_connections())?;
+    runner.enqueue(quick_job())?;
+
+    thread::scope(|scope| {
+        let run = scope.spawn(|| runner.run_all_pending_jobs());
+        barrier.wait();
+        assert_matches!(run.join().unwrap(), Ok(_));
+    });
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn try_build_fails_when_the_database_is_unreachable() {
+    use diesel::r2d2;
+    use swirl::Runner;
+
+    let pool_builder = r2d2::Pool::builder().connection_timeout(Duration::from_millis(100));
+    let result = Runner::builder(())
+        .connection_pool_builder("postgres://localhost:1/unreachable", pool_builder)
+        .try_build();
+    assert!(result.is_err());
+}
+
+#[test]
+fn run_summary_counts_the_jobs_which_ran() -> Fallible<()> {
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn summarized_job() -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(()).log_summary(true).build();
+    runner.enqueue(summarized_job())?;
+    runner.enqueue(summarized_job())?;
+    runner.enqueue(failure_job())?;
+
+    // Unlike `run_all_pending_jobs`, this waits for every job to finish
+    let report = runner.run_all_pending_jobs_with_concurrency(2)?;
+    let summary = report.to_string();
+    assert!(
+        summary.starts_with("Ran 3 jobs (2 succeeded, 1 failed) in "),
+        "unexpected summary: {}",
+        summary,
+    );
+    assert!(summary.ends_with(&format!("{}ms", report.duration().as_millis())));
+    Ok(())
+}
+
+#[test]
+fn ensure_schema_creates_the_table_in_an_empty_database() -> Fallible<()> {
+    use diesel::sql_query;
+    use swirl::Runner;
+
+    // This uses its own database, so it doesn't need the test lock
+    let database_url =
+        dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+    let mut admin_conn = PgConnection::establish(&database_url)?;
+    let server_url = &database_url[..database_url.rfind('/').unwrap()];
+    let empty_database_url = format!("{}/swirl_test_ensure_schema", server_url);
+    sql_query("DROP DATABASE IF EXISTS swirl_test_ensure_schema WITH (FORCE)")
+        .execute(&mut admin_conn)?;
+    sql_query("CREATE DATABASE swirl_test_ensure_schema").execute(&mut admin_conn)?;
+
+    let runner = Runner::builder(()).database_url(empty_database_url).build();
+    runner.ensure_schema().map_err(failure::err_msg)?;
+    // Calling it again has no effect
+    runner.ensure_schema().map_err(failure::err_msg)?;
+
+    runner.enqueue(failure_job())?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    drop(runner);
+    sql_query("DROP DATABASE swirl_test_ensure_schema WITH (FORCE)").execute(&mut admin_conn)?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_run_from_a_hash_partitioned_table() -> Fallible<()> {
+    use diesel::connection::SimpleConnection;
+    use diesel::sql_query;
+    use swirl::Runner;
+
+    // This uses its own database, so it doesn't need the test lock
+    let database_url =
+        dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+    let mut admin_conn = PgConnection::establish(&database_url)?;
+    let server_url = &database_url[..database_url.rfind('/').unwrap()];
+    let partitioned_database_url = format!("{}/swirl_test_partitioned", server_url);
+    sql_query("DROP DATABASE IF EXISTS swirl_test_partitioned WITH (FORCE)")
+        .execute(&mut admin_conn)?;
+    sql_query("CREATE DATABASE swirl_test_partitioned").execute(&mut admin_conn)?;
+
+    let runner = Runner::builder(())
+        .database_url(partitioned_database_url)
+        .build();
+    runner.ensure_schema().map_err(failure::err_msg)?;
+    let mut conn = runner.connection_pool().get()?;
+    conn.batch_execute(
+        "ALTER TABLE background_jobs RENAME TO background_jobs_unpartitioned;
+         CREATE TABLE background_jobs
+             (LIKE background_jobs_unpartitioned INCLUDING DEFAULTS)
+             PARTITION BY HASH (id);
+         ALTER SEQUENCE background_jobs_id_seq OWNED BY background_jobs.id;
+         DROP TABLE background_jobs_unpartitioned;
+         ALTER TABLE background_jobs ADD PRIMARY KEY (id);
+         CREATE INDEX background_jobs_ready ON background_jobs (retry_at, id)
+             WHERE NOT abandoned;
+         CREATE TABLE background_jobs_0 PARTITION OF background_jobs
+             FOR VALUES WITH (MODULUS 4, REMAINDER 0);
+         CREATE TABLE background_jobs_1 PARTITION OF background_jobs
+             FOR VALUES WITH (MODULUS 4, REMAINDER 1);
+         CREATE TABLE background_jobs_2 PARTITION OF background_jobs
+             FOR VALUES WITH (MODULUS 4, REMAINDER 2);
+         CREATE TABLE background_jobs_3 PARTITION OF background_jobs
+             FOR VALUES WITH (MODULUS 4, REMAINDER 3);",
+    )?;
+
+    let dependency = failure_job().enqueue_returning_id(&mut conn)?;
+    HandWrittenJob.enqueue_after(&mut conn, dependency)?;
+    for _ in 0..8 {
+        HandWrittenJob.enqueue(&mut conn)?;
+    }
+    let pending = runner.dry_run()?;
+    let ids = background_jobs::table
+        .select(background_jobs::id)
+        .order(background_jobs::id)
+        .load::<i64>(&mut conn)?;
+    // Jobs are still fetched in order across partitions, skipping the job
+    // waiting on its dependency
+    assert_eq!(9, pending.len());
+    assert_eq!(ids[0], pending[0].0);
+    assert_eq!(
+        ids[2..],
+        pending[1..].iter().map(|(id, _)| *id).collect::<Vec<_>>()[..]
+    );
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let remaining = background_jobs::table
+        .select((background_jobs::id, background_jobs::retries))
+        .order(background_jobs::id)
+        .load::<(i64, i32)>(&mut conn)?;
+    assert_eq!(vec![(ids[0], 1), (ids[1], 0)], remaining);
+
+    diesel::delete(background_jobs::table.find(dependency)).execute(&mut conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+
+    drop(conn);
+    drop(runner);
+    sql_query("DROP DATABASE swirl_test_partitioned WITH (FORCE)").execute(&mut admin_conn)?;
+    Ok(())
+}
+
+#[test]
+fn raw_sql_matches_the_runners_locking_semantics() -> Fallible<()> {
+    use diesel::sql_query;
+    use diesel::sql_types::{BigInt, Bool, Double, Integer, Nullable};
+    use swirl::sql;
+
+    #[derive(QueryableByName)]
+    struct FetchedJob {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+        #[diesel(sql_type = Bool)]
+        expired: bool,
+    }
+
+    #[derive(QueryableByName)]
+    struct FailedJob {
+        #[diesel(sql_type = Bool)]
+        abandoned: bool,
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let mut conn = runner.connection_pool().get()?;
+    let mut other_conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&mut conn)?;
+    failure_job().enqueue(&mut conn)?;
+    let pending_ids = runner
+        .dry_run()?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    swirl::request_cancel(&mut conn, pending_ids[1])?;
+
+    conn.transaction::<_, failure::Error, _>(|conn| {
+        let first = sql_query(sql::FETCH_NEXT_JOB).get_result::<FetchedJob>(conn)?;
+        assert_eq!(pending_ids[0], first.id);
+        assert!(!first.expired);
+
+        // The locked job is skipped by other workers
+        other_conn.transaction::<_, failure::Error, _>(|other_conn| {
+            let second = sql_query(sql::FETCH_NEXT_JOB).get_result::<FetchedJob>(other_conn)?;
+            assert_eq!(pending_ids[1], second.id);
+            sql_query(sql::DELETE_JOB)
+                .bind::<BigInt, _>(second.id)
+                .execute(other_conn)?;
+            Ok(())
+        })?;
+
+        let failed = sql_query(sql::MARK_JOB_FAILED)
+            .bind::<BigInt, _>(first.id)
+            .bind::<Double, _>(0.0)
+            .bind::<Nullable<Integer>, _>(Some(1))
+            .get_result::<FailedJob>(conn)?;
+        assert!(failed.abandoned);
+        Ok(())
+    })?;
+
+    assert!(runner.dry_run()?.is_empty());
+    let (retries, abandoned) = background_jobs::table
+        .select((background_jobs::retries, background_jobs::abandoned))
+        .first::<(i32, bool)>(&mut conn)?;
+    assert_eq!((1, true), (retries, abandoned));
+    assert_eq!(
+        Ok(0),
+        background_job_cancellations::table
+            .count()
+            .get_result(&mut conn)
+    );
+    Ok(())
+}
+
+/// Records whether an exclusive job ever ran at the same time as another job
+#[derive(Clone, Default)]
+pub struct OverlapTracker(std::sync::Arc<OverlapState>);
+
+#[derive(Default)]
+pub struct OverlapState {
+    running: std::sync::atomic::AtomicUsize,
+    exclusive_running: std::sync::atomic::AtomicBool,
+    overlapped: std::sync::atomic::AtomicBool,
+}
+
+#[test]
+fn exclusive_jobs_do_not_overlap_other_jobs() -> Fallible<()> {
+    use std::sync::atomic::Ordering::SeqCst;
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn shared_job(env: &OverlapTracker) -> Result<(), PerformError> {
+        let state = &env.0;
+        state.running.fetch_add(1, SeqCst);
+        for _ in 0..2 {
+            if state.exclusive_running.load(SeqCst) {
+                state.overlapped.store(true, SeqCst);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        state.running.fetch_sub(1, SeqCst);
+        Ok(())
+    }
+
+    #[swirl::background_job(exclusive = true)]
+    fn exclusive_job(env: &OverlapTracker) -> Result<(), PerformError> {
+        let state = &env.0;
+        state.exclusive_running.store(true, SeqCst);
+        for _ in 0..5 {
+            if state.running.load(SeqCst) > 0 {
+                state.overlapped.store(true, SeqCst);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        state.exclusive_running.store(false, SeqCst);
+        Ok(())
+    }
+
+    let tracker = OverlapTracker::default();
+    let runner = TestGuard::builder(tracker.clone())
+        .thread_count(4)
+        .connection_count(8)
+        .build();
+    for _ in 0..2 {
+        for _ in 0..6 {
+            runner.enqueue(shared_job())?;
+        }
+        runner.enqueue(exclusive_job())?;
+    }
+
+    let report = runner.run_all_pending_jobs_with_concurrency(4)?;
+    assert_eq!(14, report.succeeded());
+    runner.check_for_failed_jobs()?;
+    assert!(!tracker.0.overlapped.load(SeqCst));
+    Ok(())
+}
+
+/// Records the largest total weight of the jobs which ran at once
+#[derive(Clone, Default)]
+pub struct WeightTracker(std::sync::Arc<WeightState>);
+
+#[derive(Default)]
+pub struct WeightState {
+    running: std::sync::atomic::AtomicU32,
+    max_running: std::sync::atomic::AtomicU32,
+}
+
+impl WeightTracker {
+    fn run(&self, weight: u32) {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let running = self.0.running.fetch_add(weight, SeqCst) + weight;
+        self.0.max_running.fetch_max(running, SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        self.0.running.fetch_sub(weight, SeqCst);
+    }
+}
+
+#[test]
+fn jobs_do_not_run_together_beyond_the_weight_budget() -> Fallible<()> {
+    use std::sync::atomic::Ordering::SeqCst;
+    use swirl::PerformError;
+
+    #[swirl::background_job(weight = 4)]
+    fn heavy_job(env: &WeightTracker) -> Result<(), PerformError> {
+        env.run(4);
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn light_job(env: &WeightTracker) -> Result<(), PerformError> {
+        env.run(1);
+        Ok(())
+    }
+
+    let tracker = WeightTracker::default();
+    let runner = TestGuard::builder(tracker.clone())
+        .thread_count(4)
+        .connection_count(8)
+        .weight_budget(6)
+        .build();
+    runner.enqueue(heavy_job())?;
+    runner.enqueue(heavy_job())?;
+    runner.enqueue(light_job())?;
+    runner.enqueue(light_job())?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    // The heavy jobs never ran together
+    let max_running = tracker.0.max_running.load(SeqCst);
+    assert!(max_running <= 6, "ran {} weight at once", max_running);
+    Ok(())
+}
+
+#[test]
+fn sharded_runners_run_jobs_from_every_database() -> Fallible<()> {
+    use diesel::dsl::sql;
+    use diesel::sql_query;
+    use diesel::sql_types::Text;
+    use swirl::{PerformError, Runner};
+
+    #[swirl::background_job]
+    fn check_database(conn: &mut PgConnection, expected: String) -> Result<(), PerformError> {
+        let database =
+            diesel::select(sql::<Text>("current_database()")).get_result::<String>(conn)?;
+        if database == expected {
+            Ok(())
+        } else {
+            Err(format!("ran on {} instead of {}", database, expected).into())
+        }
+    }
+
+    let database_url =
+        dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+    let mut admin_conn = PgConnection::establish(&database_url)?;
+    let server_url = &database_url[..database_url.rfind('/').unwrap()];
+    let shard_url = format!("{}/swirl_test_shard", server_url);
+    sql_query("DROP DATABASE IF EXISTS swirl_test_shard WITH (FORCE)").execute(&mut admin_conn)?;
+    sql_query("CREATE DATABASE swirl_test_shard").execute(&mut admin_conn)?;
+    Runner::builder(())
+        .database_url(shard_url.as_str())
+        .build()
+        .ensure_schema()
+        .map_err(failure::err_msg)?;
+
+    let main_database =
+        diesel::select(sql::<Text>("current_database()")).get_result::<String>(&mut admin_conn)?;
+    let mut shard_conn = PgConnection::establish(&shard_url)?;
+    {
+        let runner = TestGuard::sharded_runner((), std::slice::from_ref(&shard_url));
+        let mut main_conn = runner.connection_pool().get()?;
+        for _ in 0..3 {
+            check_database(main_database.clone()).enqueue(&mut main_conn)?;
+            check_database("swirl_test_shard".into()).enqueue(&mut shard_conn)?;
+        }
+
+        runner.run_all_pending_jobs()?;
+        runner.check_for_failed_jobs()?;
+        let main_jobs = background_jobs::table.count().get_result(&mut main_conn);
+        let shard_jobs = background_jobs::table.count().get_result(&mut shard_conn);
+        assert_eq!((Ok(0), Ok(0)), (main_jobs, shard_jobs));
+    }
+
+    drop(shard_conn);
+    sql_query("DROP DATABASE swirl_test_shard WITH (FORCE)").execute(&mut admin_conn)?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_given_connections_from_a_pool_of_their_own() -> Fallible<()> {
+    use diesel::dsl::sql;
+    use diesel::r2d2;
+    use diesel::sql_query;
+    use diesel::sql_types::Text;
+    use swirl::PerformError;
+
+    fn application_name(conn: &mut PgConnection) -> QueryResult<String> {
+        diesel::select(sql::<Text>("current_setting('application_name')")).get_result(conn)
+    }
+
+    #[swirl::background_job]
+    fn uses_own_pool(conn: &mut PgConnection) -> Result<(), PerformError> {
+        assert_eq!("swirl_job_pool", application_name(conn)?);
+        Ok(())
+    }
+
+    #[swirl::background_job(no_transaction = true)]
+    fn uses_runner_pool(conn: &mut PgConnection) -> Result<(), PerformError> {
+        assert_ne!("swirl_job_pool", application_name(conn)?);
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct SetApplicationName;
+
+    impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for SetApplicationName {
+        fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+            sql_query("SET application_name = 'swirl_job_pool'")
+                .execute(conn)
+                .map_err(r2d2::Error::QueryError)?;
+            Ok(())
+        }
+    }
+
+    let database_url =
+        dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+    let job_pool = r2d2::Pool::builder()
+        .max_size(2)
+        .connection_customizer(Box::new(SetApplicationName))
+        .build_unchecked(r2d2::ConnectionManager::new(database_url));
+    let runner = TestGuard::runner_with_job_pool((), "uses_own_pool", job_pool.clone());
+    let mut conn = runner.connection_pool().get()?;
+    for _ in 0..2 {
+        uses_own_pool().enqueue(&mut conn)?;
+        uses_runner_pool().enqueue(&mut conn)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
+    assert!(job_pool.state().connections > 0);
+    Ok(())
+}
+
+#[test]
+fn commit_strategy_decides_whether_work_survives_a_failed_status_update() -> Fallible<()> {
+    use diesel::sql_query;
+    use swirl::{CommitStrategy, PerformError};
+
+    #[swirl::background_job]
+    fn record_work(conn: &mut PgConnection) -> Result<(), PerformError> {
+        sql_query("INSERT INTO commit_strategy_work VALUES (1)").execute(conn)?;
+        Ok(())
+    }
+
+    #[derive(QueryableByName)]
+    struct Count {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        count: i64,
+    }
+
+    let work_after_crash = |strategy| -> Fallible<(i64, i64)> {
+        let runner = TestGuard::builder(()).commit_strategy(strategy).build();
+        let mut conn = runner.connection_pool().get()?;
+        sql_query("CREATE TABLE commit_strategy_work (id INTEGER)").execute(&mut conn)?;
+        // Simulates the runner crashing after the job finished, but before
+        // its row was deleted
+        sql_query(
+            "CREATE FUNCTION fail_job_deletion() RETURNS trigger AS $$ \
+             BEGIN RAISE EXCEPTION 'simulated crash'; END $$ LANGUAGE plpgsql",
+        )
+        .execute(&mut conn)?;
+        sql_query(
+            "CREATE TRIGGER fail_job_deletion BEFORE DELETE ON background_jobs \
+             FOR EACH ROW EXECUTE FUNCTION fail_job_deletion()",
+        )
+        .execute(&mut conn)?;
+        record_work().enqueue(&mut conn)?;
+
+        runner.run_all_pending_jobs()?;
+        let result = runner.check_for_failed_jobs();
+
+        sql_query("DROP TRIGGER fail_job_deletion ON background_jobs").execute(&mut conn)?;
+        sql_query("DROP FUNCTION fail_job_deletion").execute(&mut conn)?;
+        let work = sql_query("SELECT COUNT(*) AS count FROM commit_strategy_work")
+            .get_result::<Count>(&mut conn)?
+            .count;
+        sql_query("DROP TABLE commit_strategy_work").execute(&mut conn)?;
+        let queued = background_jobs::table.count().get_result(&mut conn)?;
+        assert_matches!(result, Err(FailedJobsError::Other(_)));
+        Ok((work, queued))
+    };
+
+    // The job's work is rolled back along with the status update
+    assert_eq!((0, 1), work_after_crash(CommitStrategy::SingleTransaction)?);
+    // The job's work was already committed, but the job will run again
+    assert_eq!(
+        (1, 1),
+        work_after_crash(CommitStrategy::SeparateTransactions)?
+    );
+    Ok(())
+}
+
+/// Counts how many jobs have been started
+#[derive(Clone, Default)]
+pub struct StartedJobs(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+#[test]
+fn on_idle_is_called_only_once_the_queue_drains() -> Fallible<()> {
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::{Arc, Mutex};
+    use swirl::PerformError;
+
+    #[swirl::background_job]
+    fn counted_job(env: &StartedJobs) -> Result<(), PerformError> {
+        env.0.fetch_add(1, SeqCst);
+        Ok(())
+    }
+
+    let started_jobs = StartedJobs::default();
+    let idle_calls = Arc::new(Mutex::new(Vec::new()));
+    let runner = {
+        let started_jobs = started_jobs.clone();
+        let idle_calls = Arc::clone(&idle_calls);
+        TestGuard::builder(started_jobs.clone())
+            .on_idle(move || idle_calls.lock().unwrap().push(started_jobs.0.load(SeqCst)))
+            .build()
+    };
+    let mut conn = runner.connection_pool().get()?;
+    let enqueue_five = |conn: &mut PgConnection| {
+        conn.transaction(|conn| (0..5).try_for_each(|_| counted_job().enqueue(conn)))
+    };
+    let wait_for_idle_after = |started: usize| {
+        while !idle_calls.lock().unwrap().contains(&started) {
+            thread::sleep(Duration::from_millis(5));
+        }
+    };
+    enqueue_five(&mut conn)?;
+
+    let started_at = std::time::Instant::now();
+    thread::scope(|scope| -> Fallible<()> {
+        let worker = scope.spawn(|| runner.run_forever(Duration::from_millis(10)));
+        wait_for_idle_after(5);
+        enqueue_five(&mut conn)?;
+        wait_for_idle_after(10);
+        assert!(runner
+            .shutdown_with_timeout(Duration::from_secs(1))
+            .is_empty());
+        worker.join().unwrap()?;
+        Ok(())
+    })?;
+
+    let elapsed = started_at.elapsed();
+
+    // The hook is called at most once per poll interval
+    let idle_calls = idle_calls.lock().unwrap();
+    let max_calls = (elapsed.as_millis() / 10) as usize + 1;
+    assert!(idle_calls.len() <= max_calls, "{:?}", idle_calls);
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn only_one_runner_can_become_the_scheduler() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let database_url = dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let other_runner = swirl::Runner::builder(())
+        .database_url(database_url)
+        .build();
+
+    let lock = runner.try_become_scheduler().map_err(failure::err_msg)?;
+    assert!(lock.is_some());
+    let other_lock = other_runner
+        .try_become_scheduler()
+        .map_err(failure::err_msg)?;
+    assert!(other_lock.is_none());
+
+    // The lock is released when it's dropped, even though its connection is
+    // returned to the pool rather than closed
+    drop(lock);
+    let other_lock = other_runner
+        .try_become_scheduler()
+        .map_err(failure::err_msg)?;
+    assert!(other_lock.is_some());
+    assert!(runner
+        .try_become_scheduler()
+        .map_err(failure::err_msg)?
+        .is_none());
+    Ok(())
+}
+
+#[test]
+fn test_runners_have_their_own_tables_and_can_run_at_the_same_time() -> Fallible<()> {
+    use swirl::testing::TestRunner;
+
+    let database_url = dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    // Each runner's job waits for the other's, so both have to be running at
+    // once for either to finish
+    let barrier = Barrier::new(2);
+    let runner = TestRunner::build(&*database_url, barrier.clone(), |b| b.thread_count(1))
+        .map_err(failure::err_msg)?;
+    let other_runner = TestRunner::build(&*database_url, barrier, |b| b.thread_count(1))
+        .map_err(failure::err_msg)?;
+    assert_ne!(runner.schema(), other_runner.schema());
+
+    let mut conn = runner.connection_pool().get()?;
+    let mut other_conn = other_runner.connection_pool().get()?;
+    barrier_job().enqueue(&mut conn)?;
+    barrier_job().enqueue(&mut other_conn)?;
+    failure_job().enqueue(&mut other_conn)?;
+    assert_eq!(Ok(1), background_jobs::table.count().get_result(&mut conn));
+    assert_eq!(
+        Ok(2),
+        background_jobs::table.count().get_result(&mut other_conn)
+    );
+
+    thread::scope(|scope| -> Fallible<()> {
+        let run = scope.spawn(|| runner.run_all_pending_jobs());
+        other_runner.run_all_pending_jobs()?;
+        run.join().unwrap()?;
+        Ok(())
+    })?;
+
     runner.check_for_failed_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), other_runner.check_for_failed_jobs());
+    assert_eq!(Ok(0), background_jobs::table.count().get_result(&mut conn));
     Ok(())
 }
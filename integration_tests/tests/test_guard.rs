@@ -1,8 +1,9 @@
 use antidote::{Mutex, MutexGuard};
 use diesel::prelude::*;
+use diesel::r2d2;
 use std::ops::{Deref, DerefMut};
 use std::time::Duration;
-use swirl::{Builder, Runner};
+use swirl::{Builder, CommitStrategy, JobRun, PerformError, Runner};
 
 use crate::db::*;
 use crate::util::*;
@@ -24,9 +25,16 @@ pub struct TestGuard<'a, Env: 'static> {
 
 impl<'a, Env> TestGuard<'a, Env> {
     pub fn builder(env: Env) -> GuardBuilder<Env> {
+        Self::builder_with_pool(env, pool_builder())
+    }
+
+    pub fn builder_with_pool(
+        env: Env,
+        pool_builder: r2d2::Builder<r2d2::ConnectionManager<PgConnection>>,
+    ) -> GuardBuilder<Env> {
         let database_url =
             dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
-        let builder = Runner::builder(env).connection_pool_builder(database_url, pool_builder());
+        let builder = Runner::builder(env).connection_pool_builder(database_url, pool_builder);
 
         GuardBuilder { builder }
     }
@@ -34,6 +42,44 @@ impl<'a, Env> TestGuard<'a, Env> {
     pub fn runner(env: Env) -> Self {
         Self::builder(env).build()
     }
+
+    /// A runner which runs jobs from the test database, followed by each of
+    /// the given databases
+    pub fn sharded_runner(env: Env, shard_urls: &[String]) -> Self {
+        let database_url =
+            dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+        let pools = std::iter::once(&database_url)
+            .chain(shard_urls)
+            .map(|url| {
+                pool_builder()
+                    .max_size(10)
+                    .build_unchecked(r2d2::ConnectionManager::new(url.as_str()))
+            })
+            .collect();
+
+        TestGuard {
+            _lock: TEST_MUTEX.lock(),
+            runner: Runner::builder(env).connection_pools(pools).build(),
+        }
+    }
+
+    /// A runner whose jobs of the given type get their connection from
+    /// `job_pool`
+    pub fn runner_with_job_pool(env: Env, job_type: &str, job_pool: DieselPool) -> Self {
+        let database_url =
+            dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+        let pool = pool_builder()
+            .max_size(10)
+            .build_unchecked(r2d2::ConnectionManager::new(database_url));
+
+        TestGuard {
+            _lock: TEST_MUTEX.lock(),
+            runner: Runner::builder(env)
+                .connection_pool(pool)
+                .job_pool_for(job_type, job_pool)
+                .build(),
+        }
+    }
 }
 
 impl<'a> TestGuard<'a, ()> {
@@ -52,16 +98,165 @@ impl<Env> GuardBuilder<Env> {
         self
     }
 
+    pub fn thread_name_prefix(mut self, prefix: &str) -> Self {
+        self.builder = self.builder.thread_name_prefix(prefix);
+        self
+    }
+
+    pub fn lazy_threads(mut self, lazy_threads: bool) -> Self {
+        self.builder = self.builder.lazy_threads(lazy_threads);
+        self
+    }
+
+    pub fn on_bad_payload(mut self, policy: swirl::BadPayloadPolicy) -> Self {
+        self.builder = self.builder.on_bad_payload(policy);
+        self
+    }
+
+    pub fn executor<E: swirl::JobExecutor>(mut self, executor: E) -> Self {
+        self.builder = self.builder.executor(executor);
+        self
+    }
+
+    pub fn weight_budget(mut self, budget: u32) -> Self {
+        self.builder = self.builder.weight_budget(budget);
+        self
+    }
+
     pub fn connection_count(mut self, count: u32) -> Self {
         self.builder = self.builder.connection_count(count);
         self
     }
 
+    pub fn retry_jitter(mut self, fraction: f64) -> Self {
+        self.builder = self.builder.retry_jitter(fraction);
+        self
+    }
+
+    pub fn log_summary(mut self, log_summary: bool) -> Self {
+        self.builder = self.builder.log_summary(log_summary);
+        self
+    }
+
+    pub fn commit_strategy(mut self, commit_strategy: CommitStrategy) -> Self {
+        self.builder = self.builder.commit_strategy(commit_strategy);
+        self
+    }
+
+    pub fn fetch_mode(mut self, fetch_mode: swirl::FetchMode) -> Self {
+        self.builder = self.builder.fetch_mode(fetch_mode);
+        self
+    }
+
+    pub fn abandoned_job_ttl(mut self, ttl: Duration) -> Self {
+        self.builder = self.builder.abandoned_job_ttl(ttl);
+        self
+    }
+
+    pub fn panic_backtraces(mut self, panic_backtraces: bool) -> Self {
+        self.builder = self.builder.panic_backtraces(panic_backtraces);
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.builder = self.builder.max_retries(max_retries);
+        self
+    }
+
+    pub fn registry(mut self, registry: swirl::Registry<Env>) -> Self {
+        self.builder = self.builder.registry(registry);
+        self
+    }
+
+    pub fn require_non_empty_registry(mut self, require: bool) -> Self {
+        self.builder = self.builder.require_non_empty_registry(require);
+        self
+    }
+
+    pub fn on_success<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>) + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_success(hook);
+        self
+    }
+
+    pub fn on_failure<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>, &PerformError) + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_failure(hook);
+        self
+    }
+
+    pub fn on_expired<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>) + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_expired(hook);
+        self
+    }
+
+    pub fn on_idle<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_idle(hook);
+        self
+    }
+
+    pub fn on_abandon<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>, u32, &PerformError) + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_abandon(hook);
+        self
+    }
+
+    pub fn on_job_start_timeout<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.builder = self.builder.on_job_start_timeout(hook);
+        self
+    }
+
+    pub fn record_failure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut PgConnection, i64, &PerformError) -> QueryResult<()> + Send + Sync + 'static,
+    {
+        self.builder = self.builder.record_failure(f);
+        self
+    }
+
+    pub fn transform_payload<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, &mut serde_json::Value) -> Result<(), PerformError> + Send + Sync + 'static,
+    {
+        self.builder = self.builder.transform_payload(f);
+        self
+    }
+
     pub fn job_start_timeout(mut self, timeout: Duration) -> Self {
         self.builder = self.builder.job_start_timeout(timeout);
         self
     }
 
+    pub fn wait_while_saturated(mut self, wait_while_saturated: bool) -> Self {
+        self.builder = self.builder.wait_while_saturated(wait_while_saturated);
+        self
+    }
+
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.builder = self.builder.fail_fast(fail_fast);
+        self
+    }
+
+    pub fn job_start_timeout_for(mut self, job_type: &str, timeout: Duration) -> Self {
+        self.builder = self.builder.job_start_timeout_for(job_type, timeout);
+        self
+    }
+
     pub fn build<'a>(self) -> TestGuard<'a, Env> {
         TestGuard {
             _lock: TEST_MUTEX.lock(),
@@ -86,9 +281,16 @@ impl<'a, Env> DerefMut for TestGuard<'a, Env> {
 
 impl<'a, Env> Drop for TestGuard<'a, Env> {
     fn drop(&mut self) {
-        let conn = self.runner.connection_pool().get().unwrap();
-        ::diesel::sql_query("TRUNCATE TABLE background_jobs")
-            .execute(&conn)
-            .unwrap_from_drop();
+        // `run_all_pending_jobs` can return while workers it queued are still
+        // waiting to look for a job. Left running, they could pick up jobs
+        // enqueued by the next test.
+        let _ = self.runner.wait_for_jobs(Some(Duration::from_secs(5)));
+        let mut conn = self.runner.connection_pool().get().unwrap();
+        ::diesel::sql_query(
+            "TRUNCATE TABLE background_jobs, background_job_progress, \
+             background_job_cancellations, background_job_pauses",
+        )
+        .execute(&mut conn)
+        .unwrap_from_drop();
     }
 }
@@ -0,0 +1,43 @@
+//! Links the spans created by a job back to the span which enqueued it.
+//!
+//! Run with `cargo run --example otel_propagation --features otel`
+
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use opentelemetry::{global, Context};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::error::Error;
+use swirl::*;
+
+#[swirl::background_job]
+fn traced_job() -> Result<(), PerformError> {
+    let span = Context::current().span().span_context().clone();
+    println!("Running job in trace {}", span.trace_id());
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // The same propagator must be used where jobs are enqueued and run
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let database_url = dotenv::var("DATABASE_URL")?;
+    let runner = Runner::builder(()).database_url(database_url).build();
+
+    // In a real application, this would be the span of the incoming request
+    let span_context = SpanContext::new(
+        TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736")?,
+        SpanId::from_hex("00f067aa0ba902b7")?,
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    let request_context = Context::current().with_remote_span_context(span_context);
+    {
+        let _guard = request_context.attach();
+        println!("Enqueuing job in trace 4bf92f3577b34da6a3ce929d0e0e4736");
+        traced_job().enqueue(&mut *runner.connection_pool().get()?)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Compares how long it takes to run jobs when most of the table is jobs
+//! waiting to be retried, with and without the `background_jobs_ready` index.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use swirl::*;
+
+const WAITING_JOBS: usize = 200_000;
+const READY_JOBS: usize = 5_000;
+
+#[swirl::background_job]
+fn dummy_job() -> Result<(), PerformError> {
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let database_url = dotenv::var("DATABASE_URL")?;
+    let runner = Runner::builder(()).database_url(database_url).build();
+    let mut conn = runner.connection_pool().get()?;
+
+    sql_query("DROP INDEX IF EXISTS background_jobs_ready").execute(&mut conn)?;
+    let without_index = time_run(&runner, &mut conn)?;
+    sql_query(
+        "CREATE INDEX background_jobs_ready ON background_jobs (retry_at, id) \
+         WHERE NOT abandoned",
+    )
+    .execute(&mut conn)?;
+    let with_index = time_run(&runner, &mut conn)?;
+
+    println!(
+        "Ran {} jobs alongside {} waiting jobs",
+        READY_JOBS, WAITING_JOBS
+    );
+    println!("Without the index: {:?}", without_index);
+    println!("With the index: {:?}", with_index);
+    sql_query("TRUNCATE TABLE background_jobs").execute(&mut conn)?;
+    Ok(())
+}
+
+fn time_run<Pool>(
+    runner: &Runner<(), Pool>,
+    conn: &mut PgConnection,
+) -> Result<Duration, Box<dyn Error>>
+where
+    Pool: db::DieselPool + 'static,
+{
+    sql_query("TRUNCATE TABLE background_jobs").execute(conn)?;
+    sql_query(format!(
+        "INSERT INTO background_jobs (job_type, data, retries, retry_at) \
+         SELECT 'dummy_job', '{{}}', 10, CURRENT_TIMESTAMP + INTERVAL '1 day' \
+         FROM generate_series(1, {})",
+        WAITING_JOBS,
+    ))
+    .execute(conn)?;
+    for _ in 0..READY_JOBS {
+        dummy_job().enqueue(conn)?;
+    }
+    sql_query("ANALYZE background_jobs").execute(conn)?;
+
+    let started = Instant::now();
+    runner.run_all_pending_jobs_with_concurrency(5)?;
+    Ok(started.elapsed())
+}
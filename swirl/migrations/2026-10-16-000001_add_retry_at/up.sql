@@ -0,0 +1,2 @@
+ALTER TABLE background_jobs ADD COLUMN retry_at TIMESTAMP NOT NULL DEFAULT '1970-01-01';
+UPDATE background_jobs SET retry_at = last_retry + INTERVAL '1 minute' * POWER(2, retries);
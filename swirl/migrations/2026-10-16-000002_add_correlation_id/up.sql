@@ -0,0 +1,2 @@
+ALTER TABLE background_jobs ADD COLUMN correlation_id TEXT;
+CREATE INDEX background_jobs_correlation_id ON background_jobs (correlation_id);
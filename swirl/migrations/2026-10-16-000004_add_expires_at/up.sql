@@ -0,0 +1 @@
+ALTER TABLE background_jobs ADD COLUMN expires_at TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE background_jobs ADD COLUMN abandoned BOOLEAN NOT NULL DEFAULT FALSE;
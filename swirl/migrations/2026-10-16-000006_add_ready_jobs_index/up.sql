@@ -0,0 +1 @@
+CREATE INDEX background_jobs_ready ON background_jobs (retry_at, id) WHERE NOT abandoned;
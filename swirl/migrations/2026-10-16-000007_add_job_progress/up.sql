@@ -0,0 +1,7 @@
+-- Progress is kept out of `background_jobs`, since a job's row is locked for
+-- as long as it runs. There is no foreign key for the same reason.
+CREATE TABLE background_job_progress (
+  job_id BIGINT PRIMARY KEY,
+  progress SMALLINT NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
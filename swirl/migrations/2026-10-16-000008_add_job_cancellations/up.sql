@@ -0,0 +1,6 @@
+-- Like progress, cancellation requests are kept out of `background_jobs` so
+-- they can be made while the job's row is locked.
+CREATE TABLE background_job_cancellations (
+  job_id BIGINT PRIMARY KEY,
+  requested_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- There is no foreign key, since a job's dependency is deleted once it
+-- succeeds, which is what lets the dependent job run.
+ALTER TABLE background_jobs ADD COLUMN depends_on BIGINT;
@@ -0,0 +1,5 @@
+-- Job types which runners should not start, until they are resumed
+CREATE TABLE background_job_pauses (
+  job_type TEXT PRIMARY KEY,
+  paused_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
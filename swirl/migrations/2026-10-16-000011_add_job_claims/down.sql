@@ -0,0 +1 @@
+ALTER TABLE background_jobs DROP COLUMN locked_by, DROP COLUMN locked_until;
@@ -0,0 +1,3 @@
+-- Set on jobs which were claimed by a runner using `FetchMode::Claim`. The
+-- job isn't fetched again until `locked_until` has passed.
+ALTER TABLE background_jobs ADD COLUMN locked_by TEXT, ADD COLUMN locked_until TIMESTAMP;
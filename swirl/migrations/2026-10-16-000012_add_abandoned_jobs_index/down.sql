@@ -0,0 +1 @@
+DROP INDEX background_jobs_abandoned;
@@ -0,0 +1,2 @@
+-- Used to find abandoned jobs which are old enough to be pruned
+CREATE INDEX background_jobs_abandoned ON background_jobs (last_retry) WHERE abandoned;
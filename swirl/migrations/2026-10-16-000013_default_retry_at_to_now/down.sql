@@ -0,0 +1 @@
+ALTER TABLE background_jobs ALTER COLUMN retry_at SET DEFAULT '1970-01-01';
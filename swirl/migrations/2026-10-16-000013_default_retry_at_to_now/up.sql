@@ -0,0 +1,3 @@
+-- Jobs inserted without a `retry_at` are ready as soon as they're committed,
+-- and ordered by when they were enqueued, like jobs inserted by `enqueue`.
+ALTER TABLE background_jobs ALTER COLUMN retry_at SET DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,156 @@
+use diesel::{PgConnection, QueryResult};
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+
+use crate::db::DieselPoolObj;
+use crate::enqueue_builder::EnqueueBuilder;
+use crate::errors::EnqueueError;
+use crate::storage;
+use crate::Job;
+
+/// Information about the job currently being performed, and resources
+/// available to it other than the environment.
+#[allow(missing_debug_implementations)] // The pool can't implement debug
+pub struct JobContext<'a> {
+    id: i64,
+    correlation_id: Option<&'a str>,
+    retries: i32,
+    enqueued_at: SystemTime,
+    ready_at: SystemTime,
+    pool: &'a dyn DieselPoolObj,
+}
+
+impl<'a> JobContext<'a> {
+    pub(crate) fn new(
+        id: i64,
+        correlation_id: Option<&'a str>,
+        retries: i32,
+        enqueued_at: SystemTime,
+        ready_at: SystemTime,
+        pool: &'a dyn DieselPoolObj,
+    ) -> Self {
+        Self {
+            id,
+            correlation_id,
+            retries,
+            enqueued_at,
+            ready_at,
+            pool,
+        }
+    }
+
+    /// The id of the job's row in the `background_jobs` table
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// The correlation id given when the job was enqueued with
+    /// [`Job::enqueue_with_correlation`](crate::Job::enqueue_with_correlation),
+    /// if any.
+    pub fn correlation_id(&self) -> Option<&'a str> {
+        self.correlation_id
+    }
+
+    /// How many times the job has failed before this attempt
+    pub fn retries(&self) -> u32 {
+        self.retries as u32
+    }
+
+    /// When the job was enqueued
+    pub fn enqueued_at(&self) -> SystemTime {
+        self.enqueued_at
+    }
+
+    /// When the job became ready to run.
+    ///
+    /// This is when it was enqueued, unless it was enqueued with a delay, or
+    /// is being retried, in which case it's when the delay or the wait before
+    /// the retry ended. How long the job waited in the queue is the time
+    /// since then.
+    pub fn ready_at(&self) -> SystemTime {
+        self.ready_at
+    }
+
+    /// The runner's connection pool.
+    ///
+    /// This can be used to get additional connections for work which should
+    /// not be rolled back if the job fails.
+    pub fn pool(&self) -> &'a dyn DieselPoolObj {
+        self.pool
+    }
+
+    /// Record how far along the job is, as a percentage from 0 to 100.
+    ///
+    /// Progress is stored in the `background_job_progress` table, keyed by
+    /// the job's id, so it can be shown while the job runs. It is written
+    /// with a separate connection from the pool, and committed right away.
+    /// Unlike the rest of the job's work, it is visible before the job
+    /// finishes, and is not rolled back if the job fails. A job which is
+    /// retried starts with the progress of its last attempt. The progress is
+    /// deleted along with the job once it succeeds.
+    ///
+    /// Jobs run with [`Runner::run_job_inline`](crate::Runner::run_job_inline)
+    /// have no row, so this does nothing for them.
+    pub fn set_progress(&self, percent: u8) -> Result<(), Box<dyn Error>> {
+        if percent > 100 {
+            return Err(format!("Progress must be at most 100, got {}", percent).into());
+        }
+        if self.id == 0 {
+            return Ok(());
+        }
+        let mut conn = self.pool.get()?;
+        storage::set_progress(&mut conn, self.id, percent.into())?;
+        Ok(())
+    }
+
+    /// Whether [`request_cancel`](crate::request_cancel) has been called for
+    /// this job.
+    ///
+    /// Long running jobs can check this periodically, and return
+    /// [`Cancelled`](crate::Cancelled) when it's true to stop early. This can
+    /// be given the job's own connection, since the request is committed by
+    /// whoever made it.
+    pub fn is_cancel_requested(&self, conn: &mut PgConnection) -> QueryResult<bool> {
+        storage::cancel_requested(conn, self.id)
+    }
+
+    /// Enqueue this job again, with the same arguments, to run once `delay`
+    /// has passed. Returns the id of the new job.
+    ///
+    /// This lets a job reschedule itself, to run periodically without a
+    /// separate scheduler. Pass the connection the job was given, so the new
+    /// job is only enqueued if this run succeeds. If the runner crashes, or
+    /// the job fails and is retried, the chain carries on from the retry
+    /// instead of ending up with two copies of the job. Jobs which don't run
+    /// in a transaction have no such guarantee.
+    ///
+    /// The new job keeps this job's correlation id, but not its time to
+    /// live or dependency. Jobs run with
+    /// [`Runner::run_job_inline`](crate::Runner::run_job_inline) have no row
+    /// to copy, so this returns an error for them.
+    pub fn enqueue_self_after(
+        &self,
+        conn: &mut PgConnection,
+        delay: Duration,
+    ) -> Result<i64, EnqueueError> {
+        storage::enqueue_copy(conn, self.id, delay)?.ok_or_else(|| {
+            EnqueueError::Other("Only jobs run from the queue can enqueue themselves".into())
+        })
+    }
+
+    /// Start enqueueing another job from this one, with the same options as
+    /// [`Job::enqueue_builder`](crate::Job::enqueue_builder).
+    ///
+    /// The new job is given this job's correlation id, if it has one, so the
+    /// jobs a dispatcher fans out to can be traced back to it. The correlation
+    /// id can still be overridden. Pass the connection the job was given to
+    /// [`enqueue`](EnqueueBuilder::enqueue), so the new job is only enqueued if
+    /// this one succeeds.
+    pub fn enqueue_builder<J: Job>(&self, job: J) -> EnqueueBuilder<J> {
+        let builder = EnqueueBuilder::new(job);
+        match self.correlation_id {
+            Some(correlation_id) => builder.correlation_id(correlation_id),
+            None => builder,
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use diesel::PgConnection;
 use std::error::Error;
-use std::ops::Deref;
+use std::ops::DerefMut;
 
 pub type DieselPooledConn<'a, T> = <T as BorrowedConnection<'a>>::Connection;
 
@@ -9,7 +9,7 @@ pub type DieselPooledConn<'a, T> = <T as BorrowedConnection<'a>>::Connection;
 /// This will eventually change to `type Connection<'a>` on [`DieselPool`]
 pub trait BorrowedConnection<'a> {
     /// The smart pointer returned by this connection pool.
-    type Connection: Deref<Target = PgConnection>;
+    type Connection: DerefMut<Target = PgConnection>;
 }
 
 /// A connection pool for Diesel database connections
@@ -32,35 +32,50 @@ pub trait DieselPool: Clone + Send + for<'a> BorrowedConnection<'a> {
     /// - A timeout was reached
     /// - An error occurred establishing a new connection
     fn get(&self) -> Result<DieselPooledConn<'_, Self>, Self::Error>;
+
+    /// Check that a working connection can be retrieved from the pool.
+    ///
+    /// Used by [`Builder::try_build`](crate::Builder::try_build). The
+    /// default implementation gets a connection from the pool, which is
+    /// enough for pools that check connections when they are handed out.
+    fn ping(&self) -> Result<(), Self::Error> {
+        self.get().map(|_| ())
+    }
+
+    /// The maximum number of connections this pool will hand out at once, if
+    /// known.
+    ///
+    /// This is used to avoid running more jobs at once than there are
+    /// connections for. Defaults to `None`.
+    fn max_size(&self) -> Option<u32> {
+        None
+    }
 }
 
+/// A function which is given a connection by [`DieselPoolObj::with_connection`]
+type ConnectionFn<'a> = dyn Fn(&mut PgConnection) -> Result<(), Box<dyn Error>> + 'a;
+
 /// Object safe version of [`DieselPool`]
 pub trait DieselPoolObj {
     /// Object safe version of [`DieselPool::get`]
     ///
     /// This function will heap allocate the connection. This allocation can
     /// be avoided by using [`Self::with_connection`]
-    fn get(&self) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error>>;
+    fn get(&self) -> Result<Box<dyn DerefMut<Target = PgConnection> + '_>, Box<dyn Error>>;
 
-    fn with_connection(
-        &self,
-        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error>>,
-    ) -> Result<(), Box<dyn Error>>;
+    fn with_connection(&self, f: &ConnectionFn<'_>) -> Result<(), Box<dyn Error>>;
 }
 
 impl<T: DieselPool> DieselPoolObj for T {
-    fn get(&self) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error>> {
+    fn get(&self) -> Result<Box<dyn DerefMut<Target = PgConnection> + '_>, Box<dyn Error>> {
         DieselPool::get(self)
             .map(|v| Box::new(v) as _)
             .map_err(|v| Box::new(v) as _)
     }
 
-    fn with_connection(
-        &self,
-        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error>>,
-    ) -> Result<(), Box<dyn Error>> {
-        let conn = DieselPool::get(self)?;
-        f(&conn)
+    fn with_connection(&self, f: &ConnectionFn<'_>) -> Result<(), Box<dyn Error>> {
+        let mut conn = DieselPool::get(self)?;
+        f(&mut conn)
     }
 }
 
@@ -93,6 +108,10 @@ mod r2d2_impl {
         fn get<'a>(&'a self) -> Result<DieselPooledConn<'a, Self>, Self::Error> {
             self.get()
         }
+
+        fn max_size(&self) -> Option<u32> {
+            Some(r2d2::Pool::max_size(self))
+        }
     }
 
     pub struct R2d2Builder {
@@ -0,0 +1,58 @@
+use diesel::PgConnection;
+use serde_json::Value;
+
+use crate::errors::EnqueueError;
+use crate::storage;
+use crate::Job;
+
+/// Enqueues several jobs, of any types, with a single `INSERT`
+///
+/// Jobs are validated and serialized as they're added, so a job with invalid
+/// arguments is reported by [`push`](Self::push), and nothing has been
+/// enqueued yet when it is. The jobs are enqueued with the default options,
+/// the same as [`Job::enqueue`].
+///
+/// ```ignore
+/// let mut batch = EnqueueBatch::new();
+/// batch.push(send_welcome_email(user_id))?;
+/// batch.push(resize_avatar(user_id, avatar_url))?;
+/// batch.push(refresh_cache(team_id))?;
+/// batch.commit(&mut conn)?;
+/// ```
+#[must_use = "jobs do nothing unless they are enqueued"]
+#[derive(Debug, Default)]
+pub struct EnqueueBatch {
+    jobs: Vec<(&'static str, Value)>,
+}
+
+impl EnqueueBatch {
+    /// Starts a batch with no jobs in it
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a job to the batch
+    pub fn push<J: Job>(&mut self, job: J) -> Result<(), EnqueueError> {
+        job.validate()?;
+        self.jobs.push((J::JOB_TYPE, job.to_value()?));
+        Ok(())
+    }
+
+    /// The number of jobs in the batch
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Whether no jobs have been added to the batch
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Enqueue every job in the batch, and return the ids of their rows, in
+    /// the order the jobs were added.
+    ///
+    /// An empty batch doesn't query the database.
+    pub fn commit(self, conn: &mut PgConnection) -> Result<Vec<i64>, EnqueueError> {
+        Ok(storage::enqueue_jobs(conn, &self.jobs)?)
+    }
+}
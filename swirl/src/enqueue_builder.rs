@@ -0,0 +1,77 @@
+use diesel::PgConnection;
+use std::time::Duration;
+
+use crate::errors::EnqueueError;
+use crate::storage;
+use crate::Job;
+
+/// Enqueues a job with any combination of options
+///
+/// Created with [`Job::enqueue_builder`]. Each option is the same as the
+/// `enqueue_*` method of the same name on [`Job`], and any which aren't set
+/// are left at the same defaults as [`Job::enqueue`].
+#[must_use = "jobs do nothing unless they are enqueued"]
+#[allow(missing_debug_implementations)]
+pub struct EnqueueBuilder<J> {
+    job: J,
+    options: EnqueueOptions,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EnqueueOptions {
+    pub(crate) correlation_id: Option<String>,
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) dependency: Option<i64>,
+    pub(crate) delay: Option<Duration>,
+}
+
+impl<J: Job> EnqueueBuilder<J> {
+    /// Starts enqueueing the given job, with no options set
+    pub fn new(job: J) -> Self {
+        Self {
+            job,
+            options: EnqueueOptions::default(),
+        }
+    }
+
+    /// Tag the job with an externally supplied id.
+    ///
+    /// See [`Job::enqueue_with_correlation`].
+    pub fn correlation_id<S: Into<String>>(mut self, correlation_id: S) -> Self {
+        self.options.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Drop the job if it hasn't been run within `ttl` of being enqueued.
+    ///
+    /// See [`Job::enqueue_with_ttl`].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.options.ttl = Some(ttl);
+        self
+    }
+
+    /// Only run the job once the job with the id `dependency_id` has
+    /// finished.
+    ///
+    /// See [`Job::enqueue_after`].
+    pub fn after(mut self, dependency_id: i64) -> Self {
+        self.options.dependency = Some(dependency_id);
+        self
+    }
+
+    /// Don't run the job until `delay` has passed.
+    ///
+    /// The delay is measured from when the enqueueing transaction began,
+    /// using the database's clock. A job which is delayed past its
+    /// [`ttl`](Self::ttl) expires without being run.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.options.delay = Some(delay);
+        self
+    }
+
+    /// Enqueue the job with the options which were set, and return the id of
+    /// its row.
+    pub fn enqueue(self, conn: &mut PgConnection) -> Result<i64, EnqueueError> {
+        storage::enqueue_job(conn, self.job, &self.options)
+    }
+}
@@ -0,0 +1,37 @@
+use serde_json::Value;
+use std::sync::OnceLock;
+
+type Observer = dyn Fn(&str, &Value) + Send + Sync;
+
+static OBSERVER: OnceLock<Box<Observer>> = OnceLock::new();
+
+/// Register a function to be called each time any job is enqueued.
+///
+/// The function is given the job's type and its serialized arguments, as they
+/// were stored in the `data` column. It's called after the job's row has been
+/// inserted, on the thread which enqueued it. If the job was enqueued inside
+/// of a transaction which is later rolled back, the function has still been
+/// called. This is meant for auditing or logging every job which is
+/// submitted, from one place.
+///
+/// This applies to every job in the process, regardless of which runner or
+/// connection is used, and can only be set once. Until it's set, enqueuing a
+/// job doesn't do any extra work.
+///
+/// # Panics
+///
+/// Panics if an observer has already been set.
+pub fn set_enqueue_observer<F>(observer: F)
+where
+    F: Fn(&str, &Value) + Send + Sync + 'static,
+{
+    if OBSERVER.set(Box::new(observer)).is_err() {
+        panic!("An enqueue observer has already been set");
+    }
+}
+
+pub(crate) fn job_enqueued(job_type: &str, data: &Value) {
+    if let Some(observer) = OBSERVER.get() {
+        observer(job_type, data);
+    }
+}
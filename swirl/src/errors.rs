@@ -1,11 +1,19 @@
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use std::error::Error;
 use std::fmt;
+use std::time::Duration;
 
 use crate::db::DieselPool;
+use crate::RunReport;
 
 /// An error occurred queueing the job
+///
+/// Each kind of failure has its own variant, so callers can tell them apart,
+/// such as a web handler responding with a client error for
+/// [`InvalidArguments`](Self::InvalidArguments), and a server error for the
+/// rest.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EnqueueError {
     /// An error occurred serializing the job
     SerializationError(serde_json::error::Error),
@@ -13,9 +21,17 @@ pub enum EnqueueError {
     /// An error occurred inserting the job into the database
     DatabaseError(DieselError),
 
-    #[doc(hidden)]
-    /// Match on `_` instead, more variants may be added in the future
-    __NonExhaustive,
+    /// We could not acquire a database connection from the runner's pool.
+    ///
+    /// Only returned by [`Runner::enqueue`](crate::Runner::enqueue).
+    NoDatabaseConnection(Box<dyn Error + Send + Sync>),
+
+    /// The job's arguments were rejected by [`Job::validate`](crate::Job::validate)
+    InvalidArguments(Box<dyn Error + Send + Sync>),
+
+    /// Any other error, such as one returned with `?` from a
+    /// [`Job::validate`](crate::Job::validate) implementation
+    Other(Box<dyn Error + Send + Sync>),
 }
 
 impl From<serde_json::error::Error> for EnqueueError {
@@ -30,12 +46,20 @@ impl From<DieselError> for EnqueueError {
     }
 }
 
+impl From<Box<dyn Error + Send + Sync>> for EnqueueError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        EnqueueError::Other(e)
+    }
+}
+
 impl fmt::Display for EnqueueError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EnqueueError::SerializationError(e) => e.fmt(f),
             EnqueueError::DatabaseError(e) => e.fmt(f),
-            EnqueueError::__NonExhaustive => unreachable!(),
+            EnqueueError::NoDatabaseConnection(e) => e.fmt(f),
+            EnqueueError::InvalidArguments(e) => write!(f, "Invalid job arguments: {}", e),
+            EnqueueError::Other(e) => e.fmt(f),
         }
     }
 }
@@ -45,7 +69,9 @@ impl Error for EnqueueError {
         match self {
             EnqueueError::SerializationError(e) => Some(e),
             EnqueueError::DatabaseError(e) => Some(e),
-            EnqueueError::__NonExhaustive => unreachable!(),
+            EnqueueError::NoDatabaseConnection(e) => Some(&**e),
+            EnqueueError::InvalidArguments(e) => Some(&**e),
+            EnqueueError::Other(e) => Some(&**e),
         }
     }
 }
@@ -53,12 +79,105 @@ impl Error for EnqueueError {
 /// An error occurred performing the job
 pub type PerformError = Box<dyn Error>;
 
+/// Displays an error followed by each of its sources, separated by `: `.
+///
+/// This is how the runner logs the errors of failed jobs, so that the cause of
+/// a wrapped error isn't lost. It can also be used in
+/// [`Builder::on_failure`](crate::Builder::on_failure) hooks. Sources which
+/// display the same message as the error wrapping them are skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorChain<'a>(pub &'a (dyn Error + 'static));
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut message = self.0.to_string();
+        f.write_str(&message)?;
+        let mut source = self.0.source();
+        while let Some(error) = source {
+            let source_message = error.to_string();
+            if source_message != message {
+                write!(f, ": {}", source_message)?;
+            }
+            message = source_message;
+            source = error.source();
+        }
+        Ok(())
+    }
+}
+
+/// The arguments stored for a job could not be deserialized into the job's
+/// type.
+///
+/// This is the error a job fails with when that happens. The runner handles it
+/// according to
+/// [`Builder::on_bad_payload`](crate::Builder::on_bad_payload).
+#[derive(Debug)]
+pub struct BadPayload(pub serde_json::Error);
+
+impl fmt::Display for BadPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not deserialize the job's arguments: {}", self.0)
+    }
+}
+
+impl Error for BadPayload {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// An error which tells the runner when the job should be retried.
+///
+/// Returning this from a job (converted into a [`PerformError`]) retries the
+/// job after the given duration instead of the usual backoff, such as when an
+/// API responded with a `Retry-After` header. The job still counts as having
+/// failed, including towards
+/// [`Builder::max_retries`](crate::Builder::max_retries).
+#[derive(Debug)]
+pub struct RetryAfter(
+    /// How long to wait before retrying the job
+    pub Duration,
+    /// The error which caused the job to fail
+    pub PerformError,
+);
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.1.fmt(f)
+    }
+}
+
+impl Error for RetryAfter {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.1)
+    }
+}
+
+/// Returned by a job which stopped because it was asked to with
+/// [`request_cancel`](crate::request_cancel).
+///
+/// Anything the job did with its connection is rolled back, as with any other
+/// error, but the job is deleted instead of being retried, and doesn't count
+/// as a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The job was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
 /// An error occurred while attempting to fetch jobs from the queue
 pub enum FetchError<Pool: DieselPool> {
     /// We could not acquire a database connection from the pool.
     ///
     /// Either the connection pool is too small, or new connections cannot be
-    /// established.
+    /// established. This is only returned once no jobs started by the runner
+    /// are running. While any are, the runner waits for them to give their
+    /// connections back instead.
     NoDatabaseConnection(Pool::Error),
 
     /// Could not execute the query to load a job from the database.
@@ -70,6 +189,50 @@ pub enum FetchError<Pool: DieselPool> {
     NoMessageReceived,
 }
 
+impl<Pool: DieselPool> FetchError<Pool> {
+    /// Whether loading a job failed because of a serialization failure.
+    ///
+    /// These are caused by conflicts with concurrent transactions, so the
+    /// run can be retried right away.
+    pub fn is_serialization_failure(&self) -> bool {
+        matches!(
+            self.database_error_kind(),
+            Some(DatabaseErrorKind::SerializationFailure)
+        )
+    }
+
+    /// Whether a connection to the database couldn't be acquired, or was
+    /// lost while loading a job.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            FetchError::NoDatabaseConnection(_) => true,
+            _ => matches!(
+                self.database_error_kind(),
+                Some(DatabaseErrorKind::ClosedConnection | DatabaseErrorKind::UnableToSendCommand)
+            ),
+        }
+    }
+
+    /// Whether loading a job failed because the connection is in a read-only
+    /// transaction, such as when connected to a replica.
+    ///
+    /// Retrying will keep failing until the runner is pointed at a writable
+    /// database.
+    pub fn is_read_only_transaction(&self) -> bool {
+        matches!(
+            self.database_error_kind(),
+            Some(DatabaseErrorKind::ReadOnlyTransaction)
+        )
+    }
+
+    fn database_error_kind(&self) -> Option<&DatabaseErrorKind> {
+        match self {
+            FetchError::FailedLoadingJob(DieselError::DatabaseError(kind, _)) => Some(kind),
+            _ => None,
+        }
+    }
+}
+
 impl<Pool: DieselPool> fmt::Debug for FetchError<Pool> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -113,8 +276,14 @@ impl<Pool: DieselPool> Error for FetchError<Pool> {
     }
 }
 
-/// An error returned by `Runner::check_for_failed_jobs`. Only used in tests.
+/// An error returned by [`Runner::check_for_failed_jobs`](crate::Runner::check_for_failed_jobs).
+///
+/// This is intended for use in tests, where the number of failed jobs can be
+/// asserted on directly with `assert_eq!(Err(JobsFailed(3)), result)`.
+/// `JobsFailed` is re-exported at the root of the crate for this purpose. Two
+/// errors are only equal if they are both `JobsFailed` with the same count.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum FailedJobsError {
     /// Jobs failed to run
     JobsFailed(
@@ -122,25 +291,23 @@ pub enum FailedJobsError {
         i64,
     ),
 
-    #[doc(hidden)]
-    /// Match on `_` instead, more variants may be added in the future
     /// Some other error occurred. Worker threads may have panicked, an error
     /// occurred counting failed jobs in the DB, or something else
     /// unexpectedly went wrong.
-    __Unknown(Box<dyn Error + Send + Sync>),
+    Other(Box<dyn Error + Send + Sync>),
 }
 
 pub use FailedJobsError::JobsFailed;
 
 impl From<Box<dyn Error + Send + Sync>> for FailedJobsError {
     fn from(e: Box<dyn Error + Send + Sync>) -> Self {
-        FailedJobsError::__Unknown(e)
+        FailedJobsError::Other(e)
     }
 }
 
 impl From<DieselError> for FailedJobsError {
     fn from(e: DieselError) -> Self {
-        FailedJobsError::__Unknown(e.into())
+        FailedJobsError::Other(e.into())
     }
 }
 
@@ -155,11 +322,10 @@ impl PartialEq for FailedJobsError {
 
 impl fmt::Display for FailedJobsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use FailedJobsError::*;
-
         match self {
+            JobsFailed(1) => write!(f, "1 job failed"),
             JobsFailed(x) => write!(f, "{} jobs failed", x),
-            FailedJobsError::__Unknown(e) => e.fmt(f),
+            FailedJobsError::Other(e) => e.fmt(f),
         }
     }
 }
@@ -168,7 +334,101 @@ impl Error for FailedJobsError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             JobsFailed(_) => None,
-            FailedJobsError::__Unknown(e) => Some(&**e),
+            FailedJobsError::Other(e) => Some(&**e),
         }
     }
 }
+
+/// An error returned by [`Runner::run_to_completion`](crate::Runner::run_to_completion)
+pub enum RunError<Pool: DieselPool> {
+    /// Jobs could not be fetched from the queue. Jobs which were already
+    /// started may still be running.
+    Fetch(FetchError<Pool>),
+
+    /// Every pending job was run, but some of them failed. The report counts
+    /// all of the jobs which were run.
+    JobsFailed(RunReport),
+
+    /// Waiting for the jobs to finish failed, such as because a worker
+    /// thread panicked.
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl<Pool: DieselPool> From<FetchError<Pool>> for RunError<Pool> {
+    fn from(e: FetchError<Pool>) -> Self {
+        RunError::Fetch(e)
+    }
+}
+
+impl<Pool: DieselPool> fmt::Debug for RunError<Pool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Fetch(e) => f.debug_tuple("Fetch").field(e).finish(),
+            RunError::JobsFailed(report) => f.debug_tuple("JobsFailed").field(report).finish(),
+            RunError::Other(e) => f.debug_tuple("Other").field(e).finish(),
+        }
+    }
+}
+
+impl<Pool: DieselPool> fmt::Display for RunError<Pool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Fetch(e) => e.fmt(f),
+            RunError::JobsFailed(report) => report.fmt(f),
+            RunError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<Pool: DieselPool + 'static> Error for RunError<Pool> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RunError::Fetch(e) => Some(e),
+            RunError::JobsFailed(_) => None,
+            RunError::Other(e) => Some(&**e),
+        }
+    }
+}
+
+/// An error returned by [`Registry::check_env_coverage`](crate::Registry::check_env_coverage)
+#[derive(Debug)]
+pub struct EnvironmentMismatch {
+    expected_env_type: &'static str,
+    jobs: Vec<(&'static str, &'static str)>,
+}
+
+impl EnvironmentMismatch {
+    pub(crate) fn new(
+        expected_env_type: &'static str,
+        jobs: Vec<(&'static str, &'static str)>,
+    ) -> Self {
+        Self {
+            expected_env_type,
+            jobs,
+        }
+    }
+
+    /// The job types which were registered with a different environment
+    pub fn job_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.jobs.iter().map(|&(job_type, _)| job_type)
+    }
+}
+
+impl fmt::Display for EnvironmentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The following jobs do not use the environment type `{}`: ",
+            self.expected_env_type
+        )?;
+        for (i, (job_type, env_type)) in self.jobs.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} (`{}`)", job_type, env_type)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for EnvironmentMismatch {}
@@ -1,7 +1,10 @@
 use diesel::PgConnection;
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::time::Duration;
 
-use crate::db::DieselPoolObj;
+use crate::context::JobContext;
+use crate::enqueue_builder::EnqueueBuilder;
 use crate::errors::{EnqueueError, PerformError};
 use crate::storage;
 
@@ -17,12 +20,281 @@ pub trait Job: Serialize + DeserializeOwned {
     /// Typically this is the name of your struct in `snake_case`
     const JOB_TYPE: &'static str;
 
+    /// Whether this job is run inside of a transaction.
+    ///
+    /// Jobs which run statements that can't be run in a transaction, such as
+    /// `CREATE INDEX CONCURRENTLY` or `VACUUM`, or which manage their own
+    /// transactions, should set this to `false`. They are given a separate
+    /// connection from the pool, which is not in a transaction. The job's row
+    /// stays locked while it runs, and is updated once it finishes, but the
+    /// two are no longer atomic: if the job succeeds and the runner fails to
+    /// delete its row, the job will be run again. Anything the job did before
+    /// failing is not rolled back.
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(no_transaction = true)]`.
+    ///
+    /// Defaults to `true`.
+    const IN_TRANSACTION: bool = true;
+
+    /// Whether this job must not run at the same time as any other job.
+    ///
+    /// When a runner fetches an exclusive job, it stops fetching other jobs,
+    /// and waits for the jobs which are already running to finish before
+    /// running it. Once it has finished, the runner goes back to running jobs
+    /// concurrently. This only coordinates the threads of a single runner.
+    /// Jobs run by other processes are not waited on.
+    ///
+    /// While an exclusive job runs, idle workers are waiting on it to finish,
+    /// so the runner will wait for up to the job's start timeout (see
+    /// [`Builder::job_start_timeout_for`](crate::Builder::job_start_timeout_for))
+    /// for them to start another job.
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(exclusive = true)]`.
+    ///
+    /// Defaults to `false`.
+    const EXCLUSIVE: bool = false;
+
+    /// Whether this job must never run more than once, even if that means it
+    /// doesn't run at all.
+    ///
+    /// Jobs are normally run at least once: their row is only updated once
+    /// they finish, so a job which was interrupted by the runner crashing is
+    /// run again. For jobs where running twice is worse than not running,
+    /// such as charging a credit card, set this to `true`. The job is then
+    /// marked as attempted, and that is committed, before it's run. If the
+    /// runner crashes while the job is running, the job is left abandoned
+    /// instead of being run again, and whatever it was doing may be lost or
+    /// only partly done. Jobs which fail are abandoned as well, rather than
+    /// retried.
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(delivery = "at_most_once")]`.
+    ///
+    /// Defaults to `false`.
+    const AT_MOST_ONCE: bool = false;
+
+    /// How long each SQL statement the job runs on its connection may take
+    /// before Postgres cancels it.
+    ///
+    /// The timeout is set with `SET LOCAL statement_timeout` in the job's
+    /// transaction, right before the job is run, so it overrides any timeout
+    /// the connection pool sets. The previous timeout is put back once the
+    /// job returns, so the runner's own queries aren't affected by it. A
+    /// statement which runs longer fails with an error, which fails the job.
+    /// It has no effect on jobs which don't run in a transaction (see
+    /// [`IN_TRANSACTION`](Self::IN_TRANSACTION)), or on connections the job
+    /// gets from [`JobContext::pool`].
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(statement_timeout_ms = 30000)]`.
+    ///
+    /// Defaults to `None`, which leaves the connection's timeout as it is.
+    const STATEMENT_TIMEOUT: Option<Duration> = None;
+
+    /// How much of the runner's budget this job takes up while it runs.
+    ///
+    /// Runners configured with
+    /// [`Builder::weight_budget`](crate::Builder::weight_budget) don't run
+    /// jobs whose weights add up to more than the budget at the same time.
+    /// Jobs which need a lot of memory or CPU can be given a higher weight,
+    /// so fewer of them run at once than the thread count would allow. A job
+    /// which is heavier than the whole budget runs on its own.
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(weight = 4)]`.
+    ///
+    /// Defaults to `1`.
+    const WEIGHT: u32 = 1;
+
+    /// Check that this job's arguments are valid, before it's enqueued.
+    ///
+    /// This is called by every method which enqueues the job, and the job is
+    /// not inserted if it returns an error. Arguments which could never be
+    /// run successfully should be rejected here, so the caller finds out
+    /// right away, rather than the job failing and being retried when it
+    /// runs. Errors should generally be
+    /// [`EnqueueError::InvalidArguments`].
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(validate = "path::to::function")]`, where the
+    /// function takes a reference to each of the job's arguments, in order,
+    /// and returns a `Result` whose error converts into
+    /// `Box<dyn Error + Send + Sync>`.
+    ///
+    /// Defaults to accepting all arguments.
+    fn validate(&self) -> Result<(), EnqueueError> {
+        Ok(())
+    }
+
     /// Enqueue this job to be run at some point in the future.
-    fn enqueue(self, conn: &PgConnection) -> Result<(), EnqueueError> {
-        storage::enqueue_job(conn, self)
+    fn enqueue(self, conn: &mut PgConnection) -> Result<(), EnqueueError> {
+        self.enqueue_builder().enqueue(conn)?;
+        Ok(())
+    }
+
+    /// Start enqueueing this job with several options at once.
+    ///
+    /// ```ignore
+    /// send_reminder(user_id)
+    ///     .enqueue_builder()
+    ///     .delay(Duration::from_secs(60 * 60))
+    ///     .ttl(Duration::from_secs(2 * 60 * 60))
+    ///     .correlation_id(request_id)
+    ///     .enqueue(&mut conn)?;
+    /// ```
+    fn enqueue_builder(self) -> EnqueueBuilder<Self> {
+        EnqueueBuilder::new(self)
+    }
+
+    /// Enqueue this job, and return the id of its row.
+    ///
+    /// The id can be given to [`enqueue_after`](Self::enqueue_after), to
+    /// enqueue jobs which wait for this one to finish.
+    fn enqueue_returning_id(self, conn: &mut PgConnection) -> Result<i64, EnqueueError> {
+        self.enqueue_builder().enqueue(conn)
+    }
+
+    /// Enqueue this job, to be run once the job with the id `dependency_id`
+    /// has finished. Returns the id of the new job's row.
+    ///
+    /// The job isn't run until its dependency's row is gone from the
+    /// `background_jobs` table, which normally means the dependency
+    /// succeeded. Dependencies which are deleted without succeeding, because
+    /// they expired or were cancelled, also let their dependents run. A
+    /// dependency which failed is retried as usual, and its dependents wait
+    /// for it. If it is abandoned (see
+    /// [`Builder::max_retries`](crate::Builder::max_retries)), its dependents
+    /// wait until it is deleted or retried by hand, and are never run if
+    /// that doesn't happen.
+    ///
+    /// An id which doesn't belong to any job, such as one which has already
+    /// finished, is treated as a finished dependency.
+    fn enqueue_after(
+        self,
+        conn: &mut PgConnection,
+        dependency_id: i64,
+    ) -> Result<i64, EnqueueError> {
+        self.enqueue_builder().after(dependency_id).enqueue(conn)
+    }
+
+    /// Enqueue this job, tagged with an externally supplied id.
+    ///
+    /// The id is stored alongside the job, and is available while it runs
+    /// through [`JobContext::correlation_id`]. It is only meant for tracing,
+    /// such as linking a job back to the request which enqueued it. Jobs with
+    /// the same correlation id are not deduplicated.
+    fn enqueue_with_correlation(
+        self,
+        conn: &mut PgConnection,
+        correlation_id: &str,
+    ) -> Result<(), EnqueueError> {
+        self.enqueue_builder()
+            .correlation_id(correlation_id)
+            .enqueue(conn)?;
+        Ok(())
+    }
+
+    /// Enqueue this job, to be dropped if it hasn't been run within `ttl`.
+    ///
+    /// This is meant for work which is only useful for a limited time. If the
+    /// runner picks up the job after it has expired, the job is deleted
+    /// without being run. This includes jobs which are waiting to be retried
+    /// after failing.
+    fn enqueue_with_ttl(self, conn: &mut PgConnection, ttl: Duration) -> Result<(), EnqueueError> {
+        self.enqueue_builder().ttl(ttl).enqueue(conn)?;
+        Ok(())
+    }
+
+    /// Enqueue this job, unless the same job with the same arguments was
+    /// already enqueued within the last `window` and hasn't started running.
+    /// Returns the id of the new job's row, or `None` if it was skipped.
+    ///
+    /// This is meant for debouncing bursts of work, such as refreshing a
+    /// cache whenever a record changes, where one pending job covers all of
+    /// them. Unlike a uniqueness constraint, a job is enqueued again once the
+    /// earlier one starts running or is older than `window`. Arguments are
+    /// compared as JSON, after [`to_value`](Self::to_value). Jobs which were
+    /// abandoned don't count. Concurrent calls for the same job wait for each
+    /// other, so only one of them enqueues it.
+    fn enqueue_debounced(
+        self,
+        conn: &mut PgConnection,
+        window: Duration,
+    ) -> Result<Option<i64>, EnqueueError> {
+        storage::enqueue_job_debounced(conn, self, window)
+    }
+
+    /// Serialize this job to be stored in the `background_jobs` table.
+    ///
+    /// Defaults to using the job's `Serialize` implementation. Override this
+    /// along with [`from_value`](Self::from_value) if the job needs to be
+    /// stored in a different representation.
+    fn to_value(&self) -> Result<Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+
+    /// Deserialize a job which was serialized with [`to_value`](Self::to_value).
+    ///
+    /// Defaults to using the job's `Deserialize` implementation.
+    fn from_value(value: Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    /// The job type and data which enqueueing this job would store, without
+    /// touching the database.
+    ///
+    /// The job is validated and serialized the same way as it is by
+    /// [`enqueue`](Self::enqueue), so this can be used in unit tests to check
+    /// what gets stored, such as when arguments have custom serde attributes.
+    fn preview(&self) -> Result<(String, Value), EnqueueError> {
+        self.validate()?;
+        Ok((Self::JOB_TYPE.to_string(), self.to_value()?))
+    }
+
+    /// Enqueue this job using an async connection from `diesel-async`.
+    ///
+    /// This is the same as [`enqueue`](Self::enqueue), for applications
+    /// which can't block on a synchronous connection, such as async web
+    /// servers. Only the insert is async; jobs are still run by the
+    /// synchronous runner. Requires the `async` feature.
+    #[cfg(feature = "async")]
+    fn enqueue_async(
+        self,
+        conn: &mut diesel_async::AsyncPgConnection,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), EnqueueError>> + Send + '_>>
+    {
+        let job_data = self.validate().and_then(|()| Ok(self.to_value()?));
+        Box::pin(async move { storage::enqueue_job_async(conn, Self::JOB_TYPE, job_data?).await })
+    }
+
+    /// Whether this job should be run right now.
+    ///
+    /// This is checked immediately before the job is performed. If it returns
+    /// `false`, the job is put back in the queue and tried again later. This
+    /// does not count as a failure.
+    ///
+    /// When using `#[swirl::background_job]`, this can be set with
+    /// `#[swirl::background_job(guard = "path::to::function")]`, where the
+    /// function takes a reference to the environment and returns a `bool`.
+    ///
+    /// Defaults to always returning `true`.
+    fn should_run(&self, _env: &Self::Environment) -> bool {
+        true
     }
 
     /// The logic involved in actually performing this job.
-    fn perform(self, env: &Self::Environment, pool: &dyn DieselPoolObj)
-        -> Result<(), PerformError>;
+    ///
+    /// `conn` is the connection which the runner used to lock this job. The
+    /// job is run inside of a transaction on that connection, so anything
+    /// done with it will be committed if and only if the job succeeds. The
+    /// connection pool, which can be used to get additional connections for
+    /// work which should not be rolled back, is available through `ctx`.
+    fn perform(
+        self,
+        env: &Self::Environment,
+        conn: &mut PgConnection,
+        ctx: &JobContext<'_>,
+    ) -> Result<(), PerformError>;
 }
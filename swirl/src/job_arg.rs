@@ -0,0 +1,60 @@
+//! Job arguments which are stored as a different type
+//!
+//! Arguments to `#[swirl::background_job]` functions are normally serialized
+//! as-is. Types which can't be serialized, such as a handle to a resource,
+//! can still be taken by implementing [`JobArg`] for them, and marking the
+//! argument with `#[job_arg]`:
+//!
+//! ```ignore
+//! #[swirl::background_job]
+//! fn resize_image(#[job_arg] image: Image) -> Result<(), PerformError> {
+//!     // ...
+//! }
+//! ```
+//!
+//! The argument is stored as its [`JobArg::Storable`] representation, and
+//! turned back into the original type before the job is run. The functions
+//! in this module implement that with `#[serde(with = "swirl::job_arg")]`,
+//! which is what `#[job_arg]` expands to.
+
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+
+/// A job argument which is stored as another, serializable type
+pub trait JobArg: Sized {
+    /// The type which is stored in the job's row
+    type Storable: Serialize + DeserializeOwned;
+    /// The error returned when the stored value can't be turned back into
+    /// this type
+    type Error: Display;
+
+    /// Converts the argument into the value which is stored
+    fn to_storable(&self) -> Self::Storable;
+
+    /// Recreates the argument from the stored value, before the job is run.
+    ///
+    /// If this fails, the job fails the same way as if its arguments couldn't
+    /// be deserialized.
+    fn from_storable(storable: Self::Storable) -> Result<Self, Self::Error>;
+}
+
+/// Serializes an argument as its storable representation
+pub fn serialize<T, S>(arg: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: JobArg,
+    S: Serializer,
+{
+    arg.to_storable().serialize(serializer)
+}
+
+/// Deserializes an argument's storable representation, and recreates the
+/// argument from it
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: JobArg,
+    D: Deserializer<'de>,
+{
+    let storable = T::Storable::deserialize(deserializer)?;
+    T::from_storable(storable).map_err(de::Error::custom)
+}
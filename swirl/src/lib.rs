@@ -1,31 +1,48 @@
 #![deny(warnings)]
 
+#[doc(hidden)]
 #[macro_use]
-extern crate diesel;
-
+pub extern crate diesel;
 #[doc(hidden)]
 pub extern crate inventory;
 #[doc(hidden)]
 pub extern crate serde;
+#[doc(hidden)]
+pub extern crate serde_json;
 
+mod context;
+mod enqueue_batch;
+mod enqueue_builder;
+mod enqueue_observer;
 mod job;
+mod otel;
 mod registry;
 mod runner;
 mod storage;
 
 pub mod db;
 pub mod errors;
+pub mod job_arg;
 pub mod schema;
+pub mod sql;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub use swirl_proc_macro::*;
 
 #[doc(hidden)]
 pub use serde_derive::{Deserialize, Serialize};
 
+pub use context::JobContext;
+pub use enqueue_batch::EnqueueBatch;
+pub use enqueue_builder::EnqueueBuilder;
+pub use enqueue_observer::set_enqueue_observer;
 pub use errors::*;
 pub use job::*;
-pub use registry::Registry;
+pub use job_arg::JobArg;
+pub use registry::{JobMeta, Registry};
 pub use runner::*;
+pub use storage::{is_job_running, pause_job_type, request_cancel, resume_job_type};
 
 #[doc(hidden)]
 pub use registry::JobVTable;
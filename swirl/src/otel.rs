@@ -0,0 +1,56 @@
+//! Propagation of the OpenTelemetry context from the code which enqueues a
+//! job to the job itself.
+//!
+//! The context is serialized with the globally configured text map
+//! propagator, so the same propagator must be configured both where jobs are
+//! enqueued and where they are run. When the `otel` feature is disabled, no
+//! context is stored, and any stored context is ignored.
+
+use serde_json::Value;
+
+/// Serializes the current context, if there is anything to propagate
+#[cfg(feature = "otel")]
+pub fn current_context() -> Option<Value> {
+    use opentelemetry::{global, Context};
+    use std::collections::HashMap;
+
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut carrier)
+    });
+    if carrier.is_empty() {
+        None
+    } else {
+        serde_json::to_value(carrier).ok()
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn current_context() -> Option<Value> {
+    None
+}
+
+/// Runs the given function with the deserialized context as the current
+/// context, so that any spans it creates are children of the span which
+/// enqueued the job.
+#[cfg(feature = "otel")]
+pub fn with_context<T>(context: Option<Value>, f: impl FnOnce() -> T) -> T {
+    use opentelemetry::global;
+    use std::collections::HashMap;
+
+    let carrier = context.and_then(|c| serde_json::from_value::<HashMap<String, String>>(c).ok());
+    match carrier {
+        Some(carrier) => {
+            let context =
+                global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+            let _guard = context.attach();
+            f()
+        }
+        None => f(),
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn with_context<T>(_context: Option<Value>, f: impl FnOnce() -> T) -> T {
+    f()
+}
@@ -1,11 +1,13 @@
 #![allow(clippy::new_without_default)] // https://github.com/rust-lang/rust-clippy/issues/3632
 
+use diesel::PgConnection;
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::time::Duration;
 
-use crate::db::DieselPoolObj;
-use crate::errors::PerformError;
+use crate::context::JobContext;
+use crate::errors::{BadPayload, EnvironmentMismatch, PerformError};
 use crate::Job;
 
 #[derive(Default)]
@@ -18,21 +20,119 @@ pub struct Registry<Env> {
 }
 
 impl<Env: 'static> Registry<Env> {
+    /// Creates a registry with no jobs in it, for jobs to be added to with
+    /// [`register`](Self::register)
+    pub fn new() -> Self {
+        Self {
+            jobs: HashMap::new(),
+            _marker: PhantomData,
+        }
+    }
+
     /// Loads the registry from all invocations of [`register_job!`] for this
     /// environment type
+    ///
+    /// # Panics
+    ///
+    /// Panics if two different jobs for this environment type have the same
+    /// [`Job::JOB_TYPE`], such as two `#[background_job]` functions with the
+    /// same name in different modules. Only one of them could be run, so
+    /// jobs enqueued by the other would run the wrong code.
     pub fn load() -> Self {
-        let jobs = inventory::iter::<JobVTable>
+        let mut registry = Self::new();
+        let vtables = inventory::iter::<JobVTable>
+            .into_iter()
+            .filter(|vtable| vtable.env_type == TypeId::of::<Env>());
+        for &vtable in vtables {
+            if let Err(e) = registry.insert(vtable) {
+                panic!("{}", e);
+            }
+        }
+        registry
+    }
+
+    /// Checks that every job registered with [`register_job!`] uses this
+    /// registry's environment type.
+    ///
+    /// Jobs with a different environment type are not loaded into this
+    /// registry, so any of them which are enqueued will fail every time they
+    /// are run. If all of your jobs are meant to be run by the same runner,
+    /// you can call this on startup to catch this mistake early.
+    pub fn check_env_coverage(&self) -> Result<(), EnvironmentMismatch> {
+        let mismatched_jobs = inventory::iter::<JobVTable>
             .into_iter()
-            .filter(|vtable| vtable.env_type == TypeId::of::<Env>())
-            .map(|&vtable| (vtable.job_type, vtable))
-            .collect();
+            .filter(|vtable| vtable.env_type != TypeId::of::<Env>())
+            .map(|vtable| (vtable.job_type, vtable.env_type_name))
+            .collect::<Vec<_>>();
 
-        Self {
-            jobs: jobs,
-            _marker: PhantomData,
+        if mismatched_jobs.is_empty() {
+            Ok(())
+        } else {
+            Err(EnvironmentMismatch::new(
+                std::any::type_name::<Env>(),
+                mismatched_jobs,
+            ))
         }
     }
 
+    /// Returns `true` if no jobs were registered for this environment type
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Add a job to the registry, whether or not it was registered with
+    /// [`register_job!`]. Registering a job which is already in the registry
+    /// does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a different job with the same [`Job::JOB_TYPE`] is already
+    /// in the registry, the same as [`load`](Self::load).
+    pub fn register<J: Job<Environment = Env>>(&mut self) {
+        if let Err(e) = self.try_register::<J>() {
+            panic!("{}", e);
+        }
+    }
+
+    /// Like [`register`](Self::register), but returns the message it would
+    /// panic with instead
+    pub(crate) fn try_register<J: Job<Environment = Env>>(&mut self) -> Result<(), String> {
+        self.insert(JobVTable::from_job::<J>())
+    }
+
+    fn insert(&mut self, vtable: JobVTable) -> Result<(), String> {
+        match self.jobs.get(vtable.job_type) {
+            Some(existing) if existing.job_type_name != vtable.job_type_name => Err(format!(
+                "The job type `{}` is used by both `{}` and `{}`. \
+                 Each job must have a unique `JOB_TYPE`.",
+                vtable.job_type, existing.job_type_name, vtable.job_type_name,
+            )),
+            _ => {
+                self.jobs.insert(vtable.job_type, vtable);
+                Ok(())
+            }
+        }
+    }
+
+    /// The types of every job in the registry, in no particular order
+    pub fn job_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.jobs.keys().copied()
+    }
+
+    /// Information about how a registered job type is run, for tooling such
+    /// as dashboards. Returns `None` if the job type isn't registered.
+    pub fn metadata(&self, job_type: &str) -> Option<JobMeta> {
+        self.jobs.get(job_type).map(|vtable| JobMeta {
+            job_type: vtable.job_type,
+            env_type_name: vtable.env_type_name,
+            in_transaction: vtable.in_transaction,
+            exclusive: vtable.exclusive,
+            at_most_once: vtable.at_most_once,
+            statement_timeout: vtable.statement_timeout,
+            weight: vtable.weight,
+        })
+    }
+
     /// Get the perform function for a given job type
     pub fn get(&self, job_type: &str) -> Option<PerformJob<Env>> {
         self.jobs.get(job_type).map(|&vtable| PerformJob {
@@ -42,14 +142,71 @@ impl<Env: 'static> Registry<Env> {
     }
 }
 
+/// Information about a registered job type, returned by
+/// [`Registry::metadata`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobMeta {
+    /// The job's [`Job::JOB_TYPE`]
+    pub job_type: &'static str,
+    /// The name of the job's environment type
+    pub env_type_name: &'static str,
+    /// The job's [`Job::IN_TRANSACTION`]
+    pub in_transaction: bool,
+    /// The job's [`Job::EXCLUSIVE`]
+    pub exclusive: bool,
+    /// The job's [`Job::AT_MOST_ONCE`]
+    pub at_most_once: bool,
+    /// The job's [`Job::STATEMENT_TIMEOUT`]
+    pub statement_timeout: Option<Duration>,
+    /// The job's [`Job::WEIGHT`]
+    pub weight: u32,
+}
+
+/// Defines a function which returns a [`Registry`] containing the given jobs.
+///
+/// ```ignore
+/// swirl::jobs! {
+///     pub fn builtin_jobs() -> Registry<Environment> {
+///         resize_image::Job,
+///         SendEmail,
+///     }
+/// }
+///
+/// let runner = Runner::builder(environment)
+///     .registry(builtin_jobs())
+///     .build();
+/// ```
+///
+/// Jobs defined with [`background_job`](crate::background_job) are listed as
+/// `function_name::Job`. The jobs don't need to be registered with
+/// [`register_job!`], and the registry doesn't load any jobs which were.
+///
+/// [`register_job!`] relies on the linker keeping each registration around,
+/// which it may not do for jobs defined in a library which is otherwise
+/// unused, or in some release builds. Because this names each job in code
+/// which the application calls, they can't be left out. Prefer this when
+/// jobs have gone missing from the registry, or when you want the list of
+/// jobs a runner knows about to be explicit. The cost is having to add every
+/// new job to the list.
+#[macro_export]
+macro_rules! jobs {
+    ($vis:vis fn $name:ident() -> Registry<$env:ty> { $($job:ty),* $(,)? }) => {
+        $vis fn $name() -> $crate::Registry<$env> {
+            let mut registry = $crate::Registry::new();
+            $(registry.register::<$job>();)*
+            registry
+        }
+    };
+}
+
 /// Register a job to be run by swirl. This must be called for any
 /// implementors of [`swirl::Job`]
 #[macro_export]
 macro_rules! register_job {
     ($job_ty: ty) => {
         $crate::inventory::submit! {
-            #![crate = swirl]
-            swirl::JobVTable::from_job::<$job_ty>()
+            #![crate = $crate]
+            $crate::JobVTable::from_job::<$job_ty>()
         }
     };
 }
@@ -58,8 +215,20 @@ macro_rules! register_job {
 #[derive(Clone, Copy)]
 pub struct JobVTable {
     env_type: TypeId,
+    env_type_name: &'static str,
     job_type: &'static str,
-    perform: fn(serde_json::Value, &dyn Any, &dyn DieselPoolObj) -> Result<(), PerformError>,
+    job_type_name: &'static str,
+    in_transaction: bool,
+    exclusive: bool,
+    at_most_once: bool,
+    statement_timeout: Option<Duration>,
+    weight: u32,
+    perform: fn(
+        serde_json::Value,
+        &dyn Any,
+        &mut PgConnection,
+        &JobContext<'_>,
+    ) -> Result<PerformOutcome, PerformError>,
 }
 
 inventory::collect!(JobVTable);
@@ -68,24 +237,46 @@ impl JobVTable {
     pub fn from_job<T: Job>() -> Self {
         Self {
             env_type: TypeId::of::<T::Environment>(),
+            env_type_name: std::any::type_name::<T::Environment>(),
             job_type: T::JOB_TYPE,
+            job_type_name: std::any::type_name::<T>(),
+            in_transaction: T::IN_TRANSACTION,
+            exclusive: T::EXCLUSIVE,
+            at_most_once: T::AT_MOST_ONCE,
+            statement_timeout: T::STATEMENT_TIMEOUT,
+            weight: T::WEIGHT,
             perform: perform_job::<T>,
         }
     }
 }
 
+/// The result of a job which did not fail
+pub enum PerformOutcome {
+    /// The job ran to completion
+    Completed,
+    /// The job's guard returned `false`, so it was not run
+    Snoozed,
+    /// The job's time to live had passed, so it was not run
+    Expired,
+}
+
 fn perform_job<T: Job>(
     data: serde_json::Value,
     env: &dyn Any,
-    pool: &dyn DieselPoolObj,
-) -> Result<(), PerformError> {
+    conn: &mut PgConnection,
+    ctx: &JobContext<'_>,
+) -> Result<PerformOutcome, PerformError> {
     let environment = env.downcast_ref().ok_or_else::<PerformError, _>(|| {
         "Incorrect environment type. This should never happen. \
          Please open an issue at https://github.com/sgrif/swirl/issues/new"
             .into()
     })?;
-    let data = serde_json::from_value(data)?;
-    T::perform(data, environment, pool)
+    let data = T::from_value(data).map_err(BadPayload)?;
+    if !data.should_run(environment) {
+        return Ok(PerformOutcome::Snoozed);
+    }
+    T::perform(data, environment, conn, ctx)?;
+    Ok(PerformOutcome::Completed)
 }
 
 pub struct PerformJob<Env> {
@@ -94,13 +285,41 @@ pub struct PerformJob<Env> {
 }
 
 impl<Env: 'static> PerformJob<Env> {
+    /// Whether the job should be given the connection its row was locked
+    /// with, or a separate connection which is not in a transaction
+    pub fn in_transaction(&self) -> bool {
+        self.vtable.in_transaction
+    }
+
+    /// Whether the job must not run at the same time as any other job
+    pub fn exclusive(&self) -> bool {
+        self.vtable.exclusive
+    }
+
+    /// Whether the job is marked as attempted before it's run, so it's never
+    /// run again
+    pub fn at_most_once(&self) -> bool {
+        self.vtable.at_most_once
+    }
+
+    /// How long each statement the job runs in its transaction may take
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.vtable.statement_timeout
+    }
+
+    /// How much of the runner's weight budget the job takes up
+    pub fn weight(&self) -> u32 {
+        self.vtable.weight
+    }
+
     pub fn perform(
         &self,
         data: serde_json::Value,
         env: &Env,
-        pool: &dyn DieselPoolObj,
-    ) -> Result<(), PerformError> {
+        conn: &mut PgConnection,
+        ctx: &JobContext<'_>,
+    ) -> Result<PerformOutcome, PerformError> {
         let perform_fn = self.vtable.perform;
-        perform_fn(data, env, pool)
+        perform_fn(data, env, conn, ctx)
     }
 }
@@ -2,28 +2,89 @@ use diesel::prelude::*;
 #[cfg(feature = "r2d2")]
 use diesel::r2d2;
 use std::any::Any;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
 use std::error::Error;
 use std::panic::{catch_unwind, AssertUnwindSafe, PanicInfo, RefUnwindSafe, UnwindSafe};
-use std::sync::Arc;
-use std::time::Duration;
-use threadpool::ThreadPool;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::db::*;
 use crate::errors::*;
-use crate::{storage, Registry};
+use crate::registry::PerformOutcome;
+use crate::{otel, storage, Job, JobContext, Registry};
 use event::*;
+use exclusive::ExclusiveGate;
+use fetch_stats::FetchCounters;
+use hooks::Hooks;
+use running_jobs::RunningJobs;
+use thread_pool::WorkerPool;
+use weight_budget::WeightBudget;
 
+mod bad_payload;
 mod channel;
+mod commit_strategy;
 mod event;
+mod exclusive;
+mod executor;
+mod fetch_mode;
+mod fetch_stats;
+mod filter;
+mod hooks;
+mod panic_hook;
+mod report;
+mod running_jobs;
+mod scheduler_lock;
+mod thread_pool;
+mod weight_budget;
+
+pub use bad_payload::BadPayloadPolicy;
+pub use commit_strategy::CommitStrategy;
+pub use executor::JobExecutor;
+#[cfg(feature = "rayon")]
+pub use executor::RayonExecutor;
+pub use fetch_mode::FetchMode;
+pub use fetch_stats::FetchStats;
+pub use filter::JobFilter;
+pub use hooks::JobRun;
+pub use report::RunReport;
+pub use running_jobs::StuckJob;
+pub use scheduler_lock::{SchedulerLock, DEFAULT_SCHEDULER_LOCK_KEY};
 
 pub struct NoConnectionPoolGiven;
 
 #[allow(missing_debug_implementations)]
 pub struct Builder<Env, ConnectionPoolBuilder> {
     connection_pool_or_builder: ConnectionPoolBuilder,
+    /// Pools for the databases after the first, for runners given more than
+    /// one pool
+    shards: Vec<ConnectionPoolBuilder>,
+    /// Pools which jobs of the given types get their connection from
+    job_pools: HashMap<String, ConnectionPoolBuilder>,
     environment: Env,
     thread_count: Option<usize>,
+    thread_name_prefix: Option<String>,
+    lazy_threads: bool,
     job_start_timeout: Option<Duration>,
+    job_type_start_timeouts: HashMap<String, Duration>,
+    wait_while_saturated: bool,
+    fail_fast: bool,
+    retry_jitter: Option<f64>,
+    max_retries: Option<u32>,
+    panic_backtraces: bool,
+    registry: Option<Registry<Env>>,
+    require_non_empty_registry: bool,
+    log_summary: bool,
+    commit_strategy: CommitStrategy,
+    fetch_mode: FetchMode,
+    abandoned_job_ttl: Option<Duration>,
+    scheduler_lock_key: i64,
+    weight_budget: Option<u32>,
+    bad_payload_policy: BadPayloadPolicy,
+    executor: Option<Box<dyn JobExecutor>>,
+    hooks: Hooks,
 }
 
 impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
@@ -39,24 +100,504 @@ impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
         self.thread_count.unwrap_or(5)
     }
 
+    /// Set the prefix of the names given to the threads used to run jobs.
+    /// Each thread is named with the prefix and its index, such as
+    /// `swirl-worker-0`. These names show up in panic messages, debuggers,
+    /// and profilers.
+    ///
+    /// Defaults to `"swirl-worker"`
+    pub fn thread_name_prefix<S: Into<String>>(mut self, thread_name_prefix: S) -> Self {
+        self.thread_name_prefix = Some(thread_name_prefix.into());
+        self
+    }
+
+    fn get_thread_name_prefix(&self) -> String {
+        self.thread_name_prefix
+            .clone()
+            .unwrap_or_else(|| "swirl-worker".into())
+    }
+
+    /// Only start worker threads once there are jobs for them to run.
+    ///
+    /// By default, every thread is started when the runner is built. Runners
+    /// in short lived processes, which run whatever is pending and exit, often
+    /// find the queue empty, and don't need more than one thread to find that
+    /// out. With this set, jobs are fetched one at a time, and a thread is
+    /// only started when a job has been found and every thread which was
+    /// already started is busy. Threads stay around once they've been
+    /// started, up to [`thread_count`](Self::thread_count).
+    ///
+    /// Defaults to `false`
+    pub fn lazy_threads(mut self, lazy_threads: bool) -> Self {
+        self.lazy_threads = lazy_threads;
+        self
+    }
+
+    /// Run jobs with the given executor, instead of a thread pool created by
+    /// the runner.
+    ///
+    /// The runner runs as many jobs at once as the executor's
+    /// [`max_count`](JobExecutor::max_count), and the connection pool is
+    /// sized from it the same way as from [`thread_count`](Self::thread_count).
+    /// `thread_count` and [`thread_name_prefix`](Self::thread_name_prefix) are
+    /// ignored.
+    pub fn executor<E: JobExecutor>(mut self, executor: E) -> Self {
+        self.executor = Some(Box::new(executor));
+        self
+    }
+
+    fn build_executor(&mut self) -> Box<dyn JobExecutor> {
+        match self.executor.take() {
+            Some(executor) => executor,
+            None => build_thread_pool(
+                &self.get_thread_name_prefix(),
+                self.get_thread_count(),
+                self.lazy_threads,
+            ),
+        }
+    }
+
     /// The amount of time to wait for a job to start before assuming an error
     /// has occurred.
     ///
+    /// This is measured from the last time any worker started or finished a
+    /// job, so a run which is making steady progress will not time out, no
+    /// matter how long it takes overall.
+    ///
     /// Defaults to 10 seconds.
     pub fn job_start_timeout(mut self, timeout: Duration) -> Self {
         self.job_start_timeout = Some(timeout);
         self
     }
 
+    /// Allow more time for jobs to start while a job of the given type is
+    /// running.
+    ///
+    /// When every worker thread is busy, the next job can't start until one
+    /// of the running jobs finishes. If some job types are known to take a
+    /// long time, set a timeout for them here so that the runner doesn't
+    /// assume an error occurred while waiting for them.
+    ///
+    /// While jobs of several of these types are running, the longest of their
+    /// timeouts is used. This never shortens the timeout given to
+    /// [`job_start_timeout`](Self::job_start_timeout).
+    pub fn job_start_timeout_for<S: Into<String>>(
+        mut self,
+        job_type: S,
+        timeout: Duration,
+    ) -> Self {
+        self.job_type_start_timeouts
+            .insert(job_type.into(), timeout);
+        self
+    }
+
+    /// Keep waiting, instead of timing out, while every worker is busy
+    /// running a job.
+    ///
+    /// When every worker is busy, the next job can't start until a running
+    /// job finishes, so a run with jobs which take longer than
+    /// [`job_start_timeout`](Self::job_start_timeout) fails with
+    /// [`FetchError::NoMessageReceived`] even though nothing is wrong. With
+    /// this set, the timeout only applies while a worker is looking for a
+    /// job, such as when a query for the next job hangs. A job which never
+    /// finishes holds up the run forever instead of failing it, so use
+    /// [`Runner::stuck_jobs`] to find out about them.
+    ///
+    /// Defaults to `false`
+    pub fn wait_while_saturated(mut self, wait_while_saturated: bool) -> Self {
+        self.wait_while_saturated = wait_while_saturated;
+        self
+    }
+
+    /// Stop a run as soon as one of its jobs fails.
+    ///
+    /// This is meant for batch pipelines where the remaining jobs are
+    /// pointless once one of them has failed. No more jobs are started after
+    /// a job fails or is abandoned, jobs which are already running are waited
+    /// for, and the run returns a [`RunReport`] whose
+    /// [`stopped_after_failure`](RunReport::stopped_after_failure) is `true`.
+    /// Jobs which weren't started are left in the queue. Jobs which were
+    /// fetched at the same time as the failing job, by other workers, may
+    /// still be run. [`Runner::run_forever`] returns after such a run.
+    ///
+    /// Defaults to `false`
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Randomize the delay before failed jobs are retried.
+    ///
+    /// Failed jobs are retried after `2 ^ retry_count` minutes. If many jobs
+    /// fail at the same time (for example because a service they depend on
+    /// went down), they will all be retried at the same time as well. Setting
+    /// this spreads those retries out.
+    ///
+    /// `fraction` is the portion of the delay which is randomized, and must be
+    /// between `0.0` and `1.0`. For example, with a value of `0.25` a job which
+    /// would be retried in 4 minutes will instead be retried somewhere between
+    /// 3 and 4 minutes from now. A value of `1.0` picks any time between now
+    /// and the full delay.
+    ///
+    /// Defaults to `0.0`
+    pub fn retry_jitter(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "retry_jitter must be between 0.0 and 1.0"
+        );
+        self.retry_jitter = Some(fraction);
+        self
+    }
+
+    /// Stop retrying jobs once they have failed this many times.
+    ///
+    /// Abandoned jobs are left in the `background_jobs` table so they can be
+    /// inspected, and still count towards
+    /// [`Runner::check_for_failed_jobs`], but they will never be run again.
+    /// The number of jobs abandoned by a call to
+    /// [`Runner::run_all_pending_jobs`] is available from the returned
+    /// [`RunReport`].
+    ///
+    /// Defaults to retrying forever
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Include a backtrace of where a job panicked in the error it fails
+    /// with.
+    ///
+    /// The backtrace is captured when the job panics, before the stack is
+    /// unwound, regardless of `RUST_BACKTRACE`. It is appended to the error's
+    /// message, so it is logged with the failure and passed to
+    /// [`record_failure`](Self::record_failure). Capturing a backtrace is slow,
+    /// and they are only useful if the binary has debug info.
+    ///
+    /// Defaults to `false`
+    pub fn panic_backtraces(mut self, panic_backtraces: bool) -> Self {
+        self.panic_backtraces = panic_backtraces;
+        self
+    }
+
+    /// Panic when building the runner if no jobs are registered for its
+    /// environment type.
+    ///
+    /// An empty registry is almost always a misconfiguration, such as the
+    /// jobs being defined with a different environment type, or having been
+    /// removed by the linker. Every job the runner picks up will then fail
+    /// with an unknown job type. When this is not set, a warning is printed
+    /// instead.
+    ///
+    /// Defaults to `false`
+    pub fn require_non_empty_registry(mut self, require: bool) -> Self {
+        self.require_non_empty_registry = require;
+        self
+    }
+
+    /// Print a summary to stderr after each call to
+    /// [`Runner::run_all_pending_jobs`], such as
+    /// `Ran 5 jobs (4 succeeded, 1 failed) in 120ms`.
+    ///
+    /// This is meant for runners which are invoked from cron or other batch
+    /// jobs. Runners which call `run_all_pending_jobs` in a loop will
+    /// probably not want this.
+    ///
+    /// Defaults to `false`
+    pub fn log_summary(mut self, log_summary: bool) -> Self {
+        self.log_summary = log_summary;
+        self
+    }
+
+    /// Choose whether a job's work is committed in the same transaction as
+    /// the update of its row, or before it. See [`CommitStrategy`] for the
+    /// trade-off.
+    ///
+    /// Jobs which don't run in a transaction (see [`Job::IN_TRANSACTION`])
+    /// are unaffected.
+    ///
+    /// Defaults to [`CommitStrategy::SingleTransaction`]
+    pub fn commit_strategy(mut self, commit_strategy: CommitStrategy) -> Self {
+        self.commit_strategy = commit_strategy;
+        self
+    }
+
+    /// Choose whether jobs are locked for as long as they run, or claimed
+    /// and run without holding a connection. See [`FetchMode`] for the
+    /// trade-off.
+    ///
+    /// Defaults to [`FetchMode::RowLock`]
+    pub fn fetch_mode(mut self, fetch_mode: FetchMode) -> Self {
+        self.fetch_mode = fetch_mode;
+        self
+    }
+
+    /// Delete abandoned jobs once it has been `ttl` since they were last
+    /// attempted.
+    ///
+    /// Jobs which are abandoned, such as ones which reached
+    /// [`max_retries`](Self::max_retries), are left in the table so they can
+    /// be inspected, which means the table keeps growing. With a TTL set,
+    /// [`Runner::run_forever`] deletes old abandoned jobs whenever the queue
+    /// is empty, and [`Runner::prune_abandoned_jobs`] can be called to do so
+    /// at any other time.
+    ///
+    /// By default, abandoned jobs are never deleted.
+    pub fn abandoned_job_ttl(mut self, ttl: Duration) -> Self {
+        self.abandoned_job_ttl = Some(ttl);
+        self
+    }
+
+    /// Limit the total [weight](Job::WEIGHT) of the jobs which run at once.
+    ///
+    /// Once a worker knows which job it's running, it waits until the
+    /// weights of the jobs which are already running leave room for it. The
+    /// thread count still limits how many jobs run at once, so with the
+    /// default weight of 1, this only matters if it's lower than the thread
+    /// count. Like [exclusive jobs](Job::EXCLUSIVE), this only applies to the
+    /// threads of one runner.
+    ///
+    /// Defaults to no limit
+    pub fn weight_budget(mut self, budget: u32) -> Self {
+        self.weight_budget = Some(budget);
+        self
+    }
+
+    /// Set the key of the advisory lock taken by
+    /// [`Runner::try_become_scheduler`].
+    ///
+    /// Processes which use the same key compete to be the scheduler. Only
+    /// set this if another application uses the same database, and needs a
+    /// scheduler of its own.
+    ///
+    /// Defaults to [`DEFAULT_SCHEDULER_LOCK_KEY`]
+    pub fn scheduler_lock_key(mut self, key: i64) -> Self {
+        self.scheduler_lock_key = key;
+        self
+    }
+
+    /// Use the given registry, instead of loading one from every job
+    /// registered with [`register_job!`](crate::register_job).
+    ///
+    /// This is meant for registries built with [`jobs!`](crate::jobs), which
+    /// don't rely on the linker keeping each job's registration around.
+    pub fn registry(mut self, registry: Registry<Env>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    fn load_registry(&mut self) -> Registry<Env>
+    where
+        Env: 'static,
+    {
+        let registry = self.registry.take().unwrap_or_else(Registry::load);
+        if registry.is_empty() {
+            let message = format!(
+                "No jobs are registered for the environment type `{}`",
+                std::any::type_name::<Env>(),
+            );
+            if self.require_non_empty_registry {
+                panic!("{}", message);
+            }
+            eprintln!("Warning: {}", message);
+        }
+        registry
+    }
+
+    /// Register a function to be called each time a job completes
+    /// successfully.
+    ///
+    /// The function is called on the worker thread which ran the job, after
+    /// the job has been removed from the queue.
+    pub fn on_success<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>) + Send + Sync + 'static,
+    {
+        self.hooks.on_success = Some(Arc::new(hook));
+        self
+    }
+
+    /// Register a function to be called each time a job fails, either by
+    /// returning an error or by panicking.
+    ///
+    /// The function is called on the worker thread which ran the job, after
+    /// the failure has been recorded. Jobs which are snoozed by their guard
+    /// are neither successes nor failures, and do not call either hook.
+    pub fn on_failure<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>, &PerformError) + Send + Sync + 'static,
+    {
+        self.hooks.on_failure = Some(Arc::new(hook));
+        self
+    }
+
+    /// Register a function to be called when a job fails for the last time,
+    /// and is abandoned because it reached
+    /// [`max_retries`](Self::max_retries).
+    ///
+    /// The function is given the number of times the job has failed, and the
+    /// error from its last attempt. It's called once per abandoned job, after
+    /// the hook registered with [`on_failure`](Self::on_failure), on the
+    /// worker thread which ran the job.
+    pub fn on_abandon<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>, u32, &PerformError) + Send + Sync + 'static,
+    {
+        self.hooks.on_abandon = Some(Arc::new(hook));
+        self
+    }
+
+    /// Register a function to be called each time a job is deleted without
+    /// being run because it was enqueued with a time to live which has
+    /// passed.
+    ///
+    /// The `duration` given to this function is always zero.
+    pub fn on_expired<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&JobRun<'_>) + Send + Sync + 'static,
+    {
+        self.hooks.on_expired = Some(Arc::new(hook));
+        self
+    }
+
+    /// Choose what happens to jobs whose arguments can't be deserialized,
+    /// such as rows enqueued before the job's arguments were changed. See
+    /// [`BadPayloadPolicy`] for the options.
+    ///
+    /// Jobs whose type isn't registered are not affected, and are always
+    /// retried.
+    ///
+    /// Defaults to [`BadPayloadPolicy::Retry`]
+    pub fn on_bad_payload(mut self, policy: BadPayloadPolicy) -> Self {
+        self.bad_payload_policy = policy;
+        self
+    }
+
+    /// Register a function which can rewrite or reject the arguments of each
+    /// job before they are deserialized.
+    ///
+    /// The function is given the job's type and its arguments, as they were
+    /// loaded from the `background_jobs` table. Changes it makes are only
+    /// seen by the job, and aren't written back to the row. This can be used
+    /// to migrate the arguments of jobs which were enqueued before the job's
+    /// arguments changed, or to strip fields which shouldn't end up in logs.
+    /// If it returns an error, the job isn't run, and fails with that error.
+    ///
+    /// Jobs run with [`Runner::run_job_inline`] aren't affected, since their
+    /// arguments are never serialized.
+    pub fn transform_payload<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, &mut serde_json::Value) -> Result<(), PerformError> + Send + Sync + 'static,
+    {
+        self.hooks.transform_payload = Some(Arc::new(f));
+        self
+    }
+
+    /// Register a function which records a failed job in the database.
+    ///
+    /// The function is given the connection the job was locked with, the
+    /// job's id, and the error, and runs in the same transaction which
+    /// updates the job's retry count. This can be used to fill in columns you
+    /// have added to the `background_jobs` table, such as the last error or
+    /// the host the job failed on. If it returns an error, the error is
+    /// logged, and anything it wrote is rolled back, but the job is still
+    /// marked as failed.
+    pub fn record_failure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut PgConnection, i64, &PerformError) -> QueryResult<()> + Send + Sync + 'static,
+    {
+        self.hooks.record_failure = Some(Arc::new(f));
+        self
+    }
+
+    /// Register a function to be called when
+    /// [`Runner::run_forever`] finds the queue empty.
+    ///
+    /// The function is called on the thread running `run_forever`, before it
+    /// sleeps until the next poll. It is called at most once per poll
+    /// interval, and not at all while there are jobs to run. This can be used
+    /// for housekeeping, such as flushing metrics, without a separate thread.
+    /// Jobs are not fetched while it runs, so it should return quickly.
+    pub fn on_idle<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_idle = Some(Arc::new(hook));
+        self
+    }
+
+    /// Register a function to be called when
+    /// [`Runner::run_all_pending_jobs`] gives up waiting for a job to start,
+    /// just before it returns [`FetchError::NoMessageReceived`].
+    ///
+    /// The function is given the number of jobs which were queued on the
+    /// thread pool, but hadn't reported back yet. This usually means every
+    /// thread is busy with a job that has hung, or the connection pool is
+    /// too small. This can be used to log or alert with more context than
+    /// the error has, since the error alone is easy to mistake for a
+    /// database problem.
+    pub fn on_job_start_timeout<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.hooks.on_job_start_timeout = Some(Arc::new(hook));
+        self
+    }
+
     /// Provide a connection pool to be used by the runner
     pub fn connection_pool<NewPool>(self, pool: NewPool) -> Builder<Env, NewPool> {
         Builder {
             connection_pool_or_builder: pool,
+            shards: Vec::new(),
+            job_pools: HashMap::new(),
             environment: self.environment,
             thread_count: self.thread_count,
+            thread_name_prefix: self.thread_name_prefix,
+            lazy_threads: self.lazy_threads,
             job_start_timeout: self.job_start_timeout,
+            job_type_start_timeouts: self.job_type_start_timeouts,
+            wait_while_saturated: self.wait_while_saturated,
+            fail_fast: self.fail_fast,
+            retry_jitter: self.retry_jitter,
+            max_retries: self.max_retries,
+            panic_backtraces: self.panic_backtraces,
+            registry: self.registry,
+            require_non_empty_registry: self.require_non_empty_registry,
+            log_summary: self.log_summary,
+            commit_strategy: self.commit_strategy,
+            fetch_mode: self.fetch_mode,
+            abandoned_job_ttl: self.abandoned_job_ttl,
+            scheduler_lock_key: self.scheduler_lock_key,
+            weight_budget: self.weight_budget,
+            bad_payload_policy: self.bad_payload_policy,
+            executor: self.executor,
+            hooks: self.hooks,
         }
     }
+
+    /// Provide a connection pool for each database the runner should run jobs
+    /// from.
+    ///
+    /// This is for applications whose data is sharded across several
+    /// databases, each with its own `background_jobs` table. Jobs are fetched
+    /// from each database in turn. A job is locked, run, and updated using
+    /// connections from the pool of the database it was fetched from, and
+    /// [`JobContext::pool`] returns that pool as well.
+    ///
+    /// [`Runner::enqueue`], [`Runner::dry_run`] and [`Runner::run_job_inline`]
+    /// only use the first pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pools` is empty.
+    pub fn connection_pools<NewPool>(self, pools: Vec<NewPool>) -> Builder<Env, NewPool> {
+        let mut pools = pools.into_iter();
+        let first = pools
+            .next()
+            .expect("At least one connection pool must be given");
+        let mut builder = self.connection_pool(first);
+        builder.shards = pools.collect();
+        builder
+    }
 }
 
 #[cfg(feature = "r2d2")]
@@ -88,6 +629,11 @@ impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
 #[cfg(feature = "r2d2")]
 impl<Env> Builder<Env, R2d2Builder> {
     /// Set the max size of the database connection pool
+    ///
+    /// Each running job holds a connection. If jobs get additional
+    /// connections from the pool, this must be larger than the thread count,
+    /// or every worker can end up waiting on a connection that will never be
+    /// freed.
     pub fn connection_count(mut self, connection_count: u32) -> Self {
         self.connection_pool_or_builder
             .connection_count(connection_count);
@@ -95,18 +641,76 @@ impl<Env> Builder<Env, R2d2Builder> {
     }
 
     /// Build the runner with an r2d2 connection pool.
-    pub fn build(self) -> Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>> {
-        let thread_count = self.get_thread_count();
+    pub fn build(mut self) -> Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>> {
+        let thread_pool = self.build_executor();
+        let thread_count = thread_pool.max_count();
         let connection_pool_size = thread_count as u32 * 2;
+        let thread_name_prefix = self.get_thread_name_prefix();
+        let registry = self.load_registry();
         let connection_pool = self.connection_pool_or_builder.build(connection_pool_size);
+        let mut shards = vec![connection_pool.clone()];
+        shards.extend(
+            self.shards
+                .into_iter()
+                .map(|builder| builder.build(connection_pool_size)),
+        );
+        for pool in &shards {
+            warn_if_pool_is_too_small(pool, thread_count);
+        }
+        let job_pools = self
+            .job_pools
+            .into_iter()
+            .map(|(job_type, builder)| (job_type, builder.build(connection_pool_size)))
+            .collect();
 
         Runner {
             connection_pool,
-            thread_pool: ThreadPool::new(thread_count),
+            shards,
+            job_pools,
+            next_shard: AtomicUsize::new(0),
+            thread_pool,
+            thread_name_prefix,
+            lazy_threads: self.lazy_threads,
             environment: Arc::new(self.environment),
-            registry: Arc::new(Registry::load()),
+            registry: Arc::new(RwLock::new(registry)),
             job_start_timeout: self.job_start_timeout.unwrap_or(Duration::from_secs(10)),
+            job_type_start_timeouts: Arc::new(self.job_type_start_timeouts),
+            wait_while_saturated: self.wait_while_saturated,
+            fail_fast: self.fail_fast,
+            running_jobs: RunningJobs::default(),
+            fetch_counters: FetchCounters::default(),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            exclusive_gate: Arc::default(),
+            weight_budget: Arc::new(WeightBudget::new(self.weight_budget)),
+            bad_payload_policy: self.bad_payload_policy,
+            retry_jitter: self.retry_jitter.unwrap_or(0.0),
+            max_retries: self.max_retries,
+            panic_backtraces: self.panic_backtraces,
+            log_summary: self.log_summary,
+            commit_strategy: self.commit_strategy,
+            fetch_mode: self.fetch_mode,
+            abandoned_job_ttl: self.abandoned_job_ttl,
+            scheduler_lock_key: self.scheduler_lock_key,
+            hooks: Arc::new(self.hooks),
+        }
+    }
+
+    /// Build the runner with an r2d2 connection pool, and check that a
+    /// connection to the database can be established.
+    ///
+    /// The pool does not connect to the database until a connection is
+    /// needed, so a misconfigured database URL normally isn't noticed until
+    /// the runner first tries to fetch a job. Use this instead of
+    /// [`build`](Self::build) to fail on startup instead.
+    pub fn try_build(
+        self,
+    ) -> Result<Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>>, r2d2::PoolError>
+    {
+        let runner = self.build();
+        for pool in &runner.shards {
+            pool.ping()?;
         }
+        Ok(runner)
     }
 }
 
@@ -114,15 +718,80 @@ impl<Env, ConnectionPool> Builder<Env, ConnectionPool>
 where
     ConnectionPool: DieselPool,
 {
+    /// Give jobs of the given type their connection from `pool`, instead of
+    /// the pool they were fetched with.
+    ///
+    /// This keeps jobs which make heavy use of the database, such as large
+    /// imports, from using up the connections other jobs need, or lets them
+    /// connect as a role with different limits. Jobs are still fetched,
+    /// locked and updated using the runner's pool, and
+    /// [`JobContext::pool`] is still the runner's pool. The pool must
+    /// connect to the same database as the runner's, or the database of
+    /// every shard for runners given more than one pool.
+    ///
+    /// A job can't share the transaction its row is locked in if its
+    /// connection comes from another pool, so jobs with a pool of their own
+    /// are always run as with
+    /// [`CommitStrategy::SeparateTransactions`]: their work is committed
+    /// before their row is updated.
+    pub fn job_pool_for<S: Into<String>>(mut self, job_type: S, pool: ConnectionPool) -> Self {
+        self.job_pools.insert(job_type.into(), pool);
+        self
+    }
+
     /// Build the runner
-    pub fn build(self) -> Runner<Env, ConnectionPool> {
+    pub fn build(mut self) -> Runner<Env, ConnectionPool> {
+        let registry = self.load_registry();
+        let thread_name_prefix = self.get_thread_name_prefix();
+        let thread_pool = self.build_executor();
+        let thread_count = thread_pool.max_count();
+        let mut shards = vec![self.connection_pool_or_builder.clone()];
+        shards.extend(self.shards);
+        for pool in &shards {
+            warn_if_pool_is_too_small(pool, thread_count);
+        }
         Runner {
-            thread_pool: ThreadPool::new(self.get_thread_count()),
+            thread_pool,
+            thread_name_prefix,
+            lazy_threads: self.lazy_threads,
             connection_pool: self.connection_pool_or_builder,
+            shards,
+            job_pools: self.job_pools,
+            next_shard: AtomicUsize::new(0),
             environment: Arc::new(self.environment),
-            registry: Arc::new(Registry::load()),
+            registry: Arc::new(RwLock::new(registry)),
             job_start_timeout: self.job_start_timeout.unwrap_or(Duration::from_secs(10)),
+            job_type_start_timeouts: Arc::new(self.job_type_start_timeouts),
+            wait_while_saturated: self.wait_while_saturated,
+            fail_fast: self.fail_fast,
+            running_jobs: RunningJobs::default(),
+            fetch_counters: FetchCounters::default(),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            exclusive_gate: Arc::default(),
+            weight_budget: Arc::new(WeightBudget::new(self.weight_budget)),
+            bad_payload_policy: self.bad_payload_policy,
+            retry_jitter: self.retry_jitter.unwrap_or(0.0),
+            max_retries: self.max_retries,
+            panic_backtraces: self.panic_backtraces,
+            log_summary: self.log_summary,
+            commit_strategy: self.commit_strategy,
+            fetch_mode: self.fetch_mode,
+            abandoned_job_ttl: self.abandoned_job_ttl,
+            scheduler_lock_key: self.scheduler_lock_key,
+            hooks: Arc::new(self.hooks),
+        }
+    }
+
+    /// Build the runner, and check that a connection to the database can be
+    /// established.
+    ///
+    /// See [`DieselPool::ping`].
+    pub fn try_build(self) -> Result<Runner<Env, ConnectionPool>, ConnectionPool::Error> {
+        let runner = self.build();
+        for pool in &runner.shards {
+            pool.ping()?;
         }
+        Ok(runner)
     }
 }
 
@@ -130,10 +799,36 @@ where
 /// The core runner responsible for locking and running jobs
 pub struct Runner<Env: 'static, ConnectionPool> {
     connection_pool: ConnectionPool,
-    thread_pool: ThreadPool,
+    /// The pool of every database jobs are run from, starting with
+    /// `connection_pool`
+    shards: Vec<ConnectionPool>,
+    /// Pools which jobs of the given types get their connection from
+    job_pools: HashMap<String, ConnectionPool>,
+    next_shard: AtomicUsize,
+    thread_pool: Box<dyn JobExecutor>,
+    thread_name_prefix: String,
+    lazy_threads: bool,
     environment: Arc<Env>,
-    registry: Arc<Registry<Env>>,
+    registry: Arc<RwLock<Registry<Env>>>,
     job_start_timeout: Duration,
+    job_type_start_timeouts: Arc<HashMap<String, Duration>>,
+    wait_while_saturated: bool,
+    fail_fast: bool,
+    running_jobs: RunningJobs,
+    fetch_counters: FetchCounters,
+    shutting_down: Arc<AtomicBool>,
+    exclusive_gate: Arc<ExclusiveGate>,
+    weight_budget: Arc<WeightBudget>,
+    bad_payload_policy: BadPayloadPolicy,
+    retry_jitter: f64,
+    max_retries: Option<u32>,
+    panic_backtraces: bool,
+    log_summary: bool,
+    commit_strategy: CommitStrategy,
+    fetch_mode: FetchMode,
+    abandoned_job_ttl: Option<Duration>,
+    scheduler_lock_key: i64,
+    hooks: Arc<Hooks>,
 }
 
 impl<Env> Runner<Env, NoConnectionPoolGiven> {
@@ -146,9 +841,30 @@ impl<Env> Runner<Env, NoConnectionPoolGiven> {
     pub fn builder(environment: Env) -> Builder<Env, NoConnectionPoolGiven> {
         Builder {
             connection_pool_or_builder: NoConnectionPoolGiven,
+            shards: Vec::new(),
+            job_pools: HashMap::new(),
             environment,
             thread_count: None,
+            thread_name_prefix: None,
+            lazy_threads: false,
             job_start_timeout: None,
+            job_type_start_timeouts: HashMap::new(),
+            wait_while_saturated: false,
+            fail_fast: false,
+            retry_jitter: None,
+            max_retries: None,
+            panic_backtraces: false,
+            registry: None,
+            require_non_empty_registry: false,
+            log_summary: false,
+            commit_strategy: CommitStrategy::default(),
+            fetch_mode: FetchMode::default(),
+            abandoned_job_ttl: None,
+            scheduler_lock_key: DEFAULT_SCHEDULER_LOCK_KEY,
+            weight_budget: None,
+            bad_payload_policy: BadPayloadPolicy::default(),
+            executor: None,
+            hooks: Hooks::default(),
         }
     }
 }
@@ -159,6 +875,77 @@ impl<Env, ConnectionPool> Runner<Env, ConnectionPool> {
     pub fn connection_pool(&self) -> &ConnectionPool {
         &self.connection_pool
     }
+
+    /// How many times this runner's workers have queried for the next job,
+    /// and how long that took, since the runner was built
+    ///
+    /// Each job is fetched with its own query, and every call to
+    /// [`run_all_pending_jobs`](Self::run_all_pending_jobs) makes at least one
+    /// more which finds the queue empty. Take the stats before and after a
+    /// run, and use [`FetchStats::since`] to measure what that run cost.
+    pub fn fetch_stats(&self) -> FetchStats {
+        self.fetch_counters.snapshot()
+    }
+
+    /// The jobs this runner's workers have been running for longer than
+    /// `threshold`
+    ///
+    /// The jobs are left running. This is meant to be polled by a monitor, to
+    /// warn about jobs which may never finish well before they hold up a
+    /// deploy or use up the pool. Only this runner's own jobs are reported,
+    /// not those run by other processes working the same queue. The time
+    /// starts once the job has been fetched, so it includes time spent waiting
+    /// for an exclusive job or the weight budget.
+    pub fn stuck_jobs(&self, threshold: Duration) -> Vec<StuckJob> {
+        self.running_jobs.running_longer_than(threshold)
+    }
+
+    /// Waits for all running jobs to complete, without checking whether any
+    /// of them failed.
+    ///
+    /// If `timeout` is given, this gives up once it has elapsed, and returns
+    /// an error if jobs are still running. An error is also returned if any
+    /// worker threads panicked. Unlike
+    /// [`check_for_failed_jobs`](Self::check_for_failed_jobs), the database is
+    /// not queried.
+    pub fn wait_for_jobs(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.join_thread_pool(timeout) {
+            return Err("Timed out waiting for running jobs to finish".into());
+        }
+        let panic_count = self.thread_pool.panic_count();
+        if panic_count == 0 {
+            Ok(())
+        } else {
+            Err(format!("{} threads panicked", panic_count).into())
+        }
+    }
+
+    /// Waits for the thread pool to finish every job it was given, for up to
+    /// `timeout`. Returns whether it finished.
+    fn join_thread_pool(&self, timeout: Option<Duration>) -> bool {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                self.thread_pool.join();
+                return true;
+            }
+        };
+
+        // `ThreadPool` can't join with a timeout, so we poll it instead
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.thread_pool.active_count() + self.thread_pool.queued_count() == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
 
 impl<Env, ConnectionPool> Runner<Env, ConnectionPool>
@@ -172,16 +959,168 @@ where
     /// but does not wait for them to complete. When this function returns, at
     /// least one thread will have tried to acquire a new job, and found there
     /// were none in the queue.
-    pub fn run_all_pending_jobs(&self) -> Result<(), FetchError<ConnectionPool>> {
-        use std::cmp::max;
+    ///
+    /// The returned [`RunReport`] counts the jobs which were started, and how
+    /// many of those which finished before this function returned succeeded,
+    /// failed, or were abandoned.
+    pub fn run_all_pending_jobs(&self) -> Result<RunReport, FetchError<ConnectionPool>> {
+        self.run_jobs_where(JobFilter::new())
+    }
+
+    /// Runs every pending job, waits for them to finish, and reports how they
+    /// went.
+    ///
+    /// This is meant to be the entry point of a command line job runner,
+    /// which runs the queue once and exits. Unlike
+    /// [`run_all_pending_jobs`](Self::run_all_pending_jobs), the returned
+    /// [`RunReport`] accounts for every job which was started. If any of
+    /// those jobs failed, [`RunError::JobsFailed`] is returned with the same
+    /// report, so the process can print it and exit with a non-zero status:
+    ///
+    /// ```ignore
+    /// match runner.run_to_completion() {
+    ///     Ok(report) => println!("{}", report),
+    ///     Err(e) => {
+    ///         eprintln!("{}", e);
+    ///         std::process::exit(1);
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// Only jobs run by this call are counted. Unlike
+    /// [`check_for_failed_jobs`](Self::check_for_failed_jobs), jobs which
+    /// failed before it was called and are waiting to be retried don't make
+    /// it return an error.
+    pub fn run_to_completion(&self) -> Result<RunReport, RunError<ConnectionPool>> {
+        let report = self.run_jobs_on(&*self.thread_pool, JobFilter::new(), None, true)?;
+        self.wait_for_jobs(None).map_err(RunError::Other)?;
+        if report.failed() > 0 {
+            Err(RunError::JobsFailed(report))
+        } else {
+            Ok(report)
+        }
+    }
+
+    /// Runs all pending jobs in the queue which match the given filter
+    ///
+    /// This behaves the same as [`run_all_pending_jobs`](Self::run_all_pending_jobs),
+    /// except that jobs which don't match the filter are left in the queue.
+    /// This can be used to dedicate a process to a backed up job type, or to
+    /// retry a specific job.
+    pub fn run_jobs_where(
+        &self,
+        filter: JobFilter,
+    ) -> Result<RunReport, FetchError<ConnectionPool>> {
+        self.run_jobs_on(&*self.thread_pool, filter, None, false)
+    }
+
+    /// Runs jobs until [`shutdown_with_timeout`](Self::shutdown_with_timeout)
+    /// is called from another thread.
+    ///
+    /// This calls [`run_all_pending_jobs`](Self::run_all_pending_jobs) in a
+    /// loop. Whenever the queue is empty, it calls the hook registered with
+    /// [`Builder::on_idle`], and then waits `poll_interval` before looking for
+    /// more jobs. Returns the first error returned by `run_all_pending_jobs`,
+    /// or once a run stops after a failure, if the runner was built with
+    /// [`Builder::fail_fast`].
+    pub fn run_forever(&self, poll_interval: Duration) -> Result<(), FetchError<ConnectionPool>> {
+        while !self.shutting_down.load(Ordering::SeqCst) {
+            let report = self.run_all_pending_jobs()?;
+            if report.stopped_after_failure() {
+                break;
+            }
+            if report.started() == 0 {
+                if let Err(e) = self.prune_abandoned_jobs() {
+                    eprintln!("Failed to delete old abandoned jobs: {}", e);
+                }
+                self.hooks.idle();
+                thread::sleep(poll_interval);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs all pending jobs in the queue, using `concurrency` threads instead
+    /// of the runner's thread count.
+    ///
+    /// This is meant for one-off runs which need more throughput than usual,
+    /// such as backfills. A separate thread pool of the given size is created
+    /// for this call. Unlike [`run_all_pending_jobs`](Self::run_all_pending_jobs),
+    /// this function waits for every job it started to finish before
+    /// returning, since the threads are not kept around afterwards. The
+    /// returned [`RunReport`] accounts for every job which was run.
+    ///
+    /// Each thread holds a database connection while it runs a job. If the
+    /// connection pool is smaller than `concurrency`, the number of threads is
+    /// reduced to the size of the pool.
+    pub fn run_all_pending_jobs_with_concurrency(
+        &self,
+        concurrency: usize,
+    ) -> Result<RunReport, FetchError<ConnectionPool>> {
+        let mut concurrency = concurrency;
+        if let Some(max_size) = self.connection_pool.max_size() {
+            let max_size = max_size as usize;
+            if concurrency > max_size {
+                eprintln!(
+                    "Warning: the connection pool only has {} connections, \
+                     running {} jobs at a time instead of {}",
+                    max_size, max_size, concurrency,
+                );
+                concurrency = max_size;
+            }
+        }
+
+        let thread_pool =
+            build_thread_pool(&self.thread_name_prefix, concurrency, self.lazy_threads);
+        let result = self.run_jobs_on(&*thread_pool, JobFilter::new(), None, true);
+        thread_pool.join();
+        result
+    }
+
+    /// Runs at most `max_jobs` pending jobs, and waits for them to finish.
+    ///
+    /// This is meant for batch workers which are started on a schedule, and
+    /// should only run for a bounded amount of time. Jobs are picked up the
+    /// same way as [`run_all_pending_jobs`](Self::run_all_pending_jobs), but
+    /// no more jobs are started once `max_jobs` have been, even if more are
+    /// pending. Returns the number of jobs which were run, which is less
+    /// than `max_jobs` if the queue ran out of jobs first.
+    pub fn run_n_pending_jobs(&self, max_jobs: usize) -> Result<usize, FetchError<ConnectionPool>> {
+        let report =
+            self.run_jobs_on(&*self.thread_pool, JobFilter::new(), Some(max_jobs), true)?;
+        Ok(report.started())
+    }
 
-        let max_threads = self.thread_pool.max_count();
+    /// If `limit` is given, no more than that many jobs are started.
+    ///
+    /// If `wait_for_jobs` is true, the returned report includes every job
+    /// which was started, not just the ones which finished before the queue
+    /// was found to be empty.
+    fn run_jobs_on(
+        &self,
+        thread_pool: &dyn JobExecutor,
+        filter: JobFilter,
+        limit: Option<usize>,
+        wait_for_jobs: bool,
+    ) -> Result<RunReport, FetchError<ConnectionPool>> {
+        use std::cmp::{max, min};
+
+        let started_at = Instant::now();
+        let filter = Arc::new(filter);
+        let max_threads = thread_pool.max_count();
         let (sender, receiver) = channel::new(max_threads);
         let mut pending_messages = 0;
+        let mut report = RunReport::default();
+        let mut empty_shards = vec![false; self.shards.len()];
+        // Set by the worker whose job failed, before it fetches anything else
+        let failed = self.fail_fast.then(Arc::default);
         loop {
-            let available_threads = max_threads - self.thread_pool.active_count();
+            if limit.is_some_and(|limit| report.started() >= limit) {
+                break;
+            }
+            let available_threads = max_threads - thread_pool.active_count();
 
-            let jobs_to_queue = if pending_messages == 0 {
+            let mut jobs_to_queue = if pending_messages == 0 {
                 // If we have no queued jobs talking to us, and there are no
                 // available threads, we still need to queue at least one job
                 // or we'll never receive a message
@@ -189,47 +1128,456 @@ where
             } else {
                 available_threads
             };
+            if let Some(limit) = limit {
+                // Every queued job which hasn't reported yet may still pick
+                // one up
+                jobs_to_queue = min(jobs_to_queue, limit - report.started() - pending_messages);
+            }
+            if self.lazy_threads {
+                // Each fetch which is queued may start a thread, so only
+                // fetch the next job once the last one was found
+                jobs_to_queue = if pending_messages == 0 {
+                    min(jobs_to_queue, 1)
+                } else {
+                    0
+                };
+            }
 
             for _ in 0..jobs_to_queue {
-                self.run_single_job(sender.clone());
+                let shard = self.next_shard(&empty_shards);
+                self.run_single_job(
+                    thread_pool,
+                    sender.clone(),
+                    shard,
+                    Arc::clone(&filter),
+                    failed.clone(),
+                );
             }
 
             pending_messages += jobs_to_queue;
-            match receiver.recv_timeout(self.job_start_timeout) {
-                Ok(Event::Working) => pending_messages -= 1,
-                Ok(Event::NoJobAvailable) => return Ok(()),
+            // The timeout starts over whenever any worker reports anything,
+            // including finishing a job. It's only hit if no progress at all
+            // is being made.
+            match receiver.recv_timeout(self.job_start_timeout()) {
+                Ok(Event::Working) => {
+                    pending_messages -= 1;
+                    report.job_started();
+                }
+                Ok(Event::Finished(outcome)) => {
+                    report.job_finished(outcome);
+                    if failed.as_ref().is_some_and(|f| f.load(Ordering::SeqCst)) {
+                        break;
+                    }
+                }
+                Ok(Event::NoJobAvailable(shard)) => {
+                    pending_messages -= 1;
+                    empty_shards[shard] = true;
+                    if empty_shards.iter().all(|&empty| empty) {
+                        break;
+                    }
+                }
                 Ok(Event::ErrorLoadingJob(e)) => return Err(FetchError::FailedLoadingJob(e)),
                 Ok(Event::FailedToAcquireConnection(e)) => {
-                    return Err(FetchError::NoDatabaseConnection(e));
+                    pending_messages -= 1;
+                    // Jobs can hold more than one connection, such as ones
+                    // which don't run in a transaction, so the pool may be
+                    // exhausted by our own jobs even if it's as large as the
+                    // thread pool. They give their connections back once
+                    // they finish, so keep trying until they have.
+                    if self.running_jobs.ids().is_empty() {
+                        return Err(FetchError::NoDatabaseConnection(e));
+                    }
+                }
+                // None of the queued fetches have started, so every worker
+                // is busy running a job
+                Err(_)
+                    if self.wait_while_saturated
+                        && pending_messages > 0
+                        && thread_pool.queued_count() >= pending_messages => {}
+                Err(_) => {
+                    self.hooks.job_start_timed_out(pending_messages);
+                    return Err(FetchError::NoMessageReceived);
+                }
+            }
+        }
+
+        let failed = failed.is_some_and(|f| f.load(Ordering::SeqCst));
+        if wait_for_jobs || failed {
+            // Every worker holds a sender until it's done, so this ends once
+            // all of the jobs we started have finished
+            drop(sender);
+            for event in receiver {
+                match event {
+                    Event::Working => report.job_started(),
+                    Event::Finished(outcome) => report.job_finished(outcome),
+                    _ => {}
                 }
-                Err(_) => return Err(FetchError::NoMessageReceived),
             }
         }
+
+        if failed {
+            report.stop_after_failure();
+        }
+        report.finish(started_at.elapsed());
+        if self.log_summary {
+            eprintln!("{}", report);
+        }
+        Ok(report)
+    }
+
+    /// Lists the jobs that would be run by [`run_all_pending_jobs`](Self::run_all_pending_jobs),
+    /// without running them.
+    ///
+    /// Returns the id and type of each job, in the order they would be
+    /// picked up. This function does not lock any rows, so it will not
+    /// interfere with other runners working the queue. As a consequence,
+    /// jobs which are currently being run elsewhere are included as well,
+    /// unless they were claimed by a runner using [`FetchMode::Claim`].
+    pub fn dry_run(&self) -> Result<Vec<(i64, String)>, FetchError<ConnectionPool>> {
+        self.dry_run_on(&self.connection_pool)
+    }
+
+    /// The same as [`dry_run`](Self::dry_run), but reading from `read_pool`
+    /// instead of the runner's own pool.
+    ///
+    /// This is meant for deployments which send reads to a replica, so that
+    /// inspecting the queue doesn't add load to the primary. A replica may lag
+    /// behind, so the jobs returned may already have been run, and recently
+    /// enqueued jobs may be missing. Everything which fetches, locks or
+    /// updates jobs still uses the runner's pool, which must be connected to
+    /// the primary.
+    pub fn dry_run_on<ReadPool: DieselPool>(
+        &self,
+        read_pool: &ReadPool,
+    ) -> Result<Vec<(i64, String)>, FetchError<ReadPool>> {
+        let mut conn = read_pool.get().map_err(FetchError::NoDatabaseConnection)?;
+        storage::pending_jobs(&mut conn).map_err(FetchError::FailedLoadingJob)
+    }
+
+    /// Returns the id and type of the job that would be run next, without
+    /// running it.
+    ///
+    /// This is the first job [`dry_run`](Self::dry_run) would return. Like
+    /// `dry_run`, no rows are locked, so the job may already be running
+    /// elsewhere. Returns `None` if no jobs are ready to be run.
+    pub fn peek_next(&self) -> Result<Option<(i64, String)>, FetchError<ConnectionPool>> {
+        self.peek_next_on(&self.connection_pool)
+    }
+
+    /// The same as [`peek_next`](Self::peek_next), but reading from
+    /// `read_pool` instead of the runner's own pool.
+    ///
+    /// See [`dry_run_on`](Self::dry_run_on).
+    pub fn peek_next_on<ReadPool: DieselPool>(
+        &self,
+        read_pool: &ReadPool,
+    ) -> Result<Option<(i64, String)>, FetchError<ReadPool>> {
+        let mut conn = read_pool.get().map_err(FetchError::NoDatabaseConnection)?;
+        storage::next_pending_job(&mut conn).map_err(FetchError::FailedLoadingJob)
+    }
+
+    /// Deletes abandoned jobs which were last attempted longer ago than the
+    /// TTL set with [`Builder::abandoned_job_ttl`]. Returns the number of jobs
+    /// which were deleted, which is always `0` if no TTL was set.
+    ///
+    /// Jobs are deleted in batches, each in its own transaction, so pruning a
+    /// large backlog doesn't keep many rows locked for long.
+    pub fn prune_abandoned_jobs(&self) -> Result<usize, FetchError<ConnectionPool>> {
+        const BATCH_SIZE: i64 = 1000;
+
+        let ttl = match self.abandoned_job_ttl {
+            Some(ttl) => ttl,
+            None => return Ok(0),
+        };
+        let mut conn = self
+            .connection_pool
+            .get()
+            .map_err(FetchError::NoDatabaseConnection)?;
+        let mut deleted = 0;
+        loop {
+            let batch = storage::delete_abandoned_jobs(&mut conn, ttl, BATCH_SIZE)
+                .map_err(FetchError::FailedLoadingJob)?;
+            deleted += batch;
+            if batch < BATCH_SIZE as usize {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    /// Makes failed jobs which match `filter` ready to run again right away.
+    /// Returns the number of jobs which will be retried.
+    ///
+    /// This is meant for recovering once whatever made the jobs fail has been
+    /// fixed, such as retrying every mailer job after the SMTP settings were
+    /// corrected. The jobs' retry counts are reset, as if they had never
+    /// failed, and jobs which were abandoned after reaching
+    /// [`Builder::max_retries`] are run again as well. Jobs which are
+    /// currently running are left alone.
+    pub fn retry_failed_jobs(
+        &self,
+        filter: JobFilter,
+    ) -> Result<usize, FetchError<ConnectionPool>> {
+        let mut conn = self
+            .connection_pool
+            .get()
+            .map_err(FetchError::NoDatabaseConnection)?;
+        storage::retry_failed_jobs(&mut conn, &filter).map_err(FetchError::FailedLoadingJob)
+    }
+
+    /// Creates the `background_jobs` table if it doesn't exist, and updates it
+    /// to the schema expected by this version of swirl.
+    ///
+    /// This is meant to be called once when your application starts, instead
+    /// of copying swirl's migrations into your own. It only runs migrations
+    /// which haven't been run yet, so it's safe to call on every startup.
+    /// Requires the `migrations` feature.
+    #[cfg(feature = "migrations")]
+    pub fn ensure_schema(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for pool in &self.shards {
+            storage::run_pending_migrations(&mut *pool.get()?)?;
+        }
+        Ok(())
+    }
+
+    /// Register a job type with this runner after it has been built.
+    ///
+    /// Jobs are normally registered with [`register_job!`](crate::register_job),
+    /// and loaded when the runner is built. This is for jobs which are only
+    /// known at runtime, such as those provided by plugins. It's safe to call
+    /// while jobs are running: workers which have already looked up their
+    /// job are unaffected, and any job fetched afterwards can use the new job
+    /// type. Registering a job which is already registered does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a different job with the same [`Job::JOB_TYPE`] is already
+    /// registered, the same as [`Registry::register`].
+    pub fn register_job<J>(&self)
+    where
+        J: Job<Environment = Env>,
+    {
+        // Panicking while the lock is held would poison it for every worker
+        let result = self.registry.write().unwrap().try_register::<J>();
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    /// Enqueue a job using a connection from the runner's pool.
+    ///
+    /// This is the same as calling [`Job::enqueue`] with a connection you
+    /// got from the pool yourself.
+    pub fn enqueue<J>(&self, job: J) -> Result<(), EnqueueError>
+    where
+        J: Job<Environment = Env>,
+    {
+        let mut conn = self
+            .connection_pool
+            .get()
+            .map_err(|e| EnqueueError::NoDatabaseConnection(e.into()))?;
+        job.enqueue(&mut conn)
     }
 
-    fn run_single_job(&self, sender: EventSender<ConnectionPool>) {
+    /// Try to become the one process which enqueues recurring jobs.
+    ///
+    /// When several processes run jobs from the same queue, only one of them
+    /// should enqueue jobs which happen on a schedule, or each would be
+    /// enqueued once per process. This takes a session level advisory lock
+    /// on a connection from the runner's pool, without waiting for it.
+    /// Returns `None` if another process already holds it, in which case
+    /// this process should only run jobs, and try again later in case the
+    /// scheduler goes away.
+    ///
+    /// The lock is held until the returned [`SchedulerLock`] is dropped, or
+    /// its connection is lost, so it should be kept for as long as this
+    /// process enqueues recurring jobs. It holds one connection from the pool
+    /// in the meantime. For runners with several pools, the lock is taken in
+    /// the first database.
+    pub fn try_become_scheduler(
+        &self,
+    ) -> Result<Option<SchedulerLock<'_, ConnectionPool>>, Box<dyn Error + Send + Sync>>
+    where
+        ConnectionPool: DieselPool,
+    {
+        let mut conn = self.connection_pool.get()?;
+        if storage::try_advisory_lock(&mut conn, self.scheduler_lock_key)? {
+            Ok(Some(SchedulerLock::new(conn, self.scheduler_lock_key)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Runs a job immediately on the current thread, without enqueuing it.
+    ///
+    /// This is meant for testing the logic of a job. The job's `perform` is
+    /// called directly with the runner's environment, and its result is
+    /// returned. Nothing is written to the `background_jobs` table, the job's
+    /// guard is not checked, and the job is not retried if it fails.
+    ///
+    /// `Job::perform` always takes a connection, so this still needs a working
+    /// connection pool. Like jobs run from the queue, the job runs inside of a
+    /// transaction which is only committed if it succeeds, unless
+    /// [`Job::IN_TRANSACTION`] is `false`. Since the job has no row,
+    /// [`JobContext::id`] and [`JobContext::retries`] return `0`, and the job
+    /// counts as enqueued when this is called.
+    pub fn run_job_inline<J>(&self, job: J) -> Result<(), PerformError>
+    where
+        J: Job<Environment = Env>,
+    {
+        let mut conn = self.connection_pool.get()?;
+        let now = SystemTime::now();
+        let ctx = JobContext::new(0, None, 0, now, now, &self.connection_pool);
+        if J::IN_TRANSACTION {
+            conn.transaction(|conn| job.perform(&self.environment, conn, &ctx))
+        } else {
+            job.perform(&self.environment, &mut conn, &ctx)
+        }
+    }
+
+    /// Picks the shard to fetch the next job from, skipping any which were
+    /// found to have no jobs during this run
+    fn next_shard(&self, empty_shards: &[bool]) -> usize {
+        loop {
+            let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+            if !empty_shards[shard] {
+                return shard;
+            }
+        }
+    }
+
+    /// How long to wait for a job to start, given the jobs which are
+    /// currently running
+    fn job_start_timeout(&self) -> Duration {
+        self.running_jobs
+            .job_types()
+            .iter()
+            .filter_map(|job_type| self.job_type_start_timeouts.get(job_type))
+            .fold(self.job_start_timeout, |a, &b| a.max(b))
+    }
+
+    fn run_single_job(
+        &self,
+        thread_pool: &dyn JobExecutor,
+        sender: EventSender<ConnectionPool>,
+        shard: usize,
+        filter: Arc<JobFilter>,
+        failed: Option<Arc<AtomicBool>>,
+    ) {
         let environment = Arc::clone(&self.environment);
         let registry = Arc::clone(&self.registry);
         // FIXME: https://github.com/sfackler/r2d2/pull/70
-        let connection_pool = AssertUnwindSafe(self.connection_pool().clone());
-        self.get_single_job(sender, move |job| {
-            let perform_job = registry
-                .get(&job.job_type)
-                .ok_or_else(|| PerformError::from(format!("Unknown job type {}", job.job_type)))?;
-            perform_job.perform(job.data, &environment, &connection_pool.0)
-        })
+        let connection_pool = AssertUnwindSafe(self.shards[shard].clone());
+        let job_pools = AssertUnwindSafe(self.job_pools.clone());
+        let exclusive_gate = Arc::clone(&self.exclusive_gate);
+        let weight_budget = Arc::clone(&self.weight_budget);
+        let commit_strategy = self.commit_strategy;
+        // The hooks are never mutated, so a panic can't leave them broken
+        let hooks = AssertUnwindSafe(Arc::clone(&self.hooks));
+        self.get_single_job(
+            thread_pool,
+            sender,
+            shard,
+            filter,
+            failed,
+            move |job, conn| {
+                let perform_job = registry.read().unwrap().get(&job.job_type).ok_or_else(|| {
+                    PerformError::from(format!("Unknown job type {}", job.job_type))
+                })?;
+                let _exclusive_guard = exclusive_gate.enter(perform_job.exclusive());
+                let _weight_guard = weight_budget.acquire(perform_job.weight());
+                let ctx = JobContext::new(
+                    job.id,
+                    job.correlation_id.as_deref(),
+                    job.retries,
+                    job.created_at,
+                    job.retry_at,
+                    &connection_pool.0,
+                );
+                let mut data = job.data;
+                hooks.0.transform_payload(&job.job_type, &mut data)?;
+                let perform_in_transaction = |conn: &mut PgConnection, data| {
+                    let previous_timeout = perform_job
+                        .statement_timeout()
+                        .map(|timeout| storage::set_local_statement_timeout(conn, timeout))
+                        .transpose()?;
+                    let result = perform_job.perform(data, &environment, conn, &ctx);
+                    // The runner records the job's outcome in the same
+                    // transaction, which shouldn't be held to the job's
+                    // timeout. If the job failed, rolling back its savepoint
+                    // puts the timeout back instead.
+                    if let (Ok(_), Some(previous_timeout)) = (&result, previous_timeout) {
+                        storage::restore_local_statement_timeout(conn, &previous_timeout)?;
+                    }
+                    result
+                };
+                let own_pool = job_pools.0.get(&job.job_type);
+                let job_connection_pool = own_pool.unwrap_or(&connection_pool.0);
+                otel::with_context(job.trace_context, || match conn {
+                    _ if !perform_job.in_transaction() => {
+                        let mut conn = job_connection_pool.get()?;
+                        perform_job.perform(data, &environment, &mut conn, &ctx)
+                    }
+                    Some(conn)
+                        if commit_strategy == CommitStrategy::SingleTransaction
+                            && own_pool.is_none() =>
+                    {
+                        perform_in_transaction(conn, data)
+                    }
+                    // Claimed jobs aren't given a connection, and jobs with a pool
+                    // of their own can't use it, so they get their own transaction
+                    _ => {
+                        let mut conn = job_connection_pool.get()?;
+                        conn.transaction(|conn| perform_in_transaction(conn, data))
+                    }
+                })
+            },
+        )
     }
 
-    fn get_single_job<F>(&self, sender: EventSender<ConnectionPool>, f: F)
-    where
-        F: FnOnce(storage::BackgroundJob) -> Result<(), PerformError> + Send + UnwindSafe + 'static,
+    fn get_single_job<F>(
+        &self,
+        thread_pool: &dyn JobExecutor,
+        sender: EventSender<ConnectionPool>,
+        shard: usize,
+        filter: Arc<JobFilter>,
+        failed: Option<Arc<AtomicBool>>,
+        f: F,
+    ) where
+        F: FnOnce(
+                storage::BackgroundJob,
+                Option<&mut PgConnection>,
+            ) -> Result<PerformOutcome, PerformError>
+            + Send
+            + UnwindSafe
+            + 'static,
     {
         use diesel::result::Error::RollbackTransaction;
 
+        panic_hook::install();
+
         // The connection may not be `Send` so we need to clone the pool instead
-        let pool = self.connection_pool.clone();
-        self.thread_pool.execute(move || {
-            let conn = match pool.get() {
+        let pool = self.shards[shard].clone();
+        let retry_jitter = self.retry_jitter;
+        let max_retries = self.max_retries;
+        let panic_backtraces = self.panic_backtraces;
+        let bad_payload_policy = self.bad_payload_policy.clone();
+        let fetch_mode = self.fetch_mode;
+        let registry = Arc::clone(&self.registry);
+        let hooks = Arc::clone(&self.hooks);
+        let running_jobs = self.running_jobs.clone();
+        let fetch_counters = self.fetch_counters.clone();
+        let shutting_down = Arc::clone(&self.shutting_down);
+        let exclusive_gate = Arc::clone(&self.exclusive_gate);
+        thread_pool.execute(Box::new(move || {
+            // Don't fetch any jobs while an exclusive job is running
+            exclusive_gate.wait_until_open();
+            let run_failed = failed.as_ref().is_some_and(|f| f.load(Ordering::SeqCst));
+            if shutting_down.load(Ordering::SeqCst) || run_failed {
+                sender.send(Event::NoJobAvailable(shard));
+                return;
+            }
+
+            let mut conn = match pool.get() {
                 Ok(conn) => conn,
                 Err(e) => {
                     sender.send(Event::FailedToAcquireConnection(e));
@@ -237,47 +1585,211 @@ where
                 }
             };
 
-            let job_run_result = conn.transaction::<_, diesel::result::Error, _>(|| {
-                let job = match storage::find_next_unlocked_job(&conn).optional() {
-                    Ok(Some(j)) => {
-                        sender.send(Event::Working);
-                        j
-                    }
-                    Ok(None) => {
-                        sender.send(Event::NoJobAvailable);
-                        return Ok(());
+            let mut _running_job = None;
+            let mut fetched =
+                |fetch: &mut dyn FnMut() -> QueryResult<Option<storage::BackgroundJob>>| {
+                    let started_at = Instant::now();
+                    let job = fetch();
+                    if let Ok(job) = &job {
+                        fetch_counters.record(started_at.elapsed(), job.is_some());
                     }
-                    Err(e) => {
-                        sender.send(Event::ErrorLoadingJob(e));
-                        return Err(RollbackTransaction);
+                    match job {
+                        Ok(Some(j)) => {
+                            // This must happen before the runner is told we're
+                            // working, so it knows how long to wait for the next
+                            // job
+                            _running_job = Some(running_jobs.start(j.id, &j.job_type));
+                            sender.send(Event::Working);
+                            Ok(Some(j))
+                        }
+                        Ok(None) => {
+                            sender.send(Event::NoJobAvailable(shard));
+                            Ok(None)
+                        }
+                        Err(e) => {
+                            sender.send(Event::ErrorLoadingJob(e));
+                            Err(RollbackTransaction)
+                        }
                     }
                 };
-                let job_id = job.id;
+            let at_most_once = |job: &storage::BackgroundJob| {
+                !job.expired
+                    && registry
+                        .read()
+                        .unwrap()
+                        .get(&job.job_type)
+                        .is_some_and(|job| job.at_most_once())
+            };
+            let finish = |conn: &mut PgConnection, job_id, result: &_, attempts| match attempts {
+                Some(attempts) => record_at_most_once_outcome(
+                    conn,
+                    job_id,
+                    result,
+                    attempts,
+                    retry_jitter,
+                    &hooks,
+                ),
+                None => record_outcome(
+                    conn,
+                    job_id,
+                    result,
+                    retry_jitter,
+                    max_retries,
+                    &bad_payload_policy,
+                    &hooks,
+                ),
+            };
+            let job_run_result = match fetch_mode {
+                FetchMode::RowLock => {
+                    let mut f = Some(f);
+                    let mut run_locked =
+                        |conn: &mut PgConnection, job: storage::BackgroundJob, attempts| {
+                            let job_id = job.id;
+                            let job_type = job.job_type.clone();
+                            let started_at = Instant::now();
+                            let f = f.take().expect("a job is only run once");
 
-                let result = catch_unwind(|| f(job))
-                    .map_err(|e| try_to_extract_panic_info(&e))
-                    .and_then(|r| r);
+                            // The job is run in a savepoint, so anything it does
+                            // with the connection is rolled back if it fails.
+                            // Panics are caught inside of the savepoint so that
+                            // they are rolled back as well, which is also why it's
+                            // fine to assert the connection is unwind safe.
+                            let result = if job.expired {
+                                Ok(PerformOutcome::Expired)
+                            } else {
+                                conn.transaction::<_, PerformError, _>(|conn| {
+                                    run_catching_panics(panic_backtraces, || f(job, Some(conn)))
+                                })
+                            };
+                            let duration = started_at.elapsed();
+                            let outcome = finish(conn, job_id, &result, attempts)?;
+                            Ok((job_id, job_type, duration, result, outcome))
+                        };
 
-                match result {
-                    Ok(_) => storage::delete_successful_job(&conn, job_id)?,
-                    Err(e) => {
-                        eprintln!("Job {} failed to run: {}", job_id, e);
-                        storage::update_failed_job(&conn, job_id);
+                    let fetch_result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                        let job = match fetched(&mut || {
+                            storage::find_next_unlocked_job(conn, &filter).optional()
+                        })? {
+                            Some(job) => job,
+                            None => return Ok(None),
+                        };
+                        if at_most_once(&job) {
+                            let attempts = storage::mark_attempted(conn, job.id)?;
+                            return Ok(Some(RowLockFetch::AtMostOnce(job, attempts)));
+                        }
+                        run_locked(conn, job, None).map(|job| Some(RowLockFetch::Finished(job)))
+                    });
+                    match fetch_result {
+                        // The attempt has been committed, so the job can run
+                        // in a new transaction. Nobody else fetches abandoned
+                        // jobs, but the row is locked again so the job is
+                        // still seen as running.
+                        Ok(Some(RowLockFetch::AtMostOnce(job, attempts))) => {
+                            conn.transaction(|conn| {
+                                storage::lock_job(conn, job.id)?;
+                                run_locked(conn, job, Some(attempts)).map(Some)
+                            })
+                        }
+                        Ok(Some(RowLockFetch::Finished(job))) => Ok(Some(job)),
+                        Ok(None) => Ok(None),
+                        Err(e) => Err(e),
                     }
                 }
-                Ok(())
-            });
+                FetchMode::Claim { timeout } => (|| {
+                    let worker = format!(
+                        "{} {:?} (pid {})",
+                        thread::current().name().unwrap_or("swirl-worker"),
+                        thread::current().id(),
+                        std::process::id(),
+                    );
+                    let job = match fetched(&mut || {
+                        storage::claim_next_job(&mut conn, &filter, &worker, timeout)
+                    })? {
+                        Some(job) => job,
+                        None => return Ok(None),
+                    };
+                    let attempts = if at_most_once(&job) {
+                        Some(storage::mark_attempted(&mut conn, job.id)?)
+                    } else {
+                        None
+                    };
+                    // The claim has been committed, so the connection can be
+                    // used by someone else while the job runs
+                    drop(conn);
+                    let job_id = job.id;
+                    let job_type = job.job_type.clone();
+                    let started_at = Instant::now();
+
+                    let result = if job.expired {
+                        Ok(PerformOutcome::Expired)
+                    } else {
+                        run_catching_panics(panic_backtraces, || f(job, None))
+                    };
+                    let duration = started_at.elapsed();
+
+                    let mut conn = match pool.get() {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            // The job will be run again once its claim runs out
+                            eprintln!("Failed to record the result of job {}: {}", job_id, e);
+                            return Ok(None);
+                        }
+                    };
+                    conn.transaction(|conn| {
+                        if !storage::lock_claimed_job(conn, job_id, &worker)? {
+                            eprintln!(
+                                "The claim on job {} ran out before it finished, \
+                                 so its result was not recorded",
+                                job_id,
+                            );
+                            return Ok(None);
+                        }
+                        let outcome = finish(conn, job_id, &result, attempts)?;
+                        Ok(Some((job_id, job_type, duration, result, outcome)))
+                    })
+                })(),
+            };
 
             match job_run_result {
-                Ok(_) | Err(RollbackTransaction) => {}
+                // Hooks are run once the transaction has been committed, so a
+                // panicking hook can't leave the job locked or half updated
+                Ok(Some((id, job_type, duration, result, outcome))) => {
+                    if let (Some(failed), JobOutcome::Failed | JobOutcome::Abandoned(_)) =
+                        (&failed, outcome)
+                    {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    let run = JobRun {
+                        id,
+                        job_type: &job_type,
+                        duration,
+                    };
+                    match result {
+                        Ok(PerformOutcome::Completed) => hooks.succeeded(&run),
+                        Ok(PerformOutcome::Snoozed) => {}
+                        Ok(PerformOutcome::Expired) => hooks.expired(&run),
+                        Err(e) if e.is::<Cancelled>() => {}
+                        Err(e) => {
+                            hooks.failed(&run, &e);
+                            if let JobOutcome::Abandoned(retries) = outcome {
+                                hooks.abandoned(&run, retries, &e);
+                            }
+                        }
+                    }
+                    sender.send(Event::Finished(outcome));
+                }
+                Ok(None) | Err(RollbackTransaction) => {}
                 Err(e) => {
                     panic!("Failed to update job: {:?}", e);
                 }
             }
-        })
+        }))
     }
 
-    fn connection(&self) -> Result<DieselPooledConn<ConnectionPool>, Box<dyn Error + Send + Sync>> {
+    #[cfg(test)]
+    fn connection(
+        &self,
+    ) -> Result<DieselPooledConn<'_, ConnectionPool>, Box<dyn Error + Send + Sync>> {
         self.connection_pool.get().map_err(Into::into)
     }
 
@@ -288,11 +1800,15 @@ where
     /// will return `swirl::JobsFailed` with the number of jobs that failed.
     ///
     /// If any other unexpected errors occurred, such as panicked worker threads
-    /// or an error loading the job count from the database, an opaque error
-    /// will be returned.
+    /// or an error loading the job count from the database,
+    /// `FailedJobsError::Other` will be returned.
     pub fn check_for_failed_jobs(&self) -> Result<(), FailedJobsError> {
-        self.wait_for_jobs()?;
-        let failed_jobs = storage::failed_job_count(&*self.connection()?)?;
+        self.wait_for_jobs(None)?;
+        let mut failed_jobs = 0;
+        for pool in &self.shards {
+            let mut conn = pool.get().map_err(|e| FailedJobsError::Other(e.into()))?;
+            failed_jobs += storage::failed_job_count(&mut conn)?;
+        }
         if failed_jobs == 0 {
             Ok(())
         } else {
@@ -300,32 +1816,243 @@ where
         }
     }
 
-    fn wait_for_jobs(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        self.thread_pool.join();
-        let panic_count = self.thread_pool.panic_count();
-        if panic_count == 0 {
-            Ok(())
-        } else {
-            Err(format!("{} threads panicked", panic_count).into())
+    /// Stops running new jobs, and waits up to `timeout` for the jobs which are
+    /// currently running to finish.
+    ///
+    /// Returns the ids of the jobs which were still running when the timeout
+    /// elapsed, so they can be reported as interrupted. The list is empty if
+    /// every job finished in time. Jobs which are interrupted by the process
+    /// exiting are retried by the next runner, as their transaction is never
+    /// committed.
+    ///
+    /// Once this has been called, the runner will not start any more jobs.
+    /// Calls to [`run_all_pending_jobs`](Self::run_all_pending_jobs) will
+    /// return without running anything.
+    pub fn shutdown_with_timeout(&self, timeout: Duration) -> Vec<i64> {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.join_thread_pool(Some(timeout));
+        self.running_jobs.ids()
+    }
+}
+
+/// Each worker holds a connection while it runs a job. If the pool has no
+/// connections beyond those, a job which gets another connection from the
+/// pool can wait forever (or until the pool times out) when every worker is
+/// busy.
+fn warn_if_pool_is_too_small(pool: &impl DieselPool, thread_count: usize) {
+    if let Some(max_size) = pool.max_size() {
+        if max_size as usize <= thread_count {
+            eprintln!(
+                "Warning: The connection pool has {} connections for {} worker threads. \
+                 Jobs which get a connection from the pool while every worker is busy \
+                 will wait until the pool times out. The pool should have more \
+                 connections than there are threads.",
+                max_size, thread_count,
+            );
         }
     }
 }
 
+/// Locks one of the mutexes which the runner uses for its bookkeeping.
+///
+/// These mutexes are only held while counters and maps are updated, never
+/// while a job, hook or other user code is run. A thread can't panic while
+/// holding one, so a poisoned mutex still guards consistent state, and is
+/// recovered instead of taking down every other worker with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn build_thread_pool(
+    thread_name_prefix: &str,
+    thread_count: usize,
+    lazy: bool,
+) -> Box<dyn JobExecutor> {
+    Box::new(WorkerPool::new(thread_name_prefix, thread_count, lazy))
+}
+
+/// Runs a job, turning a panic into an error
+fn run_catching_panics<F>(capture_backtrace: bool, f: F) -> Result<PerformOutcome, PerformError>
+where
+    F: FnOnce() -> Result<PerformOutcome, PerformError>,
+{
+    panic_hook::while_running_job(capture_backtrace, || catch_unwind(AssertUnwindSafe(f)))
+        .map_err(|e| {
+            try_to_extract_panic_info(
+                &e,
+                panic_hook::take_panic_location(),
+                panic_hook::take_panic_backtrace(),
+            )
+        })
+        .and_then(|r| r)
+}
+
+/// The ID, type and run time of a job which was just run, how it went, and
+/// what was done with its row
+type FinishedJob = (
+    i64,
+    String,
+    Duration,
+    Result<PerformOutcome, PerformError>,
+    JobOutcome,
+);
+
+/// What happened to a job fetched with [`FetchMode::RowLock`]
+enum RowLockFetch {
+    /// The job was run in the transaction which locked its row
+    Finished(FinishedJob),
+    /// The job is run at most once, so it was marked as attempted, with the
+    /// given number of attempts, rather than run in that transaction
+    AtMostOnce(storage::BackgroundJob, i32),
+}
+
+/// Deletes or updates the row of a job which was just run, depending on how
+/// it went. The row must be locked by `conn`.
+fn record_outcome(
+    conn: &mut PgConnection,
+    job_id: i64,
+    result: &Result<PerformOutcome, PerformError>,
+    retry_jitter: f64,
+    max_retries: Option<u32>,
+    bad_payload_policy: &BadPayloadPolicy,
+    hooks: &Hooks,
+) -> QueryResult<JobOutcome> {
+    let outcome = match result {
+        Ok(PerformOutcome::Completed) => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Succeeded
+        }
+        Ok(PerformOutcome::Expired) => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Expired
+        }
+        Ok(PerformOutcome::Snoozed) => {
+            storage::snooze_job(conn, job_id, retry_jitter)?;
+            JobOutcome::Snoozed
+        }
+        Err(e) if e.is::<Cancelled>() => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Cancelled
+        }
+        Err(e) => {
+            let bad_payload = e.downcast_ref::<BadPayload>();
+            let handled = match (bad_payload_policy, bad_payload) {
+                (BadPayloadPolicy::Custom(handle), Some(BadPayload(error))) => {
+                    match conn.transaction(|conn| handle(conn, job_id, error)) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            eprintln!("Failed to handle the bad payload of job {}: {}", job_id, e);
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            let abandoned = if handled {
+                None
+            } else if bad_payload.is_some()
+                && matches!(bad_payload_policy, BadPayloadPolicy::DeadLetter)
+            {
+                storage::abandon_job(conn, job_id)
+            } else {
+                let retry_after = e.downcast_ref::<RetryAfter>().map(|e| e.0);
+                storage::update_failed_job(conn, job_id, retry_jitter, max_retries, retry_after)
+            };
+            // The custom handler may have removed the row
+            if !handled {
+                hooks.record_failure(conn, job_id, e);
+            }
+            if let Some(retries) = abandoned {
+                eprintln!(
+                    "Job {} failed to run and will not be retried: {}",
+                    job_id,
+                    ErrorChain(&**e)
+                );
+                JobOutcome::Abandoned(retries as u32)
+            } else {
+                eprintln!("Job {} failed to run: {}", job_id, ErrorChain(&**e));
+                JobOutcome::Failed
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+/// Like [`record_outcome`], for a job which was marked as attempted with
+/// [`storage::mark_attempted`] before it ran. Failed jobs are left abandoned
+/// rather than retried, since their work may have been partly done.
+fn record_at_most_once_outcome(
+    conn: &mut PgConnection,
+    job_id: i64,
+    result: &Result<PerformOutcome, PerformError>,
+    attempts: i32,
+    retry_jitter: f64,
+    hooks: &Hooks,
+) -> QueryResult<JobOutcome> {
+    let outcome = match result {
+        Ok(PerformOutcome::Completed) => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Succeeded
+        }
+        Ok(PerformOutcome::Expired) => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Expired
+        }
+        Ok(PerformOutcome::Snoozed) => {
+            // The job's guard kept it from running, so it's safe to try again
+            storage::unmark_attempted(conn, job_id)?;
+            storage::snooze_job(conn, job_id, retry_jitter)?;
+            JobOutcome::Snoozed
+        }
+        Err(e) if e.is::<Cancelled>() => {
+            storage::delete_job(conn, job_id)?;
+            JobOutcome::Cancelled
+        }
+        Err(e) => {
+            hooks.record_failure(conn, job_id, e);
+            eprintln!(
+                "Job {} failed to run and will not be retried: {}",
+                job_id,
+                ErrorChain(&**e)
+            );
+            JobOutcome::Abandoned(attempts as u32)
+        }
+    };
+    Ok(outcome)
+}
+
 /// Try to figure out what's in the box, and print it if we can.
 ///
 /// The actual error type we will get from `panic::catch_unwind` is really poorly documented.
 /// However, the `panic::set_hook` functions deal with a `PanicInfo` type, and its payload is
 /// documented as "commonly but not always `&'static str` or `String`". So we can try all of those,
 /// and give up if we didn't get one of those three types.
-fn try_to_extract_panic_info(info: &(dyn Any + Send + 'static)) -> PerformError {
-    if let Some(x) = info.downcast_ref::<PanicInfo>() {
-        format!("job panicked: {}", x).into()
+///
+/// Since our panic hook keeps the panic from being printed, we also include
+/// where the panic occurred if we know it, and its backtrace if one was
+/// captured.
+fn try_to_extract_panic_info(
+    info: &(dyn Any + Send + 'static),
+    location: Option<String>,
+    backtrace: Option<Backtrace>,
+) -> PerformError {
+    let message = if let Some(x) = info.downcast_ref::<PanicInfo>() {
+        format!("job panicked: {}", x)
     } else if let Some(x) = info.downcast_ref::<&'static str>() {
-        format!("job panicked: {}", x).into()
+        format!("job panicked: {}", x)
     } else if let Some(x) = info.downcast_ref::<String>() {
-        format!("job panicked: {}", x).into()
+        format!("job panicked: {}", x)
     } else {
         "job panicked".into()
+    };
+
+    let message = match location {
+        Some(location) => format!("{} ({})", message, location),
+        None => message,
+    };
+    match backtrace {
+        Some(backtrace) => format!("{}\n\nstack backtrace:\n{}", message, backtrace).into(),
+        None => message.into(),
     }
 }
 
@@ -351,21 +2078,35 @@ mod tests {
         let return_barrier = Arc::new(AssertUnwindSafe(Barrier::new(2)));
         let return_barrier2 = return_barrier.clone();
 
-        runner.get_single_job(channel::dummy_sender(), move |job| {
-            fetch_barrier.0.wait(); // Tell thread 2 it can lock its job
-            assert_eq!(first_job_id, job.id);
-            return_barrier.0.wait(); // Wait for thread 2 to lock its job
-            Ok(())
-        });
+        runner.get_single_job(
+            &*runner.thread_pool,
+            channel::dummy_sender(),
+            0,
+            Arc::default(),
+            None,
+            move |job, _| {
+                fetch_barrier.0.wait(); // Tell thread 2 it can lock its job
+                assert_eq!(first_job_id, job.id);
+                return_barrier.0.wait(); // Wait for thread 2 to lock its job
+                Ok(PerformOutcome::Completed)
+            },
+        );
 
         fetch_barrier2.0.wait(); // Wait until thread 1 locks its job
-        runner.get_single_job(channel::dummy_sender(), move |job| {
-            assert_eq!(second_job_id, job.id);
-            return_barrier2.0.wait(); // Tell thread 1 it can unlock its job
-            Ok(())
-        });
+        runner.get_single_job(
+            &*runner.thread_pool,
+            channel::dummy_sender(),
+            0,
+            Arc::default(),
+            None,
+            move |job, _| {
+                assert_eq!(second_job_id, job.id);
+                return_barrier2.0.wait(); // Tell thread 1 it can unlock its job
+                Ok(PerformOutcome::Completed)
+            },
+        );
 
-        runner.wait_for_jobs().unwrap();
+        runner.wait_for_jobs(None).unwrap();
     }
 
     #[test]
@@ -375,12 +2116,19 @@ mod tests {
         let runner = runner();
         create_dummy_job(&runner);
 
-        runner.get_single_job(channel::dummy_sender(), |_| Ok(()));
-        runner.wait_for_jobs().unwrap();
+        runner.get_single_job(
+            &*runner.thread_pool,
+            channel::dummy_sender(),
+            0,
+            Arc::default(),
+            None,
+            |_, _| Ok(PerformOutcome::Completed),
+        );
+        runner.wait_for_jobs(None).unwrap();
 
         let remaining_jobs = background_jobs
             .count()
-            .get_result(&*runner.connection().unwrap());
+            .get_result(&mut *runner.connection().unwrap());
         assert_eq!(Ok(0), remaining_jobs);
     }
 
@@ -393,13 +2141,20 @@ mod tests {
         let barrier = Arc::new(AssertUnwindSafe(Barrier::new(2)));
         let barrier2 = barrier.clone();
 
-        runner.get_single_job(channel::dummy_sender(), move |_| {
-            barrier.0.wait();
-            // error so the job goes back into the queue
-            Err("nope".into())
-        });
+        runner.get_single_job(
+            &*runner.thread_pool,
+            channel::dummy_sender(),
+            0,
+            Arc::default(),
+            None,
+            move |_, _| {
+                barrier.0.wait();
+                // error so the job goes back into the queue
+                Err("nope".into())
+            },
+        );
 
-        let conn = runner.connection().unwrap();
+        let mut conn = runner.connection().unwrap();
         // Wait for the first thread to acquire the lock
         barrier2.0.wait();
         // We are intentionally not using `get_single_job` here.
@@ -411,7 +2166,7 @@ mod tests {
             .select(id)
             .filter(retries.eq(0))
             .for_update()
-            .load::<i64>(&*conn)
+            .load::<i64>(&mut *conn)
             .unwrap();
         assert_eq!(0, available_jobs.len());
 
@@ -419,11 +2174,11 @@ mod tests {
         let total_jobs_including_failed = background_jobs
             .select(id)
             .for_update()
-            .load::<i64>(&*conn)
+            .load::<i64>(&mut *conn)
             .unwrap();
         assert_eq!(1, total_jobs_including_failed.len());
 
-        runner.wait_for_jobs().unwrap();
+        runner.wait_for_jobs(None).unwrap();
     }
 
     #[test]
@@ -432,14 +2187,21 @@ mod tests {
         let runner = runner();
         let job_id = create_dummy_job(&runner).id;
 
-        runner.get_single_job(channel::dummy_sender(), |_| panic!());
-        runner.wait_for_jobs().unwrap();
+        runner.get_single_job(
+            &*runner.thread_pool,
+            channel::dummy_sender(),
+            0,
+            Arc::default(),
+            None,
+            |_, _| panic!(),
+        );
+        runner.wait_for_jobs(None).unwrap();
 
         let tries = background_jobs
             .find(job_id)
             .select(retries)
             .for_update()
-            .first::<i32>(&*runner.connection().unwrap())
+            .first::<i32>(&mut *runner.connection().unwrap())
             .unwrap();
         assert_eq!(1, tries);
     }
@@ -465,7 +2227,7 @@ mod tests {
     impl<'a> Drop for TestGuard<'a> {
         fn drop(&mut self) {
             ::diesel::sql_query("TRUNCATE TABLE background_jobs")
-                .execute(&*runner().connection().unwrap())
+                .execute(&mut *runner().connection().unwrap())
                 .unwrap();
         }
     }
@@ -485,8 +2247,8 @@ mod tests {
     fn create_dummy_job(runner: &Runner<()>) -> storage::BackgroundJob {
         ::diesel::insert_into(background_jobs)
             .values((job_type.eq("Foo"), data.eq(serde_json::json!(null))))
-            .returning((id, job_type, data))
-            .get_result(&*runner.connection().unwrap())
+            .returning(storage::background_job_columns())
+            .get_result(&mut *runner.connection().unwrap())
             .unwrap()
     }
 }
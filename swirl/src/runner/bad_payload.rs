@@ -0,0 +1,62 @@
+use diesel::{PgConnection, QueryResult};
+use std::fmt;
+use std::sync::Arc;
+
+type HandleBadPayload =
+    dyn Fn(&mut PgConnection, i64, &serde_json::Error) -> QueryResult<()> + Send + Sync;
+
+/// What to do with a job whose arguments can't be deserialized
+///
+/// This happens when a job's arguments have changed since it was enqueued,
+/// so the stored row no longer matches the job's type. Retrying the job
+/// usually won't help. Set with
+/// [`Builder::on_bad_payload`](crate::Builder::on_bad_payload).
+#[derive(Clone, Default)]
+pub enum BadPayloadPolicy {
+    /// The job is treated like any other failed job, and retried with the
+    /// usual backoff.
+    ///
+    /// This is the default.
+    #[default]
+    Retry,
+
+    /// The job is abandoned right away, as if it had reached
+    /// [`Builder::max_retries`](crate::Builder::max_retries). Its row is
+    /// left in the table, but it is never run again.
+    DeadLetter,
+
+    /// The given function is called with the connection the job was locked
+    /// with, the job's id, and the deserialization error, in the same
+    /// transaction which would have updated the job's row.
+    ///
+    /// The function is responsible for the row, such as by moving it to a
+    /// table of its own. The runner doesn't update the row afterwards, so if
+    /// the function leaves it as it was, the job is picked up again right
+    /// away. If the function returns an error, anything it wrote is rolled
+    /// back, and the job is retried as usual.
+    Custom(Arc<HandleBadPayload>),
+}
+
+impl BadPayloadPolicy {
+    /// Handle bad payloads with the given function. See
+    /// [`BadPayloadPolicy::Custom`].
+    pub fn custom<F>(f: F) -> Self
+    where
+        F: Fn(&mut PgConnection, i64, &serde_json::Error) -> QueryResult<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        BadPayloadPolicy::Custom(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for BadPayloadPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BadPayloadPolicy::Retry => f.write_str("Retry"),
+            BadPayloadPolicy::DeadLetter => f.write_str("DeadLetter"),
+            BadPayloadPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
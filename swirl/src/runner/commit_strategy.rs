@@ -0,0 +1,29 @@
+/// When the work done by a job is committed, relative to the update of its
+/// row in the queue
+///
+/// Set with [`Builder::commit_strategy`](crate::Builder::commit_strategy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitStrategy {
+    /// The job runs in the same transaction that locked its row, and which
+    /// deletes or updates that row once the job finishes.
+    ///
+    /// The job's work and the update of its row are committed atomically. If
+    /// the runner crashes or fails to update the row after a job succeeds,
+    /// the job's work is rolled back with it, and the job is run again.
+    ///
+    /// This is the default.
+    #[default]
+    SingleTransaction,
+
+    /// The job runs in its own transaction, on a separate connection, which
+    /// is committed as soon as the job succeeds. Its row is updated
+    /// afterwards, on the connection which locked it.
+    ///
+    /// The job's row stays locked while it runs, so it is still only run by
+    /// one worker at a time. However, if the runner crashes or fails to
+    /// update the row after the job's transaction was committed, the job's
+    /// work is kept and the job will be run again. Only use this for jobs
+    /// where losing their committed work is worse than them running twice.
+    /// Each running job holds two connections from the pool.
+    SeparateTransactions,
+}
@@ -7,18 +7,33 @@ pub type EventSender<Pool> = channel::Sender<Event<Pool>>;
 
 pub enum Event<Pool: DieselPool> {
     Working,
-    NoJobAvailable,
+    Finished(JobOutcome),
+    /// The queue of the shard with the given index had no jobs to run
+    NoJobAvailable(usize),
     ErrorLoadingJob(DieselError),
     FailedToAcquireConnection(Pool::Error),
 }
 
+/// What happened to a job which a worker picked up
+#[derive(Debug, Clone, Copy)]
+pub enum JobOutcome {
+    Succeeded,
+    Failed,
+    /// The job failed for the given number of times, and will not be retried
+    Abandoned(u32),
+    Snoozed,
+    Expired,
+    Cancelled,
+}
+
 use std::fmt;
 
 impl<Pool: DieselPool> fmt::Debug for Event<Pool> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Event::Working => f.debug_struct("Working").finish(),
-            Event::NoJobAvailable => f.debug_struct("NoJobAvailable").finish(),
+            Event::Finished(outcome) => f.debug_tuple("Finished").field(outcome).finish(),
+            Event::NoJobAvailable(shard) => f.debug_tuple("NoJobAvailable").field(shard).finish(),
             Event::ErrorLoadingJob(e) => f.debug_tuple("ErrorLoadingJob").field(e).finish(),
             Event::FailedToAcquireConnection(e) => {
                 f.debug_tuple("FailedToAcquireConnection").field(e).finish()
@@ -0,0 +1,76 @@
+//! Keeps jobs which must run alone from overlapping with any other job.
+//!
+//! Workers wait for the gate to open before fetching a job, and enter it once
+//! they know which job they are running. An exclusive job closes the gate,
+//! then waits for every other running job to finish before it runs.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+#[derive(Default)]
+pub struct ExclusiveGate {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// The number of non-exclusive jobs which are running
+    running: usize,
+    /// Whether an exclusive job is running, or waiting to run
+    exclusive: bool,
+}
+
+impl ExclusiveGate {
+    /// Blocks while an exclusive job is running or waiting to run
+    pub fn wait_until_open(&self) {
+        let _state = self.wait_while(self.lock(), |state| state.exclusive);
+    }
+
+    /// Records that a job is about to run, blocking until it's allowed to.
+    /// The job is considered to be running until the returned guard is
+    /// dropped.
+    pub fn enter(&self, exclusive: bool) -> ExclusiveGuard<'_> {
+        let mut state = self.wait_while(self.lock(), |state| state.exclusive);
+        if exclusive {
+            state.exclusive = true;
+            let _state = self.wait_while(state, |state| state.running > 0);
+        } else {
+            state.running += 1;
+        }
+        ExclusiveGuard {
+            gate: self,
+            exclusive,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        super::lock(&self.state)
+    }
+
+    fn wait_while<'a>(
+        &self,
+        state: MutexGuard<'a, State>,
+        condition: impl FnMut(&mut State) -> bool,
+    ) -> MutexGuard<'a, State> {
+        self.changed
+            .wait_while(state, condition)
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct ExclusiveGuard<'a> {
+    gate: &'a ExclusiveGate,
+    exclusive: bool,
+}
+
+impl Drop for ExclusiveGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gate.lock();
+        if self.exclusive {
+            state.exclusive = false;
+        } else {
+            state.running -= 1;
+        }
+        self.gate.changed.notify_all();
+    }
+}
@@ -0,0 +1,152 @@
+#[cfg(feature = "rayon")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "rayon")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use threadpool::ThreadPool;
+
+/// Runs the work a runner hands out to its workers
+///
+/// Each call to [`execute`](Self::execute) fetches and runs at most one job.
+/// The runner uses the counts returned by the other methods to decide how many
+/// jobs to fetch at once, and to wait for running jobs to finish. By default,
+/// runners use a thread pool of their own, which gives each thread a numbered
+/// name. Another executor, such as a `threadpool::ThreadPool`, can be given
+/// with [`Builder::executor`](crate::Builder::executor).
+pub trait JobExecutor: Send + Sync + 'static {
+    /// Runs the given function on one of the executor's threads
+    fn execute(&self, f: Box<dyn FnOnce() + Send + 'static>);
+
+    /// The number of functions which can run at the same time
+    fn max_count(&self) -> usize;
+
+    /// The number of functions which are running
+    fn active_count(&self) -> usize;
+
+    /// The number of functions which are waiting for a thread to run on
+    fn queued_count(&self) -> usize;
+
+    /// The number of functions which have panicked
+    fn panic_count(&self) -> usize;
+
+    /// Blocks until every function given to the executor has finished
+    fn join(&self);
+}
+
+impl JobExecutor for ThreadPool {
+    fn execute(&self, f: Box<dyn FnOnce() + Send + 'static>) {
+        ThreadPool::execute(self, f)
+    }
+
+    fn max_count(&self) -> usize {
+        ThreadPool::max_count(self)
+    }
+
+    fn active_count(&self) -> usize {
+        ThreadPool::active_count(self)
+    }
+
+    fn queued_count(&self) -> usize {
+        ThreadPool::queued_count(self)
+    }
+
+    fn panic_count(&self) -> usize {
+        ThreadPool::panic_count(self)
+    }
+
+    fn join(&self) {
+        ThreadPool::join(self)
+    }
+}
+
+/// Runs jobs on a `rayon` thread pool
+///
+/// Jobs which use `rayon` themselves, such as with parallel iterators, share
+/// the pool's threads with the runner, so CPU heavy work is balanced across
+/// the pool instead of each job starting its own. Requires the `rayon`
+/// feature.
+#[cfg(feature = "rayon")]
+pub struct RayonExecutor {
+    pool: rayon::ThreadPool,
+    state: Arc<RayonState>,
+}
+
+#[cfg(feature = "rayon")]
+#[derive(Default)]
+struct RayonState {
+    counts: Mutex<RayonCounts>,
+    idle: Condvar,
+    panics: AtomicUsize,
+}
+
+#[cfg(feature = "rayon")]
+#[derive(Default)]
+struct RayonCounts {
+    queued: usize,
+    active: usize,
+}
+
+#[cfg(feature = "rayon")]
+impl RayonExecutor {
+    /// Runs jobs on the given pool
+    pub fn new(pool: rayon::ThreadPool) -> Self {
+        Self {
+            pool,
+            state: Arc::default(),
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl RayonState {
+    fn counts(&self) -> MutexGuard<'_, RayonCounts> {
+        super::lock(&self.counts)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl JobExecutor for RayonExecutor {
+    fn execute(&self, f: Box<dyn FnOnce() + Send + 'static>) {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        self.state.counts().queued += 1;
+        let state = Arc::clone(&self.state);
+        self.pool.spawn(move || {
+            {
+                let mut counts = state.counts();
+                counts.queued -= 1;
+                counts.active += 1;
+            }
+            // Rayon aborts the process if a spawned function panics
+            if catch_unwind(AssertUnwindSafe(f)).is_err() {
+                state.panics.fetch_add(1, Ordering::SeqCst);
+            }
+            state.counts().active -= 1;
+            state.idle.notify_all();
+        });
+    }
+
+    fn max_count(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    fn active_count(&self) -> usize {
+        self.state.counts().active
+    }
+
+    fn queued_count(&self) -> usize {
+        self.state.counts().queued
+    }
+
+    fn panic_count(&self) -> usize {
+        self.state.panics.load(Ordering::SeqCst)
+    }
+
+    fn join(&self) {
+        let counts = self.state.counts();
+        let _counts = self
+            .state
+            .idle
+            .wait_while(counts, |counts| counts.queued + counts.active > 0)
+            .unwrap_or_else(|e| e.into_inner());
+    }
+}
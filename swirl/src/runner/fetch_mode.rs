@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+/// How a runner makes sure a job is only run by one worker at a time
+///
+/// Set with [`Builder::fetch_mode`](crate::Builder::fetch_mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FetchMode {
+    /// The job's row is locked with `FOR UPDATE SKIP LOCKED` in a transaction
+    /// which stays open until the job finishes.
+    ///
+    /// If the runner crashes, the transaction is rolled back, and the job is
+    /// picked up again right away. Each running job holds a connection for as
+    /// long as it runs.
+    ///
+    /// This is the default.
+    #[default]
+    RowLock,
+
+    /// The job's row is claimed by setting its `locked_by` and `locked_until`
+    /// columns, and the claim is committed right away. The job runs without
+    /// holding the connection it was fetched with, and its row is updated
+    /// with another connection once it finishes.
+    ///
+    /// Other workers, including those of runners which use
+    /// [`RowLock`](Self::RowLock), won't fetch the job until `timeout` has
+    /// passed. If the runner crashes, the job isn't picked up again until
+    /// then. A job which is still running when its claim runs out may be run
+    /// again by another worker, and its result is not recorded when it
+    /// finishes, so `timeout` should be longer than any job takes.
+    ///
+    /// Jobs which run in a transaction (see
+    /// [`Job::IN_TRANSACTION`](crate::Job::IN_TRANSACTION)) are run the same
+    /// way as with [`CommitStrategy::SeparateTransactions`](crate::CommitStrategy::SeparateTransactions):
+    /// their work is committed before their row is updated.
+    Claim {
+        /// How long other workers wait before fetching a claimed job
+        timeout: Duration,
+    },
+}
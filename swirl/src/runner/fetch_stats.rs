@@ -0,0 +1,88 @@
+//! Counts the queries workers make to fetch the next job, so the cost of
+//! fetching jobs one at a time can be measured.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How many times the workers of a runner queried for the next job, and how
+/// long those queries took
+///
+/// Returned by [`Runner::fetch_stats`](crate::Runner::fetch_stats). The
+/// counts cover every fetch since the runner was built, so to measure a single
+/// run, take the difference of the stats from before and after it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FetchStats {
+    fetches: usize,
+    empty_fetches: usize,
+    fetch_time: Duration,
+}
+
+impl FetchStats {
+    /// The number of queries which were made for the next job, including
+    /// those which found none. Queries which returned an error aren't counted.
+    pub fn fetches(&self) -> usize {
+        self.fetches
+    }
+
+    /// The number of queries for the next job which found no job to run
+    pub fn empty_fetches(&self) -> usize {
+        self.empty_fetches
+    }
+
+    /// How long all of the queries took together
+    pub fn total_fetch_time(&self) -> Duration {
+        self.fetch_time
+    }
+
+    /// How long a query for the next job took on average, or `None` if no
+    /// jobs have been fetched
+    pub fn average_fetch_time(&self) -> Option<Duration> {
+        if self.fetches == 0 {
+            None
+        } else {
+            Some(self.fetch_time / self.fetches as u32)
+        }
+    }
+
+    /// The fetches which were made since `earlier` was taken
+    pub fn since(&self, earlier: &FetchStats) -> FetchStats {
+        FetchStats {
+            fetches: self.fetches - earlier.fetches,
+            empty_fetches: self.empty_fetches - earlier.empty_fetches,
+            fetch_time: self.fetch_time - earlier.fetch_time,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct FetchCounters(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    fetches: AtomicUsize,
+    empty_fetches: AtomicUsize,
+    fetch_nanos: AtomicU64,
+}
+
+impl FetchCounters {
+    /// Records a query for the next job which took `duration`, and whether it
+    /// found a job
+    pub fn record(&self, duration: Duration, found_job: bool) {
+        self.0.fetches.fetch_add(1, Ordering::Relaxed);
+        if !found_job {
+            self.0.empty_fetches.fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.0.fetch_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> FetchStats {
+        FetchStats {
+            fetches: self.0.fetches.load(Ordering::Relaxed),
+            empty_fetches: self.0.empty_fetches.load(Ordering::Relaxed),
+            fetch_time: Duration::from_nanos(self.0.fetch_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
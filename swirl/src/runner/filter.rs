@@ -0,0 +1,83 @@
+use std::ops::{Bound, RangeBounds};
+
+/// Restricts which jobs are run by [`Runner::run_jobs_where`](crate::Runner::run_jobs_where)
+///
+/// An empty filter matches every job. Each condition which is added narrows
+/// the filter further.
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    pub(crate) job_types: Vec<String>,
+    pub(crate) min_id: Option<i64>,
+    pub(crate) max_id: Option<i64>,
+    pub(crate) empty_id_range: bool,
+    pub(crate) arguments: Vec<(String, String)>,
+}
+
+impl JobFilter {
+    /// Creates a filter which matches every job
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match jobs of the given type.
+    ///
+    /// This can be called more than once to match jobs of any of the given
+    /// types.
+    pub fn job_type<S: Into<String>>(mut self, job_type: S) -> Self {
+        self.job_types.push(job_type.into());
+        self
+    }
+
+    /// Only match jobs whose id is in the given range.
+    ///
+    /// A range which no id can be in, such as `(Excluded(i64::MAX),
+    /// Unbounded)`, matches no jobs.
+    pub fn id_range<R: RangeBounds<i64>>(mut self, range: R) -> Self {
+        self.empty_id_range = false;
+        self.min_id = match range.start_bound() {
+            Bound::Included(&min) => Some(min),
+            Bound::Excluded(&min) => {
+                let min = min.checked_add(1);
+                self.empty_id_range |= min.is_none();
+                min
+            }
+            Bound::Unbounded => None,
+        };
+        self.max_id = match range.end_bound() {
+            Bound::Included(&max) => Some(max),
+            Bound::Excluded(&max) => {
+                let max = max.checked_sub(1);
+                self.empty_id_range |= max.is_none();
+                max
+            }
+            Bound::Unbounded => None,
+        };
+        self
+    }
+
+    /// Only match jobs whose argument with the given name is equal to the
+    /// given value.
+    ///
+    /// The argument is compared as text, with `data ->> 'name' = $1`, so
+    /// numbers must be given in the form they're serialized in, such as
+    /// `"42"`. Calling this more than once requires every argument to match.
+    ///
+    /// Filtering on an argument scans every job which is ready to run,
+    /// unless the table has an index on that argument. For a queue which is
+    /// regularly filtered by a `tenant_id` argument, add a migration like
+    /// this to your application:
+    ///
+    /// ```sql
+    /// CREATE INDEX background_jobs_ready_by_tenant_id
+    ///     ON background_jobs ((data ->> 'tenant_id'), retry_at, id)
+    ///     WHERE NOT abandoned;
+    /// ```
+    pub fn argument<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.arguments.push((name.into(), value.into()));
+        self
+    }
+}
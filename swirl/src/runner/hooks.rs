@@ -0,0 +1,102 @@
+use diesel::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::PerformError;
+
+/// Information about a job which has just finished running, passed to the
+/// hooks registered with [`Builder::on_success`](crate::Builder::on_success),
+/// [`Builder::on_failure`](crate::Builder::on_failure),
+/// [`Builder::on_abandon`](crate::Builder::on_abandon), and
+/// [`Builder::on_expired`](crate::Builder::on_expired).
+#[derive(Debug, Clone, Copy)]
+pub struct JobRun<'a> {
+    /// The id of the job's row in the `background_jobs` table
+    pub id: i64,
+    /// The type of the job
+    pub job_type: &'a str,
+    /// How long the job's `perform` took to run
+    pub duration: Duration,
+}
+
+type JobHook = dyn Fn(&JobRun<'_>) + Send + Sync;
+type FailureHook = dyn Fn(&JobRun<'_>, &PerformError) + Send + Sync;
+type AbandonHook = dyn Fn(&JobRun<'_>, u32, &PerformError) + Send + Sync;
+type IdleHook = dyn Fn() + Send + Sync;
+type StartTimeoutHook = dyn Fn(usize) + Send + Sync;
+type RecordFailure = dyn Fn(&mut PgConnection, i64, &PerformError) -> QueryResult<()> + Send + Sync;
+type TransformPayload =
+    dyn Fn(&str, &mut serde_json::Value) -> Result<(), PerformError> + Send + Sync;
+
+#[derive(Default, Clone)]
+pub(crate) struct Hooks {
+    pub(crate) on_success: Option<Arc<JobHook>>,
+    pub(crate) on_failure: Option<Arc<FailureHook>>,
+    pub(crate) on_abandon: Option<Arc<AbandonHook>>,
+    pub(crate) on_expired: Option<Arc<JobHook>>,
+    pub(crate) record_failure: Option<Arc<RecordFailure>>,
+    pub(crate) transform_payload: Option<Arc<TransformPayload>>,
+    pub(crate) on_idle: Option<Arc<IdleHook>>,
+    pub(crate) on_job_start_timeout: Option<Arc<StartTimeoutHook>>,
+}
+
+impl Hooks {
+    pub(crate) fn succeeded(&self, run: &JobRun<'_>) {
+        if let Some(hook) = &self.on_success {
+            hook(run);
+        }
+    }
+
+    pub(crate) fn failed(&self, run: &JobRun<'_>, error: &PerformError) {
+        if let Some(hook) = &self.on_failure {
+            hook(run, error);
+        }
+    }
+
+    /// Runs the custom failure update in a savepoint, so an error in it
+    /// doesn't keep the retry count from being updated.
+    pub(crate) fn record_failure(&self, conn: &mut PgConnection, id: i64, error: &PerformError) {
+        if let Some(hook) = &self.record_failure {
+            if let Err(e) = conn.transaction(|conn| hook(conn, id, error)) {
+                eprintln!("Failed to record the failure of job {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Lets the registered function rewrite or reject a job's arguments
+    /// before they are deserialized
+    pub(crate) fn transform_payload(
+        &self,
+        job_type: &str,
+        data: &mut serde_json::Value,
+    ) -> Result<(), PerformError> {
+        match &self.transform_payload {
+            Some(transform) => transform(job_type, data),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn abandoned(&self, run: &JobRun<'_>, retries: u32, error: &PerformError) {
+        if let Some(hook) = &self.on_abandon {
+            hook(run, retries, error);
+        }
+    }
+
+    pub(crate) fn expired(&self, run: &JobRun<'_>) {
+        if let Some(hook) = &self.on_expired {
+            hook(run);
+        }
+    }
+
+    pub(crate) fn idle(&self) {
+        if let Some(hook) = &self.on_idle {
+            hook();
+        }
+    }
+
+    pub(crate) fn job_start_timed_out(&self, pending_jobs: usize) {
+        if let Some(hook) = &self.on_job_start_timeout {
+            hook(pending_jobs);
+        }
+    }
+}
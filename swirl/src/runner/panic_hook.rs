@@ -0,0 +1,69 @@
+//! A panic hook which keeps panics inside of jobs from being printed by the
+//! previously installed hook. Those panics are caught by the runner and
+//! reported as job failures, so printing them here as well would only result
+//! in duplicate output.
+//!
+//! The hook is installed process-wide, but only affects threads which are
+//! currently running a job. Panics anywhere else are passed through to the
+//! hook which was installed before ours.
+//!
+//! Since the hook runs before the stack is unwound, it's also where a
+//! backtrace of the panic can be captured, if the runner asked for one.
+
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic;
+use std::sync::Once;
+
+thread_local! {
+    static RUNNING_JOB: Cell<bool> = const { Cell::new(false) };
+    static CAPTURE_BACKTRACE: Cell<bool> = const { Cell::new(false) };
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+/// Installs the hook. Calling this more than once has no effect.
+pub fn install() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if RUNNING_JOB.with(Cell::get) {
+                let location = info.location().map(ToString::to_string);
+                PANIC_LOCATION.with(|l| *l.borrow_mut() = location);
+                if CAPTURE_BACKTRACE.with(Cell::get) {
+                    let backtrace = Backtrace::force_capture();
+                    PANIC_BACKTRACE.with(|b| *b.borrow_mut() = Some(backtrace));
+                }
+            } else {
+                previous_hook(info);
+            }
+        }));
+    });
+}
+
+/// Runs the given function with the hook silenced on this thread. If
+/// `capture_backtrace` is true, a backtrace is recorded when it panics.
+///
+/// The function is expected to catch any panics itself.
+pub fn while_running_job<T>(capture_backtrace: bool, f: impl FnOnce() -> T) -> T {
+    RUNNING_JOB.with(|r| r.set(true));
+    CAPTURE_BACKTRACE.with(|c| c.set(capture_backtrace));
+    let result = f();
+    RUNNING_JOB.with(|r| r.set(false));
+    CAPTURE_BACKTRACE.with(|c| c.set(false));
+    result
+}
+
+/// Returns where the last panic on this thread occurred, if it happened
+/// while running a job.
+pub fn take_panic_location() -> Option<String> {
+    PANIC_LOCATION.with(|l| l.borrow_mut().take())
+}
+
+/// Returns the backtrace of the last panic on this thread, if it happened
+/// while running a job which asked for one.
+pub fn take_panic_backtrace() -> Option<Backtrace> {
+    PANIC_BACKTRACE.with(|b| b.borrow_mut().take())
+}
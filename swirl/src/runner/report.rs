@@ -0,0 +1,97 @@
+use std::fmt;
+use std::time::Duration;
+
+use super::event::JobOutcome;
+
+/// A summary of a call to [`Runner::run_all_pending_jobs`](crate::Runner::run_all_pending_jobs)
+///
+/// `run_all_pending_jobs` returns once every job has started, without waiting
+/// for them to finish. Jobs which were still running when it returned are
+/// counted as started, but not in any of the other counts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunReport {
+    started: usize,
+    succeeded: usize,
+    failed: usize,
+    abandoned: usize,
+    duration: Duration,
+    stopped_after_failure: bool,
+}
+
+impl RunReport {
+    /// The number of jobs which were picked up from the queue
+    pub fn started(&self) -> usize {
+        self.started
+    }
+
+    /// The number of jobs which ran successfully
+    pub fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+
+    /// The number of jobs which failed, including those which were abandoned
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    /// The number of jobs which failed, and reached the maximum number of
+    /// retries set with [`Builder::max_retries`](crate::Builder::max_retries).
+    /// These jobs will not be run again.
+    pub fn abandoned(&self) -> usize {
+        self.abandoned
+    }
+
+    /// How long the call took
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Whether the run stopped starting jobs because one of them failed, on a
+    /// runner built with [`Builder::fail_fast`](crate::Builder::fail_fast).
+    /// Jobs which weren't started are still in the queue.
+    pub fn stopped_after_failure(&self) -> bool {
+        self.stopped_after_failure
+    }
+
+    pub(super) fn finish(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    pub(super) fn stop_after_failure(&mut self) {
+        self.stopped_after_failure = true;
+    }
+
+    pub(super) fn job_started(&mut self) {
+        self.started += 1;
+    }
+
+    pub(super) fn job_finished(&mut self, outcome: JobOutcome) {
+        match outcome {
+            JobOutcome::Succeeded => self.succeeded += 1,
+            JobOutcome::Failed => self.failed += 1,
+            JobOutcome::Abandoned(_) => {
+                self.failed += 1;
+                self.abandoned += 1;
+            }
+            JobOutcome::Snoozed | JobOutcome::Expired | JobOutcome::Cancelled => {}
+        }
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ran {} {} ({} succeeded, {} failed) in {}ms",
+            self.started,
+            if self.started == 1 { "job" } else { "jobs" },
+            self.succeeded,
+            self.failed,
+            self.duration.as_millis(),
+        )?;
+        if self.stopped_after_failure {
+            write!(f, ", then stopped after a job failed")?;
+        }
+        Ok(())
+    }
+}
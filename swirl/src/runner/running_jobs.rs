@@ -0,0 +1,86 @@
+//! Tracks the jobs which are currently being run, so the runner knows how long
+//! it may have to wait for a worker thread to become available, and which jobs
+//! would be interrupted by shutting down.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A job which has been running for longer than expected
+///
+/// Returned by [`Runner::stuck_jobs`](crate::Runner::stuck_jobs).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StuckJob {
+    /// The id of the job's row in the `background_jobs` table
+    pub id: i64,
+    /// The job's type
+    pub job_type: String,
+    /// How long the job has been running
+    pub running_for: Duration,
+}
+
+#[derive(Default, Clone)]
+pub struct RunningJobs(Arc<Mutex<HashMap<i64, (String, Instant)>>>);
+
+impl RunningJobs {
+    /// Records that a job has started running. It is considered to be running
+    /// until the returned guard is dropped.
+    pub fn start(&self, id: i64, job_type: &str) -> RunningJob {
+        self.lock().insert(id, (job_type.into(), Instant::now()));
+        RunningJob {
+            running_jobs: self.clone(),
+            id,
+        }
+    }
+
+    /// The distinct types of the jobs which are currently running
+    pub fn job_types(&self) -> Vec<String> {
+        let mut job_types = self
+            .lock()
+            .values()
+            .map(|(job_type, _)| job_type.clone())
+            .collect::<Vec<_>>();
+        job_types.sort();
+        job_types.dedup();
+        job_types
+    }
+
+    /// The ids of the jobs which are currently running
+    pub fn ids(&self) -> Vec<i64> {
+        let mut ids = self.lock().keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// The jobs which have been running for longer than `threshold`, ordered
+    /// by id
+    pub fn running_longer_than(&self, threshold: Duration) -> Vec<StuckJob> {
+        let mut stuck_jobs = self
+            .lock()
+            .iter()
+            .map(|(&id, (job_type, started_at))| StuckJob {
+                id,
+                job_type: job_type.clone(),
+                running_for: started_at.elapsed(),
+            })
+            .filter(|job| job.running_for > threshold)
+            .collect::<Vec<_>>();
+        stuck_jobs.sort_by_key(|job| job.id);
+        stuck_jobs
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i64, (String, Instant)>> {
+        super::lock(&self.0)
+    }
+}
+
+pub struct RunningJob {
+    running_jobs: RunningJobs,
+    id: i64,
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.running_jobs.lock().remove(&self.id);
+    }
+}
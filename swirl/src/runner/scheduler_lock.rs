@@ -0,0 +1,48 @@
+use diesel::PgConnection;
+use std::ops::DerefMut;
+
+use crate::db::{DieselPool, DieselPooledConn};
+use crate::storage;
+
+/// The advisory lock key used by
+/// [`Runner::try_become_scheduler`](crate::Runner::try_become_scheduler),
+/// unless another one is set with
+/// [`Builder::scheduler_lock_key`](crate::Builder::scheduler_lock_key).
+pub const DEFAULT_SCHEDULER_LOCK_KEY: i64 = 0x7377_6972_6c00_0001;
+
+/// Proof that this process is the only one which should enqueue recurring
+/// jobs, returned by
+/// [`Runner::try_become_scheduler`](crate::Runner::try_become_scheduler).
+///
+/// This holds a connection from the runner's pool, which holds a session level
+/// advisory lock. The lock is released when this is dropped, or if the
+/// connection is lost.
+#[allow(missing_debug_implementations)] // The connection can't implement debug
+pub struct SchedulerLock<'a, ConnectionPool: DieselPool> {
+    conn: DieselPooledConn<'a, ConnectionPool>,
+    key: i64,
+}
+
+impl<'a, ConnectionPool: DieselPool> SchedulerLock<'a, ConnectionPool> {
+    pub(super) fn new(conn: DieselPooledConn<'a, ConnectionPool>, key: i64) -> Self {
+        Self { conn, key }
+    }
+
+    /// The connection which holds the lock.
+    ///
+    /// Checking that this connection is still usable is a good way to make
+    /// sure the lock is still held before enqueuing jobs.
+    pub fn connection(&mut self) -> &mut PgConnection {
+        self.conn.deref_mut()
+    }
+}
+
+impl<ConnectionPool: DieselPool> Drop for SchedulerLock<'_, ConnectionPool> {
+    fn drop(&mut self) {
+        // The connection goes back into the pool, so the lock has to be
+        // released explicitly
+        if let Err(e) = storage::advisory_unlock(&mut self.conn, self.key) {
+            eprintln!("Failed to release the scheduler lock: {}", e);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! The thread pool used to run jobs, unless another executor is given.
+//!
+//! Threads are named with the pool's prefix and the order they were started
+//! in, such as `swirl-worker-0`. A lazy pool only starts a thread whenever a
+//! function is queued and no thread is idle, until the pool has as many
+//! threads as it's allowed. Otherwise every thread is started along with the
+//! pool. Threads which have been started stay around until the pool is
+//! dropped.
+
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use super::executor::JobExecutor;
+
+type Thunk = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    thread_name_prefix: String,
+    max_count: usize,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when a function is queued, or the pool is dropped
+    work_queued: Condvar,
+    /// Notified when a function finishes and nothing else is left to run
+    idle: Condvar,
+    panics: AtomicUsize,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Thunk>,
+    threads: usize,
+    idle_threads: usize,
+    active: usize,
+    shutting_down: bool,
+}
+
+impl WorkerPool {
+    pub fn new(thread_name_prefix: &str, max_count: usize, lazy: bool) -> Self {
+        let pool = Self {
+            thread_name_prefix: thread_name_prefix.into(),
+            max_count,
+            shared: Arc::default(),
+        };
+        if !lazy {
+            pool.shared.lock().threads = max_count;
+            for index in 0..max_count {
+                pool.spawn_thread(index);
+            }
+        }
+        pool
+    }
+
+    fn spawn_thread(&self, index: usize) {
+        let shared = Arc::clone(&self.shared);
+        thread::Builder::new()
+            .name(format!("{}-{}", self.thread_name_prefix, index))
+            .spawn(move || shared.work())
+            .expect("failed to spawn a worker thread");
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        super::lock(&self.state)
+    }
+
+    fn work(&self) {
+        let mut state = self.lock();
+        loop {
+            if let Some(f) = state.queue.pop_front() {
+                state.active += 1;
+                drop(state);
+                if catch_unwind(AssertUnwindSafe(f)).is_err() {
+                    self.panics.fetch_add(1, Ordering::SeqCst);
+                }
+                state = self.lock();
+                state.active -= 1;
+                if state.active == 0 && state.queue.is_empty() {
+                    self.idle.notify_all();
+                }
+            } else if state.shutting_down {
+                return;
+            } else {
+                state.idle_threads += 1;
+                state = self
+                    .work_queued
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner());
+                state.idle_threads -= 1;
+            }
+        }
+    }
+}
+
+impl JobExecutor for WorkerPool {
+    fn execute(&self, f: Thunk) {
+        let mut state = self.shared.lock();
+        state.queue.push_back(f);
+        // Idle threads which were already woken up for other functions
+        // haven't taken them off the queue yet
+        if state.queue.len() > state.idle_threads && state.threads < self.max_count {
+            let index = state.threads;
+            state.threads += 1;
+            drop(state);
+            self.spawn_thread(index);
+        } else {
+            self.shared.work_queued.notify_one();
+        }
+    }
+
+    fn max_count(&self) -> usize {
+        self.max_count
+    }
+
+    fn active_count(&self) -> usize {
+        self.shared.lock().active
+    }
+
+    fn queued_count(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::SeqCst)
+    }
+
+    fn join(&self) {
+        let state = self.shared.lock();
+        let _state = self
+            .shared
+            .idle
+            .wait_while(state, |state| state.active > 0 || !state.queue.is_empty())
+            .unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Threads finish whatever is queued before they exit
+        self.shared.lock().shutting_down = true;
+        self.shared.work_queued.notify_all();
+    }
+}
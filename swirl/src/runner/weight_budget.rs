@@ -0,0 +1,61 @@
+//! Limits the total weight of the jobs a runner has running at once.
+//!
+//! Workers acquire their job's weight once they know which job they are
+//! running, waiting until enough of the budget is free for it.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+pub struct WeightBudget {
+    budget: Option<u32>,
+    /// The total weight of the jobs which are running
+    running: Mutex<u32>,
+    changed: Condvar,
+}
+
+impl WeightBudget {
+    pub fn new(budget: Option<u32>) -> Self {
+        Self {
+            budget,
+            running: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Records that a job with the given weight is about to run, blocking
+    /// until it fits in the budget. A job which is heavier than the whole
+    /// budget runs once nothing else is running, rather than never. The
+    /// weight is released when the returned guard is dropped.
+    pub fn acquire(&self, weight: u32) -> WeightGuard<'_> {
+        if let Some(budget) = self.budget {
+            let mut running = self
+                .changed
+                .wait_while(self.lock(), |running| {
+                    *running > 0 && *running + weight > budget
+                })
+                .unwrap_or_else(|e| e.into_inner());
+            *running += weight;
+        }
+        WeightGuard {
+            budget: self,
+            weight,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, u32> {
+        super::lock(&self.running)
+    }
+}
+
+pub struct WeightGuard<'a> {
+    budget: &'a WeightBudget,
+    weight: u32,
+}
+
+impl Drop for WeightGuard<'_> {
+    fn drop(&mut self) {
+        if self.budget.budget.is_some() {
+            *self.budget.lock() -= self.weight;
+            self.budget.changed.notify_all();
+        }
+    }
+}
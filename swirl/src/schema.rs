@@ -6,5 +6,35 @@ table! {
         retries -> Int4,
         last_retry -> Timestamp,
         created_at -> Timestamp,
+        retry_at -> Timestamp,
+        correlation_id -> Nullable<Text>,
+        trace_context -> Nullable<Jsonb>,
+        expires_at -> Nullable<Timestamp>,
+        abandoned -> Bool,
+        depends_on -> Nullable<Int8>,
+        locked_by -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    background_job_cancellations (job_id) {
+        job_id -> Int8,
+        requested_at -> Timestamp,
+    }
+}
+
+table! {
+    background_job_pauses (job_type) {
+        job_type -> Text,
+        paused_at -> Timestamp,
+    }
+}
+
+table! {
+    background_job_progress (job_id) {
+        job_id -> Int8,
+        progress -> Int2,
+        updated_at -> Timestamp,
     }
 }
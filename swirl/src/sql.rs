@@ -0,0 +1,85 @@
+//! The SQL run by swirl's runner, for use with other database drivers
+//!
+//! Swirl's queries are built with Diesel. These constants are equivalent
+//! versions of the queries which make up the queue's semantics, written as
+//! plain SQL with `$n` placeholders. They're meant for applications which need
+//! to work the `background_jobs` table from another query layer, while
+//! locking jobs the same way swirl does, so they can share the table with
+//! swirl runners.
+//!
+//! A job is run by executing these queries on a single connection:
+//!
+//! 1. Begin a transaction, and run [`FETCH_NEXT_JOB`]. If no row is returned,
+//!    there are no jobs to run. Otherwise the row stays locked until the
+//!    transaction ends, and other workers will skip it.
+//! 2. Run the job. If the job fails, only the work it did should be rolled
+//!    back, such as by running it in a savepoint.
+//! 3. If the job succeeded, or the `expired` column was true (in which case
+//!    the job should not be run at all), run [`DELETE_JOB`]. Otherwise, run
+//!    [`MARK_JOB_FAILED`].
+//! 4. Commit the transaction.
+//!
+//! If the process dies before committing, the lock is released and the job
+//! will be picked up again by the next worker.
+
+/// Locks and returns the next job which is ready to be run.
+///
+/// Jobs which depend on another job (see
+/// [`Job::enqueue_after`](crate::Job::enqueue_after)) aren't ready until that
+/// job's row has been deleted. Jobs whose type is in `background_job_pauses`
+/// (see [`pause_job_type`](crate::pause_job_type)) aren't ready either, and
+/// neither are jobs claimed by a runner using
+/// [`FetchMode::Claim`](crate::FetchMode::Claim), until their `locked_until`
+/// has passed.
+///
+/// Returns the columns `id`, `job_type`, `data`, `retries`, `created_at`,
+/// `retry_at`, `correlation_id`, `trace_context` and `expired`. Jobs are
+/// returned in the order they are ready to be retried, and then in the order
+/// they were enqueued. Rows which are locked by another worker are skipped
+/// instead of waited on.
+pub const FETCH_NEXT_JOB: &str = "\
+SELECT id, job_type, data, retries, created_at, retry_at, correlation_id, trace_context, \
+COALESCE(expires_at < CURRENT_TIMESTAMP, FALSE) AS expired \
+FROM background_jobs \
+WHERE retry_at <= CURRENT_TIMESTAMP AND NOT abandoned \
+AND (depends_on IS NULL OR NOT EXISTS \
+(SELECT 1 FROM background_jobs dependency WHERE dependency.id = background_jobs.depends_on)) \
+AND NOT EXISTS (SELECT 1 FROM background_job_pauses \
+WHERE background_job_pauses.job_type = background_jobs.job_type) \
+AND (locked_until IS NULL OR locked_until < CURRENT_TIMESTAMP) \
+ORDER BY retry_at, id \
+LIMIT 1 \
+FOR UPDATE SKIP LOCKED";
+
+/// Deletes a job which has finished successfully, or which expired.
+///
+/// Takes the id of the job as `$1`. Any progress the job recorded with
+/// [`JobContext::set_progress`](crate::JobContext::set_progress), which is kept
+/// in `background_job_progress`, and requests to cancel it made with
+/// [`request_cancel`](crate::request_cancel), which are kept in
+/// `background_job_cancellations`, are deleted along with it.
+pub const DELETE_JOB: &str = "\
+WITH progress AS (DELETE FROM background_job_progress WHERE job_id = $1), \
+cancellations AS (DELETE FROM background_job_cancellations WHERE job_id = $1) \
+DELETE FROM background_jobs WHERE id = $1";
+
+/// Records that a job failed, and schedules it to be retried.
+///
+/// Takes the id of the job as `$1`, the fraction of the backoff delay which is
+/// randomized (see [`Builder::retry_jitter`](crate::Builder::retry_jitter)) as
+/// a `float8` in `$2`, and the maximum number of retries (see
+/// [`Builder::max_retries`](crate::Builder::max_retries)) as an `int4` in `$3`.
+/// Pass `0.0` for no jitter, and `NULL` to retry the job forever.
+///
+/// Returns a single `abandoned` column, which is true if the job will not be
+/// retried again.
+pub const MARK_JOB_FAILED: &str = "\
+UPDATE background_jobs SET \
+retries = retries + 1, \
+last_retry = CURRENT_TIMESTAMP, \
+retry_at = CURRENT_TIMESTAMP + INTERVAL '1 minute' * POWER(2, retries + 1) * (1 - $2 * RANDOM()), \
+abandoned = COALESCE(retries + 1 >= $3, FALSE), \
+locked_by = NULL, \
+locked_until = NULL \
+WHERE id = $1 \
+RETURNING abandoned";
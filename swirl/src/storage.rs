@@ -1,57 +1,447 @@
 use diesel::dsl::now;
+use diesel::pg::data_types::PgInterval;
 use diesel::pg::Pg;
 use diesel::prelude::*;
-use diesel::sql_types::{Bool, Integer, Interval};
+use diesel::sql_types::{BigInt, Bool, Double, Interval, Nullable, Text, Timestamp};
 use diesel::{delete, insert_into, update};
 use serde_json;
+use std::time::{Duration, SystemTime};
 
+use crate::enqueue_builder::EnqueueOptions;
 use crate::errors::EnqueueError;
 use crate::schema::background_jobs;
-use crate::Job;
+use crate::{enqueue_observer, otel, Job, JobFilter};
 
+/// A job's row, as it's loaded by the runner to run it. Loaded from
+/// [`background_job_columns`].
 #[derive(Queryable, Identifiable, Debug, Clone)]
 pub struct BackgroundJob {
     pub id: i64,
     pub job_type: String,
     pub data: serde_json::Value,
+    pub retries: i32,
+    pub created_at: SystemTime,
+    pub retry_at: SystemTime,
+    pub correlation_id: Option<String>,
+    pub trace_context: Option<serde_json::Value>,
+    pub expired: bool,
 }
 
-/// Enqueues a job to be run as soon as possible.
-pub fn enqueue_job<T: Job>(conn: &PgConnection, job: T) -> Result<(), EnqueueError> {
+type BackgroundJobColumns = (
+    background_jobs::id,
+    background_jobs::job_type,
+    background_jobs::data,
+    background_jobs::retries,
+    background_jobs::created_at,
+    background_jobs::retry_at,
+    background_jobs::correlation_id,
+    background_jobs::trace_context,
+    diesel::expression::SqlLiteral<Bool>,
+);
+
+/// The columns a [`BackgroundJob`] is loaded from. Every query which fetches
+/// jobs to run selects or returns these, so they're all loaded the same way.
+pub fn background_job_columns() -> BackgroundJobColumns {
     use crate::schema::background_jobs::dsl::*;
 
-    let job_data = serde_json::to_value(job)?;
-    insert_into(background_jobs)
-        .values((job_type.eq(T::JOB_TYPE), data.eq(job_data)))
-        .execute(conn)?;
+    (
+        id,
+        job_type,
+        data,
+        retries,
+        created_at,
+        retry_at,
+        correlation_id,
+        trace_context,
+        expired(),
+    )
+}
+
+/// Enqueues a job with the given options. Returns the id of the new job.
+pub fn enqueue_job<T: Job>(
+    conn: &mut PgConnection,
+    job: T,
+    options: &EnqueueOptions,
+) -> Result<i64, EnqueueError> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+
+    job.validate()?;
+    let job_data = job.to_value()?;
+    let interval = |duration: Duration| PgInterval::from_microseconds(duration.as_micros() as i64);
+    let ttl = options.ttl.map(interval);
+    let delay = interval(options.delay.unwrap_or_default());
+    let job_id = insert_into(background_jobs)
+        .values((
+            job_type.eq(T::JOB_TYPE),
+            data.eq(&job_data),
+            correlation_id.eq(options.correlation_id.as_deref()),
+            trace_context.eq(otel::current_context()),
+            retry_at.eq(sql("CURRENT_TIMESTAMP + ").bind::<Interval, _>(delay)),
+            expires_at.eq(sql("CURRENT_TIMESTAMP + ").bind::<Nullable<Interval>, _>(ttl)),
+            depends_on.eq(options.dependency),
+        ))
+        .returning(id)
+        .get_result(conn)?;
+    enqueue_observer::job_enqueued(T::JOB_TYPE, &job_data);
+    Ok(job_id)
+}
+
+/// Enqueues a job with the default options, unless an identical job which
+/// isn't running was enqueued within `window`. Returns the id of the new job,
+/// or `None` if there already was one.
+pub fn enqueue_job_debounced<T: Job>(
+    conn: &mut PgConnection,
+    job: T,
+    window: Duration,
+) -> Result<Option<i64>, EnqueueError> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::not;
+
+    let job_data = job.to_value()?;
+    let window = PgInterval::from_microseconds(window.as_micros() as i64);
+    conn.transaction(|conn| {
+        // Held until the transaction ends, so concurrent calls for the same
+        // job can't both find nothing and insert it. Two `int4` keys don't
+        // overlap with the `bigint` key of the scheduler lock.
+        diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1), hashtext($2::text))")
+            .bind::<Text, _>(T::JOB_TYPE)
+            .bind::<diesel::sql_types::Jsonb, _>(&job_data)
+            .execute(conn)?;
+        let pending = background_jobs
+            .select(id)
+            .filter(job_type.eq(T::JOB_TYPE))
+            .filter(data.eq(&job_data))
+            .filter(created_at.gt(now - window))
+            .filter(not(abandoned))
+            .filter(not_claimed())
+            .for_update()
+            .skip_locked()
+            .first::<i64>(conn)
+            .optional()?;
+        match pending {
+            Some(_) => Ok(None),
+            None => enqueue_job(conn, job, &EnqueueOptions::default()).map(Some),
+        }
+    })
+}
+
+/// Enqueues a copy of the job with the given id, with the same type, data and
+/// correlation id, which is run once `delay` has passed. Returns the id of the
+/// new job, or `None` if there is no job with that id.
+pub fn enqueue_copy(
+    conn: &mut PgConnection,
+    job_id: i64,
+    delay: Duration,
+) -> QueryResult<Option<i64>> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+
+    let delay = PgInterval::from_microseconds(delay.as_micros() as i64);
+    let copied = background_jobs.find(job_id).select((
+        job_type,
+        data,
+        correlation_id,
+        otel::current_context().into_sql::<Nullable<diesel::sql_types::Jsonb>>(),
+        sql("CURRENT_TIMESTAMP + ").bind::<Interval, _>(delay),
+    ));
+    let copy = insert_into(background_jobs)
+        .values(copied)
+        .into_columns((job_type, data, correlation_id, trace_context, retry_at))
+        .returning((id, job_type, data))
+        .get_result::<(i64, String, serde_json::Value)>(conn)
+        .optional()?;
+    Ok(copy.map(|(copy_id, copy_type, copy_data)| {
+        enqueue_observer::job_enqueued(&copy_type, &copy_data);
+        copy_id
+    }))
+}
+
+/// Enqueues jobs of any type to be run as soon as possible, with a single
+/// `INSERT`. Returns the ids of the new jobs, in the order they were given.
+pub fn enqueue_jobs(
+    conn: &mut PgConnection,
+    jobs: &[(&'static str, serde_json::Value)],
+) -> QueryResult<Vec<i64>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    if jobs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let trace = otel::current_context();
+    let rows = jobs
+        .iter()
+        .map(|(new_type, new_data)| {
+            (
+                job_type.eq(*new_type),
+                data.eq(new_data),
+                trace_context.eq(trace.as_ref()),
+                retry_at.eq(now),
+            )
+        })
+        .collect::<Vec<_>>();
+    // The ids come from a sequence, in the order the rows are inserted, but
+    // `RETURNING` doesn't promise to return them in that order
+    let mut ids = insert_into(background_jobs)
+        .values(rows)
+        .returning(id)
+        .get_results::<i64>(conn)?;
+    ids.sort_unstable();
+    for (new_type, new_data) in jobs {
+        enqueue_observer::job_enqueued(new_type, new_data);
+    }
+    Ok(ids)
+}
+
+/// Enqueues a job to be run as soon as possible, using an async connection.
+#[cfg(feature = "async")]
+pub async fn enqueue_job_async(
+    conn: &mut diesel_async::AsyncPgConnection,
+    job_type: &'static str,
+    job_data: serde_json::Value,
+) -> Result<(), EnqueueError> {
+    let query = insert_into(background_jobs::table).values((
+        background_jobs::job_type.eq(job_type),
+        background_jobs::data.eq(&job_data),
+        background_jobs::trace_context.eq(otel::current_context()),
+        background_jobs::retry_at.eq(now),
+    ));
+    diesel_async::RunQueryDsl::execute(query, conn).await?;
+    enqueue_observer::job_enqueued(job_type, &job_data);
     Ok(())
 }
 
+/// Runs any of swirl's migrations which haven't been run on this database.
+///
+/// Versions are recorded in Diesel's `__diesel_schema_migrations` table, so
+/// migrations which were copied into the application and run from there are
+/// not run again.
+#[cfg(feature = "migrations")]
+pub fn run_pending_migrations(
+    conn: &mut PgConnection,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+    conn.run_pending_migrations(MIGRATIONS)?;
+    Ok(())
+}
+
+/// Whether a job is ready to be run.
+///
+/// `NOT abandoned` is written out literally, rather than compared against a
+/// bind parameter, so that Postgres can tell it implies the predicate of the
+/// `background_jobs_ready` partial index.
 fn retriable() -> Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Bool>> {
     use crate::schema::background_jobs::dsl::*;
-    use diesel::dsl::*;
+    use diesel::dsl::not;
+
+    Box::new(
+        retry_at
+            .le(now)
+            .and(not(abandoned))
+            .and(dependency_finished())
+            .and(not_paused())
+            .and(not_claimed()),
+    )
+}
+
+/// Whether the job a job depends on is no longer in the table. Jobs with no
+/// dependency are always ready.
+fn dependency_finished() -> diesel::expression::SqlLiteral<Bool> {
+    diesel::dsl::sql(
+        "(depends_on IS NULL OR NOT EXISTS \
+         (SELECT 1 FROM background_jobs dependency WHERE dependency.id = background_jobs.depends_on))",
+    )
+}
 
-    sql_function!(fn power(x: Integer, y: Integer) -> Integer);
+/// Whether the job's type hasn't been paused with [`pause_job_type`]
+fn not_paused() -> diesel::expression::SqlLiteral<Bool> {
+    diesel::dsl::sql(
+        "NOT EXISTS (SELECT 1 FROM background_job_pauses \
+         WHERE background_job_pauses.job_type = background_jobs.job_type)",
+    )
+}
+
+/// Whether the job hasn't been claimed with [`claim_next_job`], or its claim
+/// has run out
+fn not_claimed() -> diesel::expression::SqlLiteral<Bool> {
+    diesel::dsl::sql("(locked_until IS NULL OR locked_until < CURRENT_TIMESTAMP)")
+}
+
+/// When a job should next be tried, given that it has been tried `retries`
+/// times so far. The delay is `2 ^ retries` minutes.
+///
+/// `jitter` is the fraction of that delay which is randomized. For example,
+/// with a jitter of `0.25`, a job which would be retried in 4 minutes will
+/// instead be retried somewhere between 3 and 4 minutes from now.
+fn backoff(
+    retries: &str,
+    jitter: f64,
+) -> Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Timestamp>> {
+    use diesel::dsl::sql;
 
-    Box::new(last_retry.lt(now - 1.minute().into_sql::<Interval>() * power(2, retries)))
+    let delay = format!("INTERVAL '1 minute' * POWER(2, {}) * (1 - ", retries);
+    Box::new(
+        sql::<Timestamp>("CURRENT_TIMESTAMP + ")
+            .sql(&delay)
+            .bind::<Double, _>(jitter)
+            .sql(" * RANDOM())"),
+    )
 }
 
-/// Finds the next job that is unlocked, and ready to be retried. If a row is
-/// found, it will be locked.
-pub fn find_next_unlocked_job(conn: &PgConnection) -> QueryResult<BackgroundJob> {
+fn matches(
+    filter: &JobFilter,
+) -> Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Bool>> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+
+    let mut condition: Box<dyn BoxableExpression<background_jobs, Pg, SqlType = Bool>> =
+        Box::new(sql("TRUE"));
+    if !filter.job_types.is_empty() {
+        condition = Box::new(condition.and(job_type.eq_any(filter.job_types.clone())));
+    }
+    if let Some(min_id) = filter.min_id {
+        condition = Box::new(condition.and(id.ge(min_id)));
+    }
+    if let Some(max_id) = filter.max_id {
+        condition = Box::new(condition.and(id.le(max_id)));
+    }
+    if filter.empty_id_range {
+        condition = Box::new(condition.and(sql::<Bool>("FALSE")));
+    }
+    for (name, value) in &filter.arguments {
+        // The name is written as a literal, rather than bound, so the
+        // condition can be matched to an index on the same expression
+        let argument = format!("data ->> '{}' = ", name.replace('\'', "''"));
+        condition = Box::new(condition.and(sql::<Bool>(&argument).bind::<Text, _>(value.clone())));
+    }
+    condition
+}
+
+/// Whether a job has passed the time it had to live
+fn expired() -> diesel::expression::SqlLiteral<Bool> {
+    diesel::dsl::sql("COALESCE(expires_at < CURRENT_TIMESTAMP, FALSE)")
+}
+
+/// Finds the next job that is unlocked, ready to be retried, and matches the
+/// given filter. If a row is found, it will be locked.
+///
+/// Jobs are ordered by `retry_at`, then `id`. A new job's `retry_at` is the
+/// start of the transaction which enqueued it, plus its delay if it has one,
+/// so jobs enqueued in the same transaction are run in the order they were
+/// inserted. Ordering by `retry_at` first lets Postgres walk the
+/// `background_jobs_ready` index from its start, instead of scanning every job
+/// which isn't ready yet. The query should be planned as a `LockRows` over an
+/// index scan on that index, with `retry_at <= CURRENT_TIMESTAMP` as the index
+/// condition.
+///
+/// Changes to this query must also be made to [`crate::sql::FETCH_NEXT_JOB`].
+pub fn find_next_unlocked_job(
+    conn: &mut PgConnection,
+    filter: &JobFilter,
+) -> QueryResult<BackgroundJob> {
     use crate::schema::background_jobs::dsl::*;
 
     background_jobs
-        .select((id, job_type, data))
+        .select(background_job_columns())
         .filter(retriable())
-        .order(id)
+        .filter(matches(filter))
+        .order((retry_at, id))
         .for_update()
         .skip_locked()
         .first::<BackgroundJob>(conn)
 }
 
+/// Claims the next job that is unlocked, ready to be retried, and matches the
+/// given filter, by setting its `locked_by` to `worker`. The claim is
+/// committed right away, rather than holding the row's lock, and other
+/// workers won't fetch the job until `timeout` has passed.
+///
+/// Jobs are picked in the same order as [`find_next_unlocked_job`].
+pub fn claim_next_job(
+    conn: &mut PgConnection,
+    filter: &JobFilter,
+    worker: &str,
+    timeout: Duration,
+) -> QueryResult<Option<BackgroundJob>> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+
+    let timeout = PgInterval::from_microseconds(timeout.as_micros() as i64);
+    conn.transaction(|conn| {
+        let next_job = background_jobs
+            .select(id)
+            .filter(retriable())
+            .filter(matches(filter))
+            .order((retry_at, id))
+            .for_update()
+            .skip_locked()
+            .first::<i64>(conn)
+            .optional()?;
+        next_job
+            .map(|job_id| {
+                update(background_jobs.find(job_id))
+                    .set((
+                        locked_by.eq(worker),
+                        locked_until.eq(sql("CURRENT_TIMESTAMP + ").bind::<Interval, _>(timeout)),
+                    ))
+                    .returning(background_job_columns())
+                    .get_result(conn)
+            })
+            .transpose()
+    })
+}
+
+/// Locks the row of a job claimed with [`claim_next_job`], if `worker` still
+/// holds the claim. Returns `false` if the claim ran out, and the job was
+/// claimed by another worker, or its row is gone.
+pub fn lock_claimed_job(conn: &mut PgConnection, job_id: i64, worker: &str) -> QueryResult<bool> {
+    use crate::schema::background_jobs::dsl::*;
+
+    let job = background_jobs
+        .find(job_id)
+        .filter(locked_by.eq(worker))
+        .select(id)
+        .for_update()
+        .first::<i64>(conn)
+        .optional()?;
+    Ok(job.is_some())
+}
+
+/// Lists the id and type of every job that is ready to be run, in the order
+/// they would be picked up by [`find_next_unlocked_job`].
+///
+/// No rows are locked, so jobs which are currently being run by another
+/// worker will also be returned.
+pub fn pending_jobs(conn: &mut PgConnection) -> QueryResult<Vec<(i64, String)>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    background_jobs
+        .select((id, job_type))
+        .filter(retriable())
+        .order((retry_at, id))
+        .load(conn)
+}
+
+/// The id and type of the job that [`find_next_unlocked_job`] would pick up
+/// next, if it were called with an empty filter.
+///
+/// The row isn't locked, and locked rows aren't skipped, so the job may
+/// already be running.
+pub fn next_pending_job(conn: &mut PgConnection) -> QueryResult<Option<(i64, String)>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    background_jobs
+        .select((id, job_type))
+        .filter(retriable())
+        .order((retry_at, id))
+        .first(conn)
+        .optional()
+}
+
 /// The number of jobs that have failed at least once
-pub fn failed_job_count(conn: &PgConnection) -> QueryResult<i64> {
+pub fn failed_job_count(conn: &mut PgConnection) -> QueryResult<i64> {
     use crate::schema::background_jobs::dsl::*;
 
     background_jobs
@@ -60,22 +450,355 @@ pub fn failed_job_count(conn: &PgConnection) -> QueryResult<i64> {
         .get_result(conn)
 }
 
-/// Deletes a job that has successfully completed running
-pub fn delete_successful_job(conn: &PgConnection, job_id: i64) -> QueryResult<()> {
+/// Makes the failed jobs which match `filter` ready to run right away, as if
+/// they had never failed, including ones which were abandoned. Jobs which are
+/// currently running are skipped. Returns the number of jobs which were reset.
+pub fn retry_failed_jobs(conn: &mut PgConnection, filter: &JobFilter) -> QueryResult<usize> {
     use crate::schema::background_jobs::dsl::*;
 
+    conn.transaction(|conn| {
+        let failed = background_jobs
+            .select(id)
+            .filter(retries.gt(0))
+            .filter(not_claimed())
+            .filter(matches(filter))
+            .for_update()
+            .skip_locked()
+            .load::<i64>(conn)?;
+        update(background_jobs.filter(id.eq_any(&failed)))
+            .set((retries.eq(0), abandoned.eq(false), retry_at.eq(now)))
+            .execute(conn)
+    })
+}
+
+/// Deletes up to `batch_size` abandoned jobs which were last attempted more
+/// than `ttl` ago. Returns the number of jobs which were deleted.
+pub fn delete_abandoned_jobs(
+    conn: &mut PgConnection,
+    ttl: Duration,
+    batch_size: i64,
+) -> QueryResult<usize> {
+    use crate::schema::background_jobs::dsl::*;
+
+    let ttl = PgInterval::from_microseconds(ttl.as_micros() as i64);
+    conn.transaction(|conn| {
+        let expired = background_jobs
+            .select(id)
+            .filter(abandoned)
+            .filter(last_retry.lt(now - ttl))
+            .limit(batch_size)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(conn)?;
+        delete(background_jobs.filter(id.eq_any(&expired))).execute(conn)
+    })
+}
+
+/// Deletes a job that has successfully completed running, or expired before
+/// it could be run
+///
+/// Changes to this query must also be made to [`crate::sql::DELETE_JOB`].
+pub fn delete_job(conn: &mut PgConnection, job_id: i64) -> QueryResult<()> {
+    use crate::schema::background_jobs::dsl::*;
+    use crate::schema::{background_job_cancellations, background_job_progress};
+
     delete(background_jobs.find(job_id)).execute(conn)?;
+    delete(background_job_progress::table.find(job_id)).execute(conn)?;
+    delete(background_job_cancellations::table.find(job_id)).execute(conn)?;
     Ok(())
 }
 
-/// Marks that we just tried and failed to run a job.
+/// Asks the job with the given id to stop.
+///
+/// This only sets a flag, which the job has to check with
+/// [`JobContext::is_cancel_requested`](crate::JobContext::is_cancel_requested).
+/// It can be called while the job is running, as it doesn't touch the job's
+/// row. A job which stops because of this should return
+/// [`Cancelled`](crate::Cancelled), so that it is deleted instead of retried.
+pub fn request_cancel(conn: &mut PgConnection, id: i64) -> QueryResult<()> {
+    use crate::schema::background_job_cancellations::dsl::*;
+
+    insert_into(background_job_cancellations)
+        .values(job_id.eq(id))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Whether the job with the given id is being run right now.
+///
+/// This tries to lock the job's row the same way a worker would, so it
+/// returns `true` if the row exists but is locked by another transaction,
+/// which is normally a worker running the job. Jobs which were claimed by a
+/// runner using [`FetchMode::Claim`](crate::FetchMode::Claim), and whose
+/// claim hasn't run out, are running as well. The lock is released before
+/// this returns. The answer only holds for the moment it was checked: the
+/// job may finish, or be started, right afterwards.
+pub fn is_job_running(conn: &mut PgConnection, job_id: i64) -> QueryResult<bool> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+    use diesel::result::Error::RollbackTransaction;
+
+    let mut running = false;
+    let result = conn.transaction(|conn| {
+        let claimed = background_jobs
+            .find(job_id)
+            .select(sql::<Bool>(
+                "COALESCE(locked_until >= CURRENT_TIMESTAMP, FALSE)",
+            ))
+            .first::<bool>(conn)
+            .optional()?;
+        let unlocked = background_jobs
+            .find(job_id)
+            .select(id)
+            .for_update()
+            .skip_locked()
+            .first::<i64>(conn)
+            .optional()?;
+        running = match claimed {
+            Some(claimed) => claimed || unlocked.is_none(),
+            None => false,
+        };
+        // Never keep the lock, even if we were called inside a transaction
+        Err(RollbackTransaction)
+    });
+    match result {
+        Ok(()) | Err(RollbackTransaction) => Ok(running),
+        Err(e) => Err(e),
+    }
+}
+
+/// Stops runners from starting jobs of the given type, until
+/// [`resume_job_type`] is called with the same type.
+///
+/// This applies to every runner using the same database. Jobs of the type
+/// which are already running are not interrupted, and jobs of the type can
+/// still be enqueued. Pausing a job type which is already paused does
+/// nothing.
+pub fn pause_job_type(conn: &mut PgConnection, name: &str) -> QueryResult<()> {
+    use crate::schema::background_job_pauses::dsl::*;
+
+    insert_into(background_job_pauses)
+        .values(job_type.eq(name))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Lets runners start jobs of a type paused with [`pause_job_type`] again
+pub fn resume_job_type(conn: &mut PgConnection, name: &str) -> QueryResult<()> {
+    use crate::schema::background_job_pauses::dsl::*;
+
+    delete(background_job_pauses.find(name)).execute(conn)?;
+    Ok(())
+}
+
+/// Takes the session level advisory lock with the given key, if no other
+/// session holds it
+pub(crate) fn try_advisory_lock(conn: &mut PgConnection, key: i64) -> QueryResult<bool> {
+    use diesel::dsl::sql;
+
+    diesel::select(
+        sql::<Bool>("pg_try_advisory_lock(")
+            .bind::<BigInt, _>(key)
+            .sql(")"),
+    )
+    .get_result(conn)
+}
+
+pub(crate) fn advisory_unlock(conn: &mut PgConnection, key: i64) -> QueryResult<bool> {
+    use diesel::dsl::sql;
+
+    diesel::select(
+        sql::<Bool>("pg_advisory_unlock(")
+            .bind::<BigInt, _>(key)
+            .sql(")"),
+    )
+    .get_result(conn)
+}
+
+pub(crate) fn cancel_requested(conn: &mut PgConnection, id: i64) -> QueryResult<bool> {
+    use crate::schema::background_job_cancellations::dsl::*;
+    use diesel::dsl::exists;
+
+    diesel::select(exists(background_job_cancellations.find(id))).get_result(conn)
+}
+
+/// Records how far along a running job is.
+///
+/// This must not be called with the connection the job was locked with, or
+/// the update won't be visible until the job finishes.
+pub fn set_progress(conn: &mut PgConnection, id: i64, percent: i16) -> QueryResult<()> {
+    use crate::schema::background_job_progress::dsl::*;
+
+    insert_into(background_job_progress)
+        .values((job_id.eq(id), progress.eq(percent)))
+        .on_conflict(job_id)
+        .do_update()
+        .set((progress.eq(percent), updated_at.eq(now)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Limits how long each statement may take for the rest of the current
+/// transaction. Returns the timeout which was replaced, which can be put back
+/// with [`restore_local_statement_timeout`].
+pub fn set_local_statement_timeout(
+    conn: &mut PgConnection,
+    timeout: Duration,
+) -> QueryResult<String> {
+    use diesel::dsl::sql;
+
+    let previous =
+        diesel::select(sql::<Text>("current_setting('statement_timeout')")).get_result(conn)?;
+    restore_local_statement_timeout(conn, &timeout.as_millis().to_string())?;
+    Ok(previous)
+}
+
+/// Sets the statement timeout for the rest of the current transaction back to
+/// one returned by [`set_local_statement_timeout`]
+pub fn restore_local_statement_timeout(conn: &mut PgConnection, timeout: &str) -> QueryResult<()> {
+    // `SET` can't take bind parameters, but `set_config` can
+    diesel::sql_query("SELECT set_config('statement_timeout', $1, true)")
+        .bind::<Text, _>(timeout)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Marks a job which is run at most once as attempted, before it's run.
+/// Returns the number of times the job has been attempted.
+///
+/// The job is abandoned, so once this is committed, it won't be fetched
+/// again, even if the runner crashes while running it.
+pub fn mark_attempted(conn: &mut PgConnection, job_id: i64) -> QueryResult<i32> {
+    use crate::schema::background_jobs::dsl::*;
+
+    update(background_jobs.find(job_id))
+        .set((
+            retries.eq(retries + 1),
+            last_retry.eq(now),
+            abandoned.eq(true),
+        ))
+        .returning(retries)
+        .get_result(conn)
+}
+
+/// Locks a job's row until the end of the current transaction
+pub fn lock_job(conn: &mut PgConnection, job_id: i64) -> QueryResult<()> {
+    use crate::schema::background_jobs::dsl::*;
+
+    background_jobs
+        .find(job_id)
+        .select(id)
+        .for_update()
+        .first::<i64>(conn)?;
+    Ok(())
+}
+
+/// Undoes [`mark_attempted`], for a job which turned out not to run
+pub fn unmark_attempted(conn: &mut PgConnection, job_id: i64) -> QueryResult<()> {
+    use crate::schema::background_jobs::dsl::*;
+
+    update(background_jobs.find(job_id))
+        .set((retries.eq(retries - 1), abandoned.eq(false)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Puts a job back in the queue without counting it as a failure. It will be
+/// tried again after the current backoff delay.
+pub fn snooze_job(conn: &mut PgConnection, job_id: i64, jitter: f64) -> QueryResult<()> {
+    use crate::schema::background_jobs::dsl::*;
+
+    update(background_jobs.find(job_id))
+        .set((
+            last_retry.eq(now),
+            retry_at.eq(backoff("retries", jitter)),
+            unclaimed(),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Marks that we just tried and failed to run a job, and that it should not be
+/// retried again, regardless of `max_retries`. Returns the number of times the
+/// job has failed.
+///
+/// Ignores any database errors that may have occurred, like
+/// [`update_failed_job`].
+pub fn abandon_job(conn: &mut PgConnection, job_id: i64) -> Option<i32> {
+    use crate::schema::background_jobs::dsl::*;
+
+    update(background_jobs.find(job_id))
+        .set((
+            retries.eq(retries + 1),
+            last_retry.eq(now),
+            abandoned.eq(true),
+            unclaimed(),
+        ))
+        .returning(retries)
+        .get_result(conn)
+        .ok()
+}
+
+/// Marks that we just tried and failed to run a job. If the job has now been
+/// tried `max_retries` times, it is abandoned and will not be retried again.
+/// Returns the number of times the job has failed if it was abandoned, or
+/// `None` if it will be retried.
+///
+/// The job is retried after `retry_after` if it's given, or with the usual
+/// backoff otherwise.
+///
+/// Changes to this query must also be made to [`crate::sql::MARK_JOB_FAILED`].
 ///
 /// Ignores any database errors that may have occurred. If the DB has gone away,
 /// we assume that just trying again with a new connection will succeed.
-pub fn update_failed_job(conn: &PgConnection, job_id: i64) {
+pub fn update_failed_job(
+    conn: &mut PgConnection,
+    job_id: i64,
+    jitter: f64,
+    max_retries: Option<u32>,
+    retry_after: Option<Duration>,
+) -> Option<i32> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::sql;
+
+    let next_retry: Box<dyn BoxableExpression<background_jobs, Pg, SqlType = Timestamp>> =
+        match retry_after {
+            Some(delay) => Box::new(
+                sql::<Timestamp>("CURRENT_TIMESTAMP + ")
+                    .bind::<Interval, _>(PgInterval::from_microseconds(delay.as_micros() as i64)),
+            ),
+            None => backoff("retries + 1", jitter),
+        };
+
+    let abandon: Box<dyn BoxableExpression<background_jobs, Pg, SqlType = Bool>> = match max_retries
+    {
+        Some(max) => Box::new((retries + 1).ge(max as i32)),
+        None => Box::new(sql("FALSE")),
+    };
+    update(background_jobs.find(job_id))
+        .set((
+            retries.eq(retries + 1),
+            last_retry.eq(now),
+            retry_at.eq(next_retry),
+            abandoned.eq(abandon),
+            unclaimed(),
+        ))
+        .returning((abandoned, retries))
+        .get_result::<(bool, i32)>(conn)
+        .ok()
+        .filter(|&(abandoned_job, _)| abandoned_job)
+        .map(|(_, retry_count)| retry_count)
+}
+
+/// Clears the claim on a job which is being put back in the queue, so it can
+/// be retried before the claim would have run out
+fn unclaimed() -> (
+    diesel::dsl::Eq<background_jobs::locked_by, Option<String>>,
+    diesel::dsl::Eq<background_jobs::locked_until, Option<std::time::SystemTime>>,
+) {
     use crate::schema::background_jobs::dsl::*;
 
-    let _ = update(background_jobs.find(job_id))
-        .set((retries.eq(retries + 1), last_retry.eq(now)))
-        .execute(conn);
+    (locked_by.eq(None), locked_until.eq(None))
 }
@@ -0,0 +1,141 @@
+//! Helpers for running jobs in tests without sharing the `background_jobs`
+//! table between them.
+//!
+//! Requires the `testing` feature.
+
+use diesel::prelude::*;
+use diesel::r2d2;
+use diesel::sql_query;
+use std::error::Error;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::R2d2Builder;
+use crate::{storage, Builder, Runner};
+
+type ConnectionManager = r2d2::ConnectionManager<PgConnection>;
+type Pool = r2d2::Pool<ConnectionManager>;
+
+static SCHEMA_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A runner whose jobs are stored in a schema of its own
+///
+/// Creating a `TestRunner` creates a new schema in the given database, and
+/// runs swirl's migrations in it. Every connection in the runner's pool has
+/// its `search_path` set to only that schema, so the runner and anything
+/// using [`connection_pool`](Runner::connection_pool) see their own
+/// `background_jobs` table, and no other. The schema is dropped along with
+/// the runner.
+///
+/// Tests which each use their own `TestRunner` don't need to clean up after
+/// themselves, or be kept from running at the same time. Advisory locks are
+/// shared by the whole database, so tests which rely on them, such as with
+/// [`Job::enqueue_debounced`](crate::Job::enqueue_debounced), can still see
+/// each other.
+pub struct TestRunner<Env: 'static> {
+    // Declared before `schema` so the pool is closed before the schema is
+    // dropped
+    runner: Runner<Env, Pool>,
+    schema: TestSchema,
+}
+
+impl<Env> TestRunner<Env> {
+    /// Create a runner with the default configuration, in a new schema of the
+    /// database at `database_url`
+    pub fn new<S: Into<String>>(
+        database_url: S,
+        environment: Env,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::build(database_url, environment, |builder| builder)
+    }
+
+    /// Create a runner in a new schema of the database at `database_url`,
+    /// configured by `configure`
+    ///
+    /// The builder is already given a connection pool which sets the
+    /// `search_path`, so `configure` must not replace it.
+    pub fn build<S, F>(
+        database_url: S,
+        environment: Env,
+        configure: F,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>>
+    where
+        S: Into<String>,
+        F: FnOnce(Builder<Env, R2d2Builder>) -> Builder<Env, R2d2Builder>,
+    {
+        let database_url = database_url.into();
+        let schema = TestSchema::create(database_url.clone())?;
+        let pool_builder = r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .connection_customizer(Box::new(SetSearchPath(schema.name.clone())));
+        let builder =
+            Runner::builder(environment).connection_pool_builder(database_url, pool_builder);
+        let runner = configure(builder).build();
+        storage::run_pending_migrations(&mut *runner.connection_pool().get()?)?;
+
+        Ok(Self { runner, schema })
+    }
+
+    /// The name of the schema this runner's jobs are stored in
+    pub fn schema(&self) -> &str {
+        &self.schema.name
+    }
+}
+
+impl<Env> Deref for TestRunner<Env> {
+    type Target = Runner<Env, Pool>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.runner
+    }
+}
+
+struct TestSchema {
+    name: String,
+    database_url: String,
+}
+
+impl TestSchema {
+    fn create(database_url: String) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        // The time keeps the name from clashing with a schema which was left
+        // behind by an earlier process with the same id
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or_default();
+        let name = format!(
+            "swirl_test_{}_{}_{}",
+            std::process::id(),
+            millis,
+            SCHEMA_COUNT.fetch_add(1, Ordering::Relaxed),
+        );
+        let mut conn = PgConnection::establish(&database_url)?;
+        sql_query(format!("CREATE SCHEMA \"{}\"", name)).execute(&mut conn)?;
+
+        Ok(Self { name, database_url })
+    }
+}
+
+impl Drop for TestSchema {
+    fn drop(&mut self) {
+        // There's nowhere to report errors to, and a schema which is left
+        // behind doesn't affect other tests
+        if let Ok(mut conn) = PgConnection::establish(&self.database_url) {
+            let _ = sql_query(format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", self.name))
+                .execute(&mut conn);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SetSearchPath(String);
+
+impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for SetSearchPath {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        sql_query(format!("SET search_path TO \"{}\"", self.0))
+            .execute(conn)
+            .map_err(r2d2::Error::QueryError)?;
+        Ok(())
+    }
+}
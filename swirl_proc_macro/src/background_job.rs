@@ -1,12 +1,13 @@
 use crate::diagnostic_shim::*;
 use proc_macro2::TokenStream;
 use quote::quote;
-use std::borrow::Cow;
 use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 
-pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
-    let job = BackgroundJob::try_from(item)?;
+pub fn expand(args: syn::AttributeArgs, item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
+    let options = JobOptions::try_from(args)?;
+    let krate = options.krate.unwrap_or_else(|| syn::parse_quote!(swirl));
+    let job = BackgroundJob::try_from(item, &krate)?;
 
     let attrs = job.attrs;
     let vis = job.visibility;
@@ -14,15 +15,71 @@ pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
     let name = job.name;
     let env_pat = &job.args.env_arg.pat;
     let env_type = &job.args.env_arg.ty;
-    let connection_arg = &job.args.connection_arg;
-    let pool_pat = connection_arg.pool_pat();
-    let pool_ty = connection_arg.pool_ty();
-    let fn_args = job.args.iter();
-    let struct_def = job.args.struct_def();
+    let connection_pat = &job.args.connection_arg.pat;
+    let context_pat = &job.args.context_arg.pat;
+    let context_ty = &job.args.context_arg.ty;
+    let pool = job.args.pool_arg.as_ref().map(|PoolArg { pat, ty }| {
+        quote! {
+            let #pat: &#ty = #context_pat.pool();
+        }
+    });
+    let fn_args = job.args.fn_args();
+    let struct_def = job.args.struct_def(&krate);
     let struct_assign = job.args.struct_assign();
-    let arg_names = job.args.names();
+    let arg_names = job.args.names().collect::<Vec<_>>();
     let return_type = job.return_type;
-    let body = connection_arg.wrap(job.body);
+    let job_body = job.body;
+    let no_transaction = if options.no_transaction {
+        Some(quote! {
+            const IN_TRANSACTION: bool = false;
+        })
+    } else {
+        None
+    };
+    let exclusive = if options.exclusive {
+        Some(quote! {
+            const EXCLUSIVE: bool = true;
+        })
+    } else {
+        None
+    };
+    let at_most_once = if options.at_most_once {
+        Some(quote! {
+            const AT_MOST_ONCE: bool = true;
+        })
+    } else {
+        None
+    };
+    let statement_timeout = options.statement_timeout_ms.map(|(ms, _)| {
+        quote! {
+            const STATEMENT_TIMEOUT: Option<std::time::Duration> =
+                Some(std::time::Duration::from_millis(#ms));
+        }
+    });
+    let weight = options.weight.map(|weight| {
+        quote! {
+            const WEIGHT: u32 = #weight;
+        }
+    });
+    let validate = options.validate.map(|validate| {
+        quote! {
+            fn validate(&self) -> Result<(), #krate::EnqueueError> {
+                let Self { #(#arg_names),* } = self;
+                #validate(#(#arg_names),*)
+                    .map_err(|e| #krate::EnqueueError::InvalidArguments(e.into()))
+            }
+        }
+    });
+    let should_run = options.guard.map(|guard| {
+        quote! {
+            fn should_run(&self, env: &Self::Environment) -> bool {
+                #guard(env)
+            }
+        }
+    });
+
+    // `#[serde(crate)]` takes a string, so the path has to be written out
+    let serde_crate = format!("{}::serde", quote!(#krate)).replace(' ', "");
 
     let res = quote! {
         #(#attrs)*
@@ -32,43 +89,197 @@ pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
             }
         }
 
-        impl swirl::Job for #name :: Job {
+        impl #krate::Job for #name :: Job {
             type Environment = #env_type;
-            const JOB_TYPE: &'static str = stringify!(#name);
+            const JOB_TYPE: &'static str = #name::JOB_TYPE;
+            #no_transaction
+            #exclusive
+            #at_most_once
+            #statement_timeout
+            #weight
+
+            #validate
+            #should_run
+
+            fn from_value(
+                value: #krate::serde_json::Value,
+            ) -> Result<Self, #krate::serde_json::Error> {
+                // Jobs without arguments serialize to `{}`, but rows which
+                // were inserted by hand may use `null` instead
+                let value = match value {
+                    #krate::serde_json::Value::Null => {
+                        #krate::serde_json::Value::Object(Default::default())
+                    }
+                    value => value,
+                };
+                #krate::serde_json::from_value(value)
+            }
 
-            #fn_token perform(self, #env_pat: &Self::Environment, #pool_pat: &#pool_ty) #return_type {
+            #fn_token perform(
+                self,
+                #env_pat: &Self::Environment,
+                #connection_pat: &mut #krate::diesel::PgConnection,
+                #context_pat: &#context_ty,
+            ) -> Result<(), #krate::PerformError> {
                 let Self { #(#arg_names),* } = self;
-                #body
+                #pool
+                let result: #return_type = (|| { #(#job_body)* })();
+                result.map(|_| ()).map_err(Into::into)
             }
         }
 
-        mod #name {
+        #vis mod #name {
             use super::*;
 
-            #[derive(swirl::Serialize, swirl::Deserialize)]
-            #[serde(crate = "swirl::serde")]
+            /// The type the job is stored with, the same as its `Job::JOB_TYPE`
+            pub const JOB_TYPE: &str = stringify!(#name);
+
+            #[derive(#krate::Serialize, #krate::Deserialize)]
+            #[serde(crate = #serde_crate)]
+            #[must_use = "jobs do nothing unless they are enqueued"]
             pub struct Job {
                 #(#struct_def),*
             }
 
-            swirl::register_job!(Job);
+            #krate::register_job!(Job);
         }
     };
     Ok(res)
 }
 
+#[derive(Default)]
+struct JobOptions {
+    krate: Option<syn::Path>,
+    guard: Option<syn::Path>,
+    validate: Option<syn::Path>,
+    no_transaction: bool,
+    exclusive: bool,
+    at_most_once: bool,
+    statement_timeout_ms: Option<(u64, proc_macro2::Span)>,
+    weight: Option<u32>,
+}
+
+impl JobOptions {
+    fn try_from(args: syn::AttributeArgs) -> Result<Self, Diagnostic> {
+        let mut options = Self::default();
+
+        for arg in args {
+            match arg {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("crate") =>
+                {
+                    options.krate = Some(parse_lit_str(&name_value.lit)?);
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("guard") =>
+                {
+                    options.guard = Some(parse_lit_str(&name_value.lit)?);
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("validate") =>
+                {
+                    options.validate = Some(parse_lit_str(&name_value.lit)?);
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("no_transaction") =>
+                {
+                    options.no_transaction = parse_lit_bool(&name_value.lit)?;
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("exclusive") =>
+                {
+                    options.exclusive = parse_lit_bool(&name_value.lit)?;
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("delivery") =>
+                {
+                    options.at_most_once = parse_delivery(&name_value.lit)?;
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("statement_timeout_ms") =>
+                {
+                    let ms = parse_lit_int(&name_value.lit)?;
+                    options.statement_timeout_ms = Some((ms.into(), name_value.span()));
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(name_value))
+                    if name_value.path.is_ident("weight") =>
+                {
+                    options.weight = Some(parse_lit_int(&name_value.lit)?);
+                }
+                _ => {
+                    return Err(arg
+                        .span()
+                        .error("Unrecognized argument to #[swirl::background_job]")
+                        .help(
+                            "Valid arguments are: `crate = \"path::to::swirl\"`, \
+                             `guard = \"path::to::function\"`, \
+                             `validate = \"path::to::function\"`, \
+                             `no_transaction = true`, `exclusive = true`, \
+                             `delivery = \"at_most_once\"`, \
+                             `statement_timeout_ms = 30000`, `weight = 4`",
+                        ));
+                }
+            }
+        }
+
+        if let (true, Some((_, span))) = (options.no_transaction, options.statement_timeout_ms) {
+            return Err(span
+                .error("`statement_timeout_ms` only applies to jobs which run in a transaction")
+                .help("Remove `no_transaction = true`, or set the timeout on the connection"));
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_lit_bool(lit: &syn::Lit) -> Result<bool, Diagnostic> {
+    match lit {
+        syn::Lit::Bool(lit_bool) => Ok(lit_bool.value),
+        _ => Err(lit.span().error("Expected `true` or `false`")),
+    }
+}
+
+/// Returns whether the job should be run at most once
+fn parse_delivery(lit: &syn::Lit) -> Result<bool, Diagnostic> {
+    match lit {
+        syn::Lit::Str(lit_str) if lit_str.value() == "at_least_once" => Ok(false),
+        syn::Lit::Str(lit_str) if lit_str.value() == "at_most_once" => Ok(true),
+        _ => Err(lit
+            .span()
+            .error("Expected `\"at_least_once\"` or `\"at_most_once\"`")),
+    }
+}
+
+fn parse_lit_int(lit: &syn::Lit) -> Result<u32, Diagnostic> {
+    match lit {
+        syn::Lit::Int(lit_int) => lit_int
+            .base10_parse()
+            .map_err(|e| lit_int.span().error(e.to_string())),
+        _ => Err(lit.span().error("Expected an integer")),
+    }
+}
+
+fn parse_lit_str<T: syn::parse::Parse>(lit: &syn::Lit) -> Result<T, Diagnostic> {
+    match lit {
+        syn::Lit::Str(lit_str) => lit_str
+            .parse()
+            .map_err(|e| lit_str.span().error(e.to_string())),
+        _ => Err(lit.span().error("Expected a string literal")),
+    }
+}
+
 struct BackgroundJob {
     attrs: Vec<syn::Attribute>,
     visibility: syn::Visibility,
     fn_token: syn::Token![fn],
     name: syn::Ident,
     args: JobArgs,
-    return_type: syn::ReturnType,
+    return_type: Box<syn::Type>,
     body: Vec<syn::Stmt>,
 }
 
 impl BackgroundJob {
-    fn try_from(item: syn::ItemFn) -> Result<Self, Diagnostic> {
+    fn try_from(item: syn::ItemFn, krate: &syn::Path) -> Result<Self, Diagnostic> {
         let syn::ItemFn {
             attrs,
             vis,
@@ -114,9 +325,20 @@ impl BackgroundJob {
         }
 
         let fn_token = sig.fn_token;
-        let return_type = sig.output.clone();
+        let return_type = match sig.output.clone() {
+            syn::ReturnType::Type(_, ty) => ty,
+            syn::ReturnType::Default => {
+                return Err(sig
+                    .ident
+                    .span()
+                    .error("Background jobs must return a `Result`")
+                    .help(
+                        "Any `Result<T, E>` where `E: Into<swirl::PerformError>` can be returned",
+                    ));
+            }
+        };
         let ident = sig.ident.clone();
-        let job_args = JobArgs::try_from(sig)?;
+        let job_args = JobArgs::try_from(sig, krate)?;
 
         Ok(Self {
             attrs,
@@ -133,17 +355,17 @@ impl BackgroundJob {
 struct JobArgs {
     env_arg: EnvArg,
     connection_arg: ConnectionArg,
+    pool_arg: Option<PoolArg>,
+    context_arg: ContextArg,
     args: Punctuated<syn::PatType, syn::Token![,]>,
 }
 
 impl JobArgs {
-    fn iter(&self) -> <&Self as IntoIterator>::IntoIter {
-        self.into_iter()
-    }
-
-    fn try_from(decl: syn::Signature) -> Result<Self, Diagnostic> {
+    fn try_from(decl: syn::Signature, krate: &syn::Path) -> Result<Self, Diagnostic> {
         let mut env_arg = None;
-        let mut connection_arg = ConnectionArg::None;
+        let mut connection_arg = None;
+        let mut pool_arg = None;
+        let mut context_arg = None;
         let mut args = Punctuated::new();
 
         for fn_arg in decl.inputs {
@@ -169,34 +391,70 @@ impl JobArgs {
             }
 
             let span = pat_type.span();
-            match (&env_arg, &connection_arg, Arg::try_from(pat_type)?) {
-                (None, _, Arg::Env(arg)) => env_arg = Some(arg),
-                (Some(_), _, Arg::Env(_)) => {
+            match Arg::try_from(pat_type)? {
+                Arg::Env(arg) if env_arg.is_none() => env_arg = Some(arg),
+                Arg::Env(_) => {
                     return Err(
                         span.error("Background jobs cannot take references as arguments")
-                            .help("If this argument is a database connection, the type must be `&PgConnection`")
+                            .help("If this argument is a database connection, the type must be `&mut PgConnection` or `&dyn swirl::db::DieselPoolObj`")
+                            .help("If this argument is the job's context, the type must be `&swirl::JobContext`")
                     );
                 }
-                (_, ConnectionArg::None, Arg::Connection(arg)) => connection_arg = arg,
-                (_, _, Arg::Connection(_)) => {
-                    return Err(
-                        span.error("Multiple database connection arguments")
-                            .help("To take a connection pool as an argument instead of a single connection, use the type `&dyn swirl::db::DieselPoolObj`")
-                    );
+                Arg::Connection(arg) if connection_arg.is_none() => connection_arg = Some(arg),
+                Arg::Connection(_) => {
+                    return Err(span.error("Multiple database connection arguments"));
+                }
+                Arg::Pool(arg) if pool_arg.is_none() => pool_arg = Some(arg),
+                Arg::Pool(_) => {
+                    return Err(span.error("Multiple connection pool arguments"));
                 }
-                (_, _, Arg::Normal(pat_type)) => args.push(pat_type),
+                Arg::Context(arg) if context_arg.is_none() => context_arg = Some(arg),
+                Arg::Context(_) => {
+                    return Err(span.error("Multiple job context arguments"));
+                }
+                Arg::Normal(pat_type) => args.push(pat_type),
             }
         }
 
         Ok(Self {
             env_arg: env_arg.unwrap_or_default(),
-            connection_arg,
+            connection_arg: connection_arg.unwrap_or_default(),
+            pool_arg,
+            context_arg: context_arg.unwrap_or_else(|| ContextArg::unnamed(krate)),
             args,
         })
     }
 
-    fn struct_def(&self) -> impl Iterator<Item = proc_macro2::TokenStream> + '_ {
-        self.args.iter().map(|arg| quote::quote!(pub(super) #arg))
+    /// The arguments of the generated constructor function. `#[serde]` and
+    /// `#[job_arg]` attributes are only valid on the job struct's fields, so
+    /// they are removed here.
+    fn fn_args(&self) -> impl Iterator<Item = syn::PatType> + '_ {
+        self.args.iter().map(|arg| {
+            let mut arg = arg.clone();
+            arg.attrs
+                .retain(|attr| !attr.path.is_ident("serde") && !attr.path.is_ident("job_arg"));
+            arg
+        })
+    }
+
+    /// The fields of the job struct. Arguments marked with `#[job_arg]` are
+    /// stored using their `swirl::JobArg` implementation.
+    fn struct_def<'a>(
+        &'a self,
+        krate: &'a syn::Path,
+    ) -> impl Iterator<Item = proc_macro2::TokenStream> + 'a {
+        self.args.iter().map(move |arg| {
+            let syn::PatType { attrs, pat, ty, .. } = arg;
+            let attrs = attrs.iter().map(|attr| {
+                if attr.path.is_ident("job_arg") {
+                    let with = format!("{}::job_arg", quote::quote!(#krate)).replace(' ', "");
+                    quote::quote!(#[serde(with = #with)])
+                } else {
+                    quote::quote!(#attr)
+                }
+            });
+            quote::quote!(#(#attrs)* pub(super) #pat: #ty)
+        })
     }
 
     fn struct_assign(&self) -> impl Iterator<Item = syn::FieldValue> + '_ {
@@ -223,19 +481,31 @@ impl<'a> IntoIterator for &'a JobArgs {
 enum Arg {
     Env(EnvArg),
     Connection(ConnectionArg),
+    Pool(PoolArg),
+    Context(ContextArg),
     Normal(syn::PatType),
 }
 
 impl Arg {
     fn try_from(pat_type: syn::PatType) -> Result<Self, Diagnostic> {
         if let syn::Type::Reference(type_ref) = *pat_type.ty {
-            if let Some(mutable) = type_ref.mutability {
-                return Err(mutable.span.error("Unexpected `mut`"));
-            }
             let pat = pat_type.pat;
             let ty = type_ref.elem;
-            if ConnectionArg::is_connection_arg(&ty) {
-                Ok(Arg::Connection(ConnectionArg::from_arg(pat, ty)))
+            match (type_ref.mutability, is_connection(&ty)) {
+                (Some(_), true) => return Ok(Arg::Connection(ConnectionArg { pat })),
+                (Some(mutable), false) => return Err(mutable.span.error("Unexpected `mut`")),
+                (None, true) => {
+                    return Err(ty
+                        .span()
+                        .error("Database connections must be taken by mutable reference")
+                        .help("Use `&mut PgConnection` instead"));
+                }
+                (None, false) => {}
+            }
+            if is_pool(&ty) {
+                Ok(Arg::Pool(PoolArg { pat, ty }))
+            } else if is_context(&ty) {
+                Ok(Arg::Context(ContextArg { pat, ty }))
             } else {
                 Ok(Arg::Env(EnvArg { pat, ty }))
             }
@@ -259,78 +529,70 @@ impl Default for EnvArg {
     }
 }
 
-enum ConnectionArg {
-    None,
-    SingleConnection(Box<syn::Pat>),
-    Pool(Box<syn::Pat>, Box<syn::Type>),
+struct ConnectionArg {
+    pat: Box<syn::Pat>,
 }
 
-impl ConnectionArg {
-    fn is_single_connection(ty: &syn::Type) -> bool {
-        if let syn::Type::Path(syn::TypePath { path, .. }) = ty {
-            path_ends_with(path, "PgConnection")
-        } else {
-            false
+impl Default for ConnectionArg {
+    fn default() -> Self {
+        Self {
+            pat: syn::parse_quote!(_),
         }
     }
+}
 
-    fn is_pool(ty: &syn::Type) -> bool {
-        if let syn::Type::TraitObject(type_trait_object) = ty {
-            type_trait_object.bounds.iter().any(|bound| {
-                if let syn::TypeParamBound::Trait(trait_bound) = bound {
-                    path_ends_with(&trait_bound.path, "DieselPoolObj")
-                } else {
-                    false
-                }
-            })
-        } else {
-            false
-        }
-    }
+struct PoolArg {
+    pat: Box<syn::Pat>,
+    ty: Box<syn::Type>,
+}
 
-    fn is_connection_arg(ty: &syn::Type) -> bool {
-        Self::is_single_connection(ty) || Self::is_pool(ty)
-    }
+struct ContextArg {
+    pat: Box<syn::Pat>,
+    ty: Box<syn::Type>,
+}
 
-    fn from_arg(pat: Box<syn::Pat>, ty: Box<syn::Type>) -> Self {
-        if Self::is_single_connection(&ty) {
-            ConnectionArg::SingleConnection(pat)
-        } else if Self::is_pool(&ty) {
-            ConnectionArg::Pool(pat, ty)
-        } else {
-            ConnectionArg::None
+impl ContextArg {
+    /// The context which is passed to jobs which don't take it as an argument
+    fn unnamed(krate: &syn::Path) -> Self {
+        Self {
+            pat: syn::parse_quote!(__swirl_context),
+            ty: syn::parse_quote!(#krate::JobContext<'_>),
         }
     }
+}
 
-    fn pool_pat(&self) -> Cow<'_, syn::Pat> {
-        match self {
-            ConnectionArg::None => Cow::Owned(syn::parse_quote!(_)),
-            ConnectionArg::SingleConnection(_) => {
-                Cow::Owned(syn::parse_quote!(__swirl_connection_pool))
-            }
-            ConnectionArg::Pool(pat, _) => Cow::Borrowed(pat),
-        }
+fn is_connection(ty: &syn::Type) -> bool {
+    if let syn::Type::Path(syn::TypePath { path, .. }) = ty {
+        path_ends_with(path, "PgConnection")
+    } else {
+        false
     }
+}
 
-    fn pool_ty(&self) -> Cow<'_, syn::Type> {
-        if let ConnectionArg::Pool(_, ty) = self {
-            Cow::Borrowed(ty)
-        } else {
-            Cow::Owned(syn::parse_quote!(swirl::db::DieselPoolObj))
-        }
+fn is_pool(ty: &syn::Type) -> bool {
+    if let syn::Type::TraitObject(type_trait_object) = ty {
+        type_trait_object.bounds.iter().any(|bound| {
+            if let syn::TypeParamBound::Trait(trait_bound) = bound {
+                path_ends_with(&trait_bound.path, "DieselPoolObj")
+            } else {
+                false
+            }
+        })
+    } else {
+        false
     }
+}
 
-    fn wrap(&self, body: Vec<syn::Stmt>) -> TokenStream {
-        let mut body = quote!(#(#body)*);
-        if let ConnectionArg::SingleConnection(pat) = self {
-            let pool_pat = self.pool_pat();
-            body = quote! {
-                #pool_pat.with_connection(&|#pat| {
-                    #body
-                })
-            }
-        }
-        body
+fn is_context(ty: &syn::Type) -> bool {
+    if let syn::Type::Path(syn::TypePath { path, .. }) = ty {
+        // Unlike the other types, `JobContext` has a lifetime parameter,
+        // which may or may not be written out
+        path.segments
+            .last()
+            .map(|s| s.ident == "JobContext")
+            .unwrap_or(false)
+    } else {
+        false
     }
 }
 